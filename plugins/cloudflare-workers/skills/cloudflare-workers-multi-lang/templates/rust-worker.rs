@@ -12,7 +12,10 @@
  * 2. Copy Cargo.toml configuration below
  * 3. Copy this file to src/lib.rs
  * 4. Configure wrangler.jsonc
- * 5. Run: npx wrangler dev
+ * 5. Apply the D1 schema below as migrations
+ * 6. Set secrets: npx wrangler secret put ADMIN_KEY (and SIGNING_SECRET,
+ *    EMAIL_ENCRYPTION_KEY)
+ * 7. Run: npx wrangler dev
  */
 
 // ============================================
@@ -38,6 +41,13 @@ serde_json = "1.0"
 futures = "0.3"
 uuid = { version = "1.0", features = ["v4", "js"] }
 chrono = { version = "0.4", features = ["wasmbind"] }
+rmp-serde = "1.1"
+sha2 = "0.10"
+hmac = "0.12"
+aes-gcm = "0.10"
+getrandom = { version = "0.2", features = ["js"] }
+flate2 = "1.0"
+base64 = "0.22"
 
 [profile.release]
 opt-level = "s"
@@ -67,14 +77,209 @@ strip = true
   ],
   "r2_buckets": [
     { "binding": "STORAGE", "bucket_name": "my-bucket" }
-  ]
+  ],
+  "analytics_engine_datasets": [
+    { "binding": "ANALYTICS", "dataset": "requests" }
+  ],
+  "durable_objects": {
+    "bindings": [
+      { "name": "USER_EVENTS", "class_name": "UserEvents" },
+      { "name": "USER_COUNTER", "class_name": "UserCounter" },
+      { "name": "COMPUTE_SLOTS", "class_name": "ComputeSlots" },
+      { "name": "COMPUTE_USAGE", "class_name": "ComputeUsage" }
+    ]
+  },
+  "migrations": [
+    { "tag": "v1", "new_classes": ["UserEvents"] },
+    { "tag": "v2", "new_classes": ["UserCounter"] },
+    { "tag": "v3", "new_classes": ["ComputeSlots"] },
+    { "tag": "v4", "new_classes": ["ComputeUsage"] }
+  ],
+  "vars": {
+    "LOG_FORMAT": "text",
+    "LOG_SAMPLE_RATE": "1",
+    "LOG_SLOW_MS": "1000",
+    "ROUTE_CACHE_CONTROL": "/=public, max-age=3600; /api/users/:id=private, max-age=30; /api/users/cursor=no-store; /api/users/verify=no-store",
+    "ROUTE_BODY_LIMITS": "/api/users=16384; /api/users/:id=16384; /api/users/validate=1048576; /api/compute=10485760; /api/compute/batch=10485760",
+    "FILE_CACHE_CONTROL": "public, max-age=3600",
+    "WEBHOOK_URL": "",
+    "WEBHOOK_TIMEOUT_MS": "5000",
+    "PROXY_UPSTREAM": "",
+    "UPLOAD_DEFAULT_CHARSET": "utf-8",
+    "VALIDATE_TEXT_UPLOADS": "false",
+    "BASE64_JSON_UPLOADS": "false",
+    "SNIFF_CONTENT_TYPE": "false",
+    "ALLOWED_UPLOAD_TYPES": "",
+    "SNIFF_UPLOAD_TYPES": "false",
+    "MAX_UPLOAD_BYTES": "104857600",
+    "RESPONSE_SOFT_LIMIT_BYTES": "1048576",
+    "RESPONSE_HARD_LIMIT_BYTES": "8388608",
+    "REQUIRE_CONTENT_LENGTH": "false",
+    "MAX_REQUEST_HEADERS": "100",
+    "MAX_REQUEST_HEADER_BYTES": "32768",
+    "BATCH_CONCURRENCY": "6",
+    "CACHE_SHARDS": "1",
+    "CORS_ALLOWED_ORIGINS": "*",
+    "CORS_MAX_AGE": "86400",
+    "CORS_ALLOW_CREDENTIALS": "false",
+    "USER_CACHE_TTL": "300",
+    "USER_CACHE_LOCK_TTL_MS": "5000",
+    "USER_CACHE_LOCK_WAIT_MS": "500",
+    "USER_CACHE_STALE_TTL": "0",
+    "MAX_OFFSET": "10000",
+    "LIST_ETAGS": "true",
+    "STRICT_QUERY_PARAMS": "false",
+    "DISABLED_ROUTES": "",
+    "ROUTE_TOGGLES": "false",
+    "EMAIL_ENCRYPTION": "false",
+    "TENANT_ISOLATION": "false",
+    "HIDE_USER_EXISTENCE": "false",
+    "HIDE_USER_EXISTENCE_FLOOR_MS": "100",
+    "USER_COUNTER": "false",
+    "EMAIL_VERIFY_TTL": "86400",
+    "EMAIL_PLUS_NORMALIZE_DOMAINS": "",
+    "COMPUTE_REQUIRE_API_KEY": "false",
+    "COMPUTE_MAX_CONCURRENCY": "",
+    "COMPUTE_USAGE": "false",
+    "COMPUTE_DEFAULT_OPERATION": "summary",
+    "BACKGROUND_TASK_BUDGET_MS": "25000",
+    "REQUEST_DEADLINE_MS": "15000",
+    "HEALTH_PROBE_TIMEOUT_MS": "2000",
+    "D1_READ_REPLICAS": "false",
+    "D1_LOCKED_RETRIES": "2",
+    "D1_LOCKED_RETRY_AFTER": "1",
+    "RETENTION_DELETED_DAYS": "30",
+    "RETENTION_INACTIVE_DAYS": "",
+    "RETENTION_MAX_DELETED": "",
+    "RETENTION_CAP_IGNORES_WINDOW": "false",
+    "INDEX_CHECK_ON_START": "false",
+    "DEBUG_ECHO": "false",
+    "CSRF_PROTECTION": "false",
+    "METHOD_OVERRIDE": "false",
+    "TRUST_REQUEST_ID": "true",
+    "OMIT_NULL_FIELDS": "false",
+    "SECURITY_HEADERS": "true",
+    "CONTENT_SECURITY_POLICY": "default-src 'none'; frame-ancestors 'none'"
+  },
+  "triggers": {
+    "crons": ["0 3 * * *"]
+  }
 }
 */
 
+// ============================================
+// D1 SCHEMA (migrations/0001_create_users.sql)
+// ============================================
+
+/*
+CREATE TABLE users (
+  id TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  email TEXT NOT NULL UNIQUE,
+  created_at TEXT NOT NULL,
+  deleted_at TEXT
+);
+
+CREATE INDEX idx_users_created_at ON users (created_at);
+CREATE INDEX idx_users_deleted_at ON users (deleted_at);
+*/
+
+// ============================================
+// D1 SCHEMA (migrations/0002_user_retention.sql)
+// ============================================
+
+/*
+ALTER TABLE users ADD COLUMN updated_at TEXT;
+ALTER TABLE users ADD COLUMN anonymized_at TEXT;
+*/
+
+// ============================================
+// D1 SCHEMA (migrations/0003_users_list_order.sql)
+// ============================================
+
+/*
+DROP INDEX idx_users_created_at;
+CREATE INDEX idx_users_created_at_id ON users (created_at, id);
+*/
+
+// ============================================
+// D1 SCHEMA (migrations/0004_email_verification.sql)
+// ============================================
+
+/*
+ALTER TABLE users ADD COLUMN email_verified_at TEXT;
+*/
+
+// ============================================
+// D1 SCHEMA (migrations/0005_email_normalized.sql)
+// ============================================
+
+/*
+ALTER TABLE users ADD COLUMN email_normalized TEXT;
+UPDATE users SET email_normalized = email;
+CREATE UNIQUE INDEX idx_users_email_normalized ON users (email_normalized);
+*/
+
+// ============================================
+// D1 SCHEMA (migrations/0006_user_tenant.sql)
+// ============================================
+
+/*
+ALTER TABLE users ADD COLUMN tenant_id TEXT;
+CREATE INDEX idx_users_tenant ON users (tenant_id, created_at, id);
+*/
+
+// ============================================
+// D1 SCHEMA (migrations/0007_email_hmac.sql)
+// ============================================
+
+/*
+ALTER TABLE users ADD COLUMN email_hmac TEXT;
+CREATE UNIQUE INDEX idx_users_email_hmac ON users (email_hmac);
+*/
+
+// ============================================
+// D1 SCHEMA (migrations/0008_tenant_email_unique.sql)
+// ============================================
+
+/*
+-- Emails become unique per tenant. The column UNIQUE on `email` from 0001
+-- can only be dropped by rebuilding the table. Untenanted rows index under ''
+-- since NULLs never collide in a UNIQUE index.
+CREATE TABLE users_new (
+  id TEXT PRIMARY KEY,
+  name TEXT NOT NULL,
+  email TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  deleted_at TEXT,
+  updated_at TEXT,
+  anonymized_at TEXT,
+  email_verified_at TEXT,
+  email_normalized TEXT,
+  tenant_id TEXT,
+  email_hmac TEXT
+);
+INSERT INTO users_new (id, name, email, created_at, deleted_at, updated_at, anonymized_at,
+  email_verified_at, email_normalized, tenant_id, email_hmac)
+SELECT id, name, email, created_at, deleted_at, updated_at, anonymized_at,
+  email_verified_at, email_normalized, tenant_id, email_hmac FROM users;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE INDEX idx_users_created_at_id ON users (created_at, id);
+CREATE INDEX idx_users_deleted_at ON users (deleted_at);
+CREATE INDEX idx_users_email_normalized ON users (email_normalized);
+CREATE INDEX idx_users_tenant ON users (tenant_id, created_at, id);
+CREATE INDEX idx_users_email_hmac ON users (email_hmac);
+CREATE UNIQUE INDEX idx_users_tenant_email ON users (COALESCE(tenant_id, ''), email_normalized);
+*/
+
 // ============================================
 // MAIN WORKER CODE (src/lib.rs)
 // ============================================
 
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 use worker::*;
 
@@ -82,24 +287,54 @@ use worker::*;
 // TYPE DEFINITIONS
 // ============================================
 
+// Unknown fields are rejected (422 unknown_field) so typos like `emial` get
+// reported instead of silently dropped
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct CreateUserRequest {
     name: String,
     email: String,
 }
 
+// A create request after validation: trimmed name, lowercased email
+#[derive(Serialize, Debug, PartialEq)]
+struct NewUser {
+    name: String,
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct BatchIdsRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ValidateUsersRequest {
+    users: Vec<serde_json::Value>,
+}
+
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct UpdateUserRequest {
     name: Option<String>,
     email: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct User {
     id: String,
     name: String,
     email: String,
     created_at: String,
+    // Set when soft-deleted; only ever non-null on admin paths
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<String>,
+    // Cleared whenever the email changes
+    #[serde(default)]
+    email_verified_at: Option<String>,
+    // Owning tenant under TENANT_ISOLATION
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant_id: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -107,494 +342,11669 @@ struct ApiResponse<T> {
     success: bool,
     data: Option<T>,
     error: Option<String>,
+    code: Option<ErrorCode>,
 }
 
-#[derive(Serialize)]
-struct PaginatedResponse<T> {
-    data: Vec<T>,
-    page: u32,
-    limit: u32,
-    total: u32,
+// Stable, machine-readable error codes. Clients should branch on `code`;
+// `error` carries the human-readable message and may change wording.
+//
+//   invalid_json       400  body is not valid JSON or has the wrong shape
+//   body_required      400  a JSON body was expected and the request had none
+//   validation_failed  400  a field failed validation
+//   unknown_operation  400  compute operation is not supported
+//   unauthorized       401  missing or wrong credentials
+//   operation_not_allowed 403 the API key may not run this compute operation
+//   csrf_failed        403  X-CSRF-Token missing or doesn't match the csrf_token cookie
+//   origin_not_allowed 403  CORS preflight from an origin not in CORS_ALLOWED_ORIGINS
+//   not_found          404  cache key or file does not exist
+//   user_not_found     404  no user with the given id
+//   email_conflict     409  another user already has this email
+//   user_not_deleted   409  restore called on a user that isn't deleted
+//   invalid_token      400  verification token is malformed or was tampered with
+//   token_expired      410  verification token is past its expiry
+//   patch_test_failed  409  a JSON Patch `test` op didn't match; nothing was applied
+//   unknown_field      422  body has a field the endpoint doesn't accept
+//   unsupported_patch  422  JSON Patch op or path the endpoint doesn't support
+//   invalid_number     422  an integer field got a fraction, a string, or an out-of-range value
+//   non_finite_result  422  a compute result overflowed to Infinity or came out NaN
+//   length_required    411  upload without Content-Length (REQUIRE_CONTENT_LENGTH)
+//   precondition_failed 412 If-Match did not match the current file's ETag
+//   payload_too_large  413  upload is over MAX_UPLOAD_BYTES
+//   response_too_large 413  response is over RESPONSE_HARD_LIMIT_BYTES; page through it
+//   unsupported_media_type 415 upload type isn't in ALLOWED_UPLOAD_TYPES
+//   header_fields_too_large 431 over MAX_REQUEST_HEADERS or MAX_REQUEST_HEADER_BYTES
+//   not_configured     500  the feature needs a secret that isn't set (e.g. SIGNING_SECRET)
+//   storage_unavailable 502 R2 rejected or failed the write; nothing was changed
+//   upstream_unavailable 502 the proxy upstream could not be reached
+//   deadline_exceeded  504  the request ran past REQUEST_DEADLINE_MS
+//   database_busy      503  D1 stayed locked through every retry; see Retry-After
+//   route_disabled     503  the route is switched off (DISABLED_ROUTES or /admin/routes)
+//   overloaded         503  compute is at COMPUTE_MAX_CONCURRENCY; see Retry-After
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    InvalidJson,
+    BodyRequired,
+    ValidationFailed,
+    UnknownOperation,
+    InvalidToken,
+    Unauthorized,
+    OperationNotAllowed,
+    CsrfFailed,
+    OriginNotAllowed,
+    NotFound,
+    UserNotFound,
+    EmailConflict,
+    UserNotDeleted,
+    PatchTestFailed,
+    TokenExpired,
+    UnknownField,
+    UnsupportedPatch,
+    InvalidNumber,
+    NonFiniteResult,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    ResponseTooLarge,
+    UnsupportedMediaType,
+    HeaderFieldsTooLarge,
+    NotConfigured,
+    StorageUnavailable,
+    UpstreamUnavailable,
+    DeadlineExceeded,
+    DatabaseBusy,
+    Overloaded,
+    RouteDisabled,
 }
 
-// ============================================
-// MAIN ENTRY POINT
-// ============================================
+impl ErrorCode {
+    fn status(self) -> u16 {
+        match self {
+            ErrorCode::InvalidJson
+            | ErrorCode::BodyRequired
+            | ErrorCode::ValidationFailed
+            | ErrorCode::UnknownOperation
+            | ErrorCode::InvalidToken => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::OperationNotAllowed
+            | ErrorCode::CsrfFailed
+            | ErrorCode::OriginNotAllowed => 403,
+            ErrorCode::NotFound | ErrorCode::UserNotFound => 404,
+            ErrorCode::EmailConflict | ErrorCode::UserNotDeleted | ErrorCode::PatchTestFailed => {
+                409
+            }
+            ErrorCode::TokenExpired => 410,
+            ErrorCode::LengthRequired => 411,
+            ErrorCode::PreconditionFailed => 412,
+            ErrorCode::PayloadTooLarge | ErrorCode::ResponseTooLarge => 413,
+            ErrorCode::UnsupportedMediaType => 415,
+            ErrorCode::HeaderFieldsTooLarge => 431,
+            ErrorCode::NotConfigured => 500,
+            ErrorCode::UnknownField
+            | ErrorCode::UnsupportedPatch
+            | ErrorCode::InvalidNumber
+            | ErrorCode::NonFiniteResult => 422,
+            ErrorCode::StorageUnavailable | ErrorCode::UpstreamUnavailable => 502,
+            ErrorCode::DatabaseBusy | ErrorCode::Overloaded | ErrorCode::RouteDisabled => 503,
+            ErrorCode::DeadlineExceeded => 504,
+        }
+    }
+}
 
-#[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    // Set up panic hook for debugging
-    console_error_panic_hook::set_once();
+fn error_body(code: ErrorCode, message: impl Into<String>) -> ApiResponse<()> {
+    ApiResponse {
+        success: false,
+        data: None,
+        error: Some(message.into()),
+        code: Some(code),
+    }
+}
 
-    // Router with all routes
-    Router::new()
-        // Health check
-        .get("/health", handle_health)
-        // User CRUD
-        .get("/api/users", handle_list_users)
-        .post("/api/users", handle_create_user)
-        .get("/api/users/:id", handle_get_user)
-        .put("/api/users/:id", handle_update_user)
-        .delete("/api/users/:id", handle_delete_user)
-        // Cache example
-        .get("/api/cached/:key", handle_cache_get)
-        .put("/api/cached/:key", handle_cache_set)
-        // Storage example
-        .get("/api/files/:key", handle_file_get)
-        .put("/api/files/:key", handle_file_upload)
-        // CPU-intensive
-        .post("/api/compute", handle_compute)
-        // Default
-        .get("/", handle_index)
-        .run(req, env)
-        .await
+// Parses a request body, telling a missing body and unknown fields (named in
+// the message) apart from malformed JSON
+fn parse_json_body<T: serde::de::DeserializeOwned>(
+    body: &str,
+) -> std::result::Result<T, (ErrorCode, String)> {
+    if body.trim().is_empty() {
+        return Err((ErrorCode::BodyRequired, "Request body required".to_string()));
+    }
+    serde_json::from_str(body).map_err(|e| {
+        let message = e.to_string();
+        if message.starts_with("unknown field") {
+            let field = message.split('`').nth(1).unwrap_or_default();
+            (ErrorCode::UnknownField, format!("Unknown field: {}", field))
+        } else if let Some(detail) = message.strip_prefix(INVALID_NUMBER) {
+            // serde_json appends " at line L column C"
+            let detail = detail.rsplit_once(" at line ").map_or(detail, |(d, _)| d);
+            (
+                ErrorCode::InvalidNumber,
+                format!("Invalid number: {}", detail),
+            )
+        } else {
+            (ErrorCode::InvalidJson, format!("Invalid JSON: {}", message))
+        }
+    })
 }
 
-// ============================================
-// ROUTE HANDLERS
-// ============================================
+// Integer fields that must be exact use `deserialize_with = "exact_integer"`
+// (or `optional_exact_integer`). JSON numbers are doubles to most clients, so
+// 1.5, 3.0, 1e3, "7" and anything out of range for the field are all refused
+// with 422 invalid_number instead of being rounded, truncated, or reported
+// as malformed JSON.
+const INVALID_NUMBER: &str = "invalid number: ";
 
-async fn handle_index(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    Response::ok("Rust Worker API v1.0")
+fn exact_integer<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    // as_u64 is None for floats, even whole ones, and for negatives
+    value
+        .as_u64()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "{}{} is not a whole number in range",
+                INVALID_NUMBER, value
+            ))
+        })
 }
 
-async fn handle_health(_req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    Response::from_json(&serde_json::json!({
-        "status": "healthy",
-        "timestamp": chrono::Utc::now().to_rfc3339()
-    }))
+fn optional_exact_integer<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        value => exact_integer(value)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
 }
 
-// ============================================
-// USER CRUD HANDLERS
-// ============================================
-
-async fn handle_list_users(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let url = req.url()?;
-    let query: std::collections::HashMap<_, _> = url.query_pairs().collect();
-
-    let page: u32 = query
-        .get("page")
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(1);
-    let limit: u32 = query
-        .get("limit")
-        .and_then(|l| l.parse().ok())
-        .unwrap_or(10)
-        .min(100);
-    let offset = (page - 1) * limit;
+fn error_response(
+    ctx: &RouteContext<ReqCtx>,
+    code: ErrorCode,
+    message: impl Into<String>,
+) -> Result<Response> {
+    respond(ctx, &error_body(code, message)).map(|r| r.with_status(code.status()))
+}
 
-    let db = ctx.env.d1("DB")?;
+// Every batch endpoint answers with a BatchResult: one ItemOutcome per input
+// item, in input order, carrying the status that item would have got as a
+// single request. `BatchResult::status` picks the response status from them.
+#[derive(Serialize)]
+struct ItemOutcome<T> {
+    index: usize,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+}
 
-    // Get users with pagination
-    let users = db
-        .prepare("SELECT * FROM users ORDER BY created_at DESC LIMIT ? OFFSET ?")
-        .bind(&[limit.into(), offset.into()])?
-        .all()
-        .await?
-        .results::<User>()?;
+impl<T> ItemOutcome<T> {
+    fn ok(index: usize, data: T) -> Self {
+        ItemOutcome {
+            index,
+            status: 200,
+            data: Some(data),
+            error: None,
+            code: None,
+        }
+    }
 
-    // Get total count
-    let count: u32 = db
-        .prepare("SELECT COUNT(*) as count FROM users")
-        .first::<serde_json::Value>(None)
-        .await?
-        .and_then(|v| v.get("count").and_then(|c| c.as_u64()))
-        .unwrap_or(0) as u32;
+    fn err(index: usize, code: ErrorCode, message: impl Into<String>) -> Self {
+        ItemOutcome {
+            index,
+            status: code.status(),
+            data: None,
+            error: Some(message.into()),
+            code: Some(code),
+        }
+    }
 
-    let response = PaginatedResponse {
-        data: users,
-        page,
-        limit,
-        total: count,
-    };
+    fn succeeded(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
 
-    Response::from_json(&response)
+#[derive(Serialize)]
+struct BatchResult<T> {
+    results: Vec<ItemOutcome<T>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    timed_out: bool,
+    // Indices never started because the deadline was close
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unprocessed: Vec<usize>,
 }
 
-async fn handle_create_user(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    // Parse body
-    let input: CreateUserRequest = match req.json().await {
-        Ok(data) => data,
-        Err(_) => {
-            return Response::from_json(&ApiResponse::<()> {
-                success: false,
-                data: None,
-                error: Some("Invalid JSON body".to_string()),
-            })
-            .map(|r| r.with_status(400));
+impl<T> BatchResult<T> {
+    fn new(results: Vec<ItemOutcome<T>>) -> Self {
+        BatchResult {
+            results,
+            timed_out: false,
+            unprocessed: Vec::new(),
         }
-    };
-
-    // Validate
-    if input.name.trim().is_empty() {
-        return Response::from_json(&ApiResponse::<()> {
-            success: false,
-            data: None,
-            error: Some("Name is required".to_string()),
-        })
-        .map(|r| r.with_status(400));
     }
 
-    if !input.email.contains('@') {
-        return Response::from_json(&ApiResponse::<()> {
-            success: false,
-            data: None,
-            error: Some("Invalid email".to_string()),
-        })
-        .map(|r| r.with_status(400));
+    fn cut_short(results: Vec<ItemOutcome<T>>, unprocessed: Vec<usize>) -> Self {
+        BatchResult {
+            results,
+            timed_out: !unprocessed.is_empty(),
+            unprocessed,
+        }
     }
 
-    let db = ctx.env.d1("DB")?;
-
-    // Check for existing email
-    let existing = db
-        .prepare("SELECT id FROM users WHERE email = ?")
-        .bind(&[input.email.to_lowercase().into()])?
-        .first::<serde_json::Value>(None)
-        .await?;
+    // The code every item failed with, when they all failed for one reason
+    fn common_error(&self) -> Option<ErrorCode> {
+        if self.timed_out {
+            return None;
+        }
+        let (first, rest) = self.results.split_first()?;
+        let code = first.code?;
+        rest.iter()
+            .all(|item| item.code == Some(code))
+            .then_some(code)
+    }
 
-    if existing.is_some() {
-        return Response::from_json(&ApiResponse::<()> {
-            success: false,
-            data: None,
-            error: Some("Email already exists".to_string()),
-        })
-        .map(|r| r.with_status(409));
+    // 200 when everything succeeded, the shared error's status when every
+    // item failed the same way, and 207 Multi-Status for anything mixed
+    // (including a batch cut short at the deadline)
+    fn status(&self) -> u16 {
+        if let Some(code) = self.common_error() {
+            return code.status();
+        }
+        if self.timed_out || !self.results.iter().all(ItemOutcome::succeeded) {
+            return 207;
+        }
+        200
     }
+}
 
-    // Create user
-    let id = uuid::Uuid::new_v4().to_string();
-    let now = chrono::Utc::now().to_rfc3339();
+fn batch_response<T: Serialize>(
+    ctx: &RouteContext<ReqCtx>,
+    batch: BatchResult<T>,
+) -> Result<Response> {
+    if batch.timed_out {
+        console_warn!(
+            "batch stopped at the deadline, {} left unprocessed",
+            batch.unprocessed.len()
+        );
+    }
+    let status = batch.status();
+    let common = batch.common_error();
+    let error = common.map(|_| format!("All {} items failed", batch.results.len()));
+    respond(
+        ctx,
+        &ApiResponse {
+            success: common.is_none(),
+            data: Some(batch),
+            error,
+            code: common,
+        },
+    )
+    .map(|r| r.with_status(status))
+}
 
-    db.prepare("INSERT INTO users (id, name, email, created_at) VALUES (?, ?, ?, ?)")
-        .bind(&[
-            id.clone().into(),
-            input.name.trim().into(),
-            input.email.to_lowercase().into(),
-            now.clone().into(),
-        ])?
-        .run()
-        .await?;
+#[derive(Serialize)]
+struct PaginatedResponse<T> {
+    data: Vec<T>,
+    page: u32,
+    limit: u32,
+    total: u64,
+    total_pages: u64,
+    has_next: bool,
+    has_prev: bool,
+}
 
-    let user = User {
-        id,
-        name: input.name.trim().to_string(),
-        email: input.email.to_lowercase(),
-        created_at: now,
-    };
+const DEFAULT_PAGE_LIMIT: u32 = 10;
+const MAX_PAGE_LIMIT: u32 = 100;
 
-    Response::from_json(&ApiResponse {
-        success: true,
-        data: Some(user),
-        error: None,
-    })
-    .map(|r| r.with_status(201))
+// Page-based listing math in one place. Pages are 1-based; `new` clamps the
+// page to at least 1 and the limit to 1..=MAX_PAGE_LIMIT, so the offset can't
+// underflow and an empty page count can't divide by zero.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Paginator {
+    page: u32,
+    limit: u32,
 }
 
-async fn handle_get_user(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let id = ctx.param("id").unwrap();
-    let db = ctx.env.d1("DB")?;
+impl Paginator {
+    fn new(page: u32, limit: u32) -> Self {
+        Self {
+            page: page.max(1),
+            limit: limit.clamp(1, MAX_PAGE_LIMIT),
+        }
+    }
 
-    let user = db
-        .prepare("SELECT * FROM users WHERE id = ?")
-        .bind(&[id.into()])?
-        .first::<User>(None)
-        .await?;
+    // Rows to skip; saturates rather than wrapping on absurd page numbers
+    fn offset(&self) -> u32 {
+        (self.page - 1).saturating_mul(self.limit)
+    }
 
-    match user {
-        Some(user) => Response::from_json(&ApiResponse {
-            success: true,
-            data: Some(user),
-            error: None,
-        }),
-        None => Response::from_json(&ApiResponse::<()> {
-            success: false,
-            data: None,
-            error: Some("User not found".to_string()),
-        })
-        .map(|r| r.with_status(404)),
+    // Zero when there are no rows at all
+    fn total_pages(&self, total: u64) -> u64 {
+        total.div_ceil(u64::from(self.limit))
+    }
+
+    fn build<T>(&self, data: Vec<T>, total: u64) -> PaginatedResponse<T> {
+        PaginatedResponse {
+            data,
+            page: self.page,
+            limit: self.limit,
+            total,
+            total_pages: self.total_pages(total),
+            has_next: u64::from(self.page) < self.total_pages(total),
+            has_prev: self.page > 1,
+        }
     }
 }
 
-async fn handle_update_user(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let id = ctx.param("id").unwrap();
-    let db = ctx.env.d1("DB")?;
+// Keyset page: pass `next_cursor` back as `?cursor=` for the following page
+#[derive(Serialize)]
+struct CursorPage<T> {
+    data: Vec<T>,
+    next_cursor: Option<String>,
+}
 
-    // Check if user exists
-    let existing = db
-        .prepare("SELECT * FROM users WHERE id = ?")
-        .bind(&[id.into()])?
-        .first::<User>(None)
-        .await?;
+// ============================================
+// CONFIGURATION
+// ============================================
 
-    let mut user = match existing {
-        Some(u) => u,
-        None => {
-            return Response::from_json(&ApiResponse::<()> {
-                success: false,
-                data: None,
-                error: Some("User not found".to_string()),
-            })
-            .map(|r| r.with_status(404));
-        }
-    };
+// Tunables read from wrangler vars, with defaults for anything unset
+struct Config {
+    // Access log lines: "json" (one object per line) or "text"
+    log_format: LogFormat,
+    // Full access log lines for 1 in this many requests (1 logs all in full);
+    // the rest get a minimal line. Errors and slow requests are always full.
+    log_sample_rate: u32,
+    // Requests taking at least this long skip sampling
+    log_slow_ms: u64,
+    // Cache-Control per GET route pattern, as `pattern=value` pairs split on ';'
+    route_cache_control: Vec<(String, String)>,
+    // Max request body bytes per route pattern, as `pattern=bytes` pairs split
+    // on ';'. Routes without an entry are left to their handlers.
+    route_body_limits: Vec<(String, u64)>,
+    // Used for R2 downloads whose object has no stored Cache-Control
+    file_cache_control: String,
+    // Guess a download's type from its key extension when none was stored
+    sniff_content_type: bool,
+    // User change events are POSTed here when set
+    webhook_url: Option<String>,
+    // Each delivery attempt is abandoned after this long
+    webhook_timeout_ms: u64,
+    // Base URL for /api/proxy/*; unset disables the proxy
+    proxy_upstream: Option<String>,
+    // Charset recorded for text/* uploads that declare none and have no BOM
+    upload_default_charset: String,
+    // Reject text/* uploads whose bytes aren't valid UTF-8 (when UTF-8 is the charset)
+    validate_text_uploads: bool,
+    // Treat an application/json PUT /api/files/:key as a base64 envelope
+    // rather than a JSON file to store as-is
+    base64_json_uploads: bool,
+    // Content types uploads may declare (`image/*` wildcards allowed); unset allows any
+    allowed_upload_types: Option<Vec<String>>,
+    // Also check the type recognised from the upload's leading bytes
+    sniff_upload_types: bool,
+    max_upload_bytes: u64,
+    // Serialized responses over this are logged with a warning
+    response_soft_limit_bytes: u64,
+    // ... and over this are replaced with a 413 pointing at pagination
+    response_hard_limit_bytes: u64,
+    // Answer 411 to non-chunked uploads without a Content-Length
+    require_content_length: bool,
+    // Requests with more headers than this, or more name + value bytes across
+    // them, get a 431 before any other work
+    max_request_headers: usize,
+    max_request_header_bytes: usize,
+    // Max in-flight subrequests for batch operations
+    batch_concurrency: usize,
+    // KV namespaces the /api/cached entries are spread over (CACHE, CACHE_1, ...)
+    cache_shards: usize,
+    // Comma-separated list, or "*" for any origin
+    cors_allowed_origins: Vec<String>,
+    // How long browsers may cache a preflight, in seconds
+    cors_max_age: u32,
+    cors_allow_credentials: bool,
+    // Seconds a user stays in the read-through cache (KV minimum is 60)
+    user_cache_ttl: u64,
+    // How long a cache-fill lock holds off other misses for the same user
+    user_cache_lock_ttl_ms: u64,
+    // How long a miss waits on someone else's fill before going to D1 itself
+    user_cache_lock_wait_ms: u64,
+    // Seconds past USER_CACHE_TTL an entry is kept to serve if D1 fails (0 = off)
+    user_cache_stale_ttl: u64,
+    // Deepest OFFSET page-based listing will run; past it, use the cursor endpoint
+    max_offset: u32,
+    // Serve list totals from the UserCounter object instead of COUNT(*)
+    user_counter: bool,
+    // ETag + If-None-Match/304 on GET /api/users; costs one aggregate query
+    list_etags: bool,
+    // 400 for query params a user GET route doesn't read (`?limt=10`)
+    // instead of ignoring them
+    strict_query_params: bool,
+    // Route patterns answered with 503 route_disabled, split on ';'
+    disabled_routes: Vec<String>,
+    // Also honor routes switched off at runtime via /admin/routes (one KV
+    // read per request)
+    route_toggles: bool,
+    // Encrypt user emails in D1 with EMAIL_ENCRYPTION_KEY
+    email_encryption: bool,
+    // Scope every user route to the tenant of the caller's X-API-Key
+    tenant_isolation: bool,
+    // GET /api/users/:id needs an API key or the admin key, and every failure is the same 404
+    hide_user_existence: bool,
+    // ... padded to at least this long, so a hit and a miss take the same time
+    hide_user_existence_floor_ms: u64,
+    // Seconds an email verification link stays valid
+    email_verify_ttl: i64,
+    // Domains whose `local+tag@` addresses count as `local@` for uniqueness
+    // ("*" for every domain); unset keeps addresses as they are
+    email_plus_normalize_domains: Vec<String>,
+    // Route user queries through D1 sessions so reads can use replicas
+    d1_read_replicas: bool,
+    // Extra attempts for a write that hits "database is locked"
+    d1_locked_retries: u32,
+    // Retry-After seconds on the 503 once those attempts are used up
+    d1_locked_retry_after: u32,
+    // Reject compute calls without an X-API-Key (keys are always checked when sent)
+    compute_require_api_key: bool,
+    // In-flight compute requests across all isolates before shedding; unset is unlimited
+    compute_max_concurrency: Option<u32>,
+    // Accumulate approximate compute cost per API key (ComputeUsage object)
+    compute_usage: bool,
+    // Operation for compute bodies that leave `operation` out
+    compute_default_operation: String,
+    // Time limit for each wait_until task; the runtime allows ~30s after the response
+    background_task_budget_ms: u64,
+    // Budget for a whole request; D1/KV/R2/fetch calls get whatever is left
+    request_deadline_ms: u64,
+    // Each /health probe is reported as a timeout after this long
+    health_probe_timeout_ms: u64,
+    // Soft-deleted users are hard-deleted after this many days
+    retention_deleted_days: u32,
+    // Enables /api/debug/echo; leave off outside development
+    debug_echo: bool,
+    // Double-submit CSRF check on state-changing requests that carry cookies
+    csrf_protection: bool,
+    // Honor X-HTTP-Method-Override on POST, for clients behind proxies that drop PUT/DELETE
+    method_override: bool,
+    // Reuse a well-formed inbound X-Request-Id as this request's id
+    trust_request_id: bool,
+    // Leave null top-level fields (`data`, `error`, `code`) out of responses
+    // instead of sending them as null; `Prefer: nulls=omit|include` overrides
+    omit_null_fields: bool,
+    // HSTS, nosniff, Referrer-Policy on every response, plus X-Frame-Options
+    // and the CSP on anything a browser might render
+    security_headers: bool,
+    content_security_policy: String,
+    // Users untouched for this many days are anonymized; unset disables it
+    retention_inactive_days: Option<u32>,
+    // Past this many soft-deleted users, the oldest ones already past the
+    // retention window are hard-deleted right after a delete instead of
+    // waiting for the cron; unset disables it
+    retention_max_deleted: Option<u32>,
+    // Let the cap also hard-delete rows still inside the retention window
+    retention_cap_ignores_window: bool,
+    // Check the expected D1 indexes exist on each isolate's first request
+    // (one PRAGMA, off the request path) and warn about any that are missing
+    index_check_on_start: bool,
+}
 
-    // Parse update data
-    let input: UpdateUserRequest = match req.json().await {
-        Ok(data) => data,
-        Err(_) => {
-            return Response::from_json(&ApiResponse::<()> {
-                success: false,
-                data: None,
-                error: Some("Invalid JSON body".to_string()),
-            })
-            .map(|r| r.with_status(400));
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_format: LogFormat::Text,
+            log_sample_rate: 1,
+            log_slow_ms: 1000,
+            route_cache_control: parse_route_cache_control(DEFAULT_ROUTE_CACHE_CONTROL),
+            route_body_limits: parse_route_body_limits(DEFAULT_ROUTE_BODY_LIMITS),
+            file_cache_control: "public, max-age=3600".to_string(),
+            sniff_content_type: false,
+            webhook_url: None,
+            webhook_timeout_ms: 5_000,
+            proxy_upstream: None,
+            upload_default_charset: "utf-8".to_string(),
+            validate_text_uploads: false,
+            base64_json_uploads: false,
+            allowed_upload_types: None,
+            sniff_upload_types: false,
+            max_upload_bytes: 100 * 1024 * 1024,
+            response_soft_limit_bytes: 1024 * 1024,
+            response_hard_limit_bytes: 8 * 1024 * 1024,
+            require_content_length: false,
+            max_request_headers: 100,
+            max_request_header_bytes: 32 * 1024,
+            batch_concurrency: 6,
+            cache_shards: 1,
+            cors_allowed_origins: vec!["*".to_string()],
+            cors_max_age: 86400,
+            cors_allow_credentials: false,
+            user_cache_ttl: 300,
+            user_cache_lock_ttl_ms: 5_000,
+            user_cache_lock_wait_ms: 500,
+            user_cache_stale_ttl: 0,
+            max_offset: 10_000,
+            user_counter: false,
+            list_etags: true,
+            strict_query_params: false,
+            disabled_routes: Vec::new(),
+            route_toggles: false,
+            email_encryption: false,
+            tenant_isolation: false,
+            hide_user_existence: false,
+            hide_user_existence_floor_ms: 100,
+            email_verify_ttl: 86_400,
+            email_plus_normalize_domains: Vec::new(),
+            d1_read_replicas: false,
+            d1_locked_retries: 2,
+            d1_locked_retry_after: 1,
+            compute_require_api_key: false,
+            compute_max_concurrency: None,
+            compute_usage: false,
+            compute_default_operation: "summary".to_string(),
+            debug_echo: false,
+            csrf_protection: false,
+            method_override: false,
+            trust_request_id: true,
+            omit_null_fields: false,
+            security_headers: true,
+            content_security_policy: "default-src 'none'; frame-ancestors 'none'".to_string(),
+            background_task_budget_ms: 25_000,
+            request_deadline_ms: 15_000,
+            health_probe_timeout_ms: 2_000,
+            retention_deleted_days: 30,
+            retention_inactive_days: None,
+            retention_max_deleted: None,
+            retention_cap_ignores_window: false,
+            index_check_on_start: false,
         }
-    };
+    }
+}
 
-    // Apply updates
-    if let Some(name) = input.name {
-        if name.trim().is_empty() {
-            return Response::from_json(&ApiResponse::<()> {
-                success: false,
-                data: None,
-                error: Some("Name cannot be empty".to_string()),
-            })
-            .map(|r| r.with_status(400));
+impl Config {
+    fn from_env(env: &Env) -> Self {
+        Self::from_lookup(|name| env.var(name).ok().map(|v| v.to_string()))
+    }
+
+    fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Self {
+        let defaults = Self::default();
+        let flag = |name: &str| matches!(get(name).as_deref(), Some("true") | Some("1"));
+        Self {
+            log_format: match get("LOG_FORMAT").as_deref() {
+                Some("json") => LogFormat::Json,
+                _ => defaults.log_format,
+            },
+            log_sample_rate: get("LOG_SAMPLE_RATE")
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(defaults.log_sample_rate),
+            log_slow_ms: get("LOG_SLOW_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.log_slow_ms),
+            route_cache_control: get("ROUTE_CACHE_CONTROL")
+                .map(|v| parse_route_cache_control(&v))
+                .unwrap_or(defaults.route_cache_control),
+            route_body_limits: get("ROUTE_BODY_LIMITS")
+                .map(|v| parse_route_body_limits(&v))
+                .unwrap_or(defaults.route_body_limits),
+            file_cache_control: get("FILE_CACHE_CONTROL").unwrap_or(defaults.file_cache_control),
+            sniff_content_type: flag("SNIFF_CONTENT_TYPE"),
+            webhook_url: get("WEBHOOK_URL").filter(|url| !url.is_empty()),
+            webhook_timeout_ms: get("WEBHOOK_TIMEOUT_MS")
+                .and_then(|v| v.parse().ok())
+                .filter(|&ms| ms > 0)
+                .unwrap_or(defaults.webhook_timeout_ms),
+            proxy_upstream: get("PROXY_UPSTREAM")
+                .filter(|url| !url.is_empty())
+                .map(|url| url.trim_end_matches('/').to_string()),
+            upload_default_charset: get("UPLOAD_DEFAULT_CHARSET")
+                .unwrap_or(defaults.upload_default_charset),
+            validate_text_uploads: flag("VALIDATE_TEXT_UPLOADS"),
+            base64_json_uploads: flag("BASE64_JSON_UPLOADS"),
+            allowed_upload_types: get("ALLOWED_UPLOAD_TYPES")
+                .map(|v| {
+                    v.split(',')
+                        .map(|t| t.trim().to_ascii_lowercase())
+                        .filter(|t| !t.is_empty())
+                        .collect::<Vec<_>>()
+                })
+                .filter(|types| !types.is_empty()),
+            sniff_upload_types: flag("SNIFF_UPLOAD_TYPES"),
+            max_upload_bytes: get("MAX_UPLOAD_BYTES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_upload_bytes),
+            response_soft_limit_bytes: get("RESPONSE_SOFT_LIMIT_BYTES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.response_soft_limit_bytes),
+            response_hard_limit_bytes: get("RESPONSE_HARD_LIMIT_BYTES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.response_hard_limit_bytes),
+            require_content_length: flag("REQUIRE_CONTENT_LENGTH"),
+            max_request_headers: get("MAX_REQUEST_HEADERS")
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(defaults.max_request_headers),
+            max_request_header_bytes: get("MAX_REQUEST_HEADER_BYTES")
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(defaults.max_request_header_bytes),
+            batch_concurrency: get("BATCH_CONCURRENCY")
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(defaults.batch_concurrency),
+            cache_shards: get("CACHE_SHARDS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.cache_shards),
+            cors_allowed_origins: get("CORS_ALLOWED_ORIGINS")
+                .map(|v| {
+                    v.split(',')
+                        .map(|o| o.trim().to_string())
+                        .filter(|o| !o.is_empty())
+                        .collect()
+                })
+                .unwrap_or(defaults.cors_allowed_origins),
+            cors_max_age: get("CORS_MAX_AGE")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.cors_max_age),
+            cors_allow_credentials: flag("CORS_ALLOW_CREDENTIALS"),
+            user_cache_ttl: get("USER_CACHE_TTL")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.user_cache_ttl)
+                .max(60),
+            user_cache_lock_ttl_ms: get("USER_CACHE_LOCK_TTL_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.user_cache_lock_ttl_ms),
+            user_cache_lock_wait_ms: get("USER_CACHE_LOCK_WAIT_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.user_cache_lock_wait_ms),
+            user_cache_stale_ttl: get("USER_CACHE_STALE_TTL")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.user_cache_stale_ttl),
+            max_offset: get("MAX_OFFSET")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_offset),
+            user_counter: flag("USER_COUNTER"),
+            list_etags: get("LIST_ETAGS").map_or(defaults.list_etags, |v| v != "false"),
+            strict_query_params: flag("STRICT_QUERY_PARAMS"),
+            disabled_routes: get("DISABLED_ROUTES")
+                .map(|v| {
+                    v.split(';')
+                        .map(|route| route.trim().to_string())
+                        .filter(|route| !route.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            route_toggles: flag("ROUTE_TOGGLES"),
+            tenant_isolation: flag("TENANT_ISOLATION"),
+            hide_user_existence: flag("HIDE_USER_EXISTENCE"),
+            hide_user_existence_floor_ms: get("HIDE_USER_EXISTENCE_FLOOR_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.hide_user_existence_floor_ms),
+            email_encryption: flag("EMAIL_ENCRYPTION"),
+            email_verify_ttl: get("EMAIL_VERIFY_TTL")
+                .and_then(|v| v.parse().ok())
+                .filter(|&ttl| ttl > 0)
+                .unwrap_or(defaults.email_verify_ttl),
+            email_plus_normalize_domains: get("EMAIL_PLUS_NORMALIZE_DOMAINS")
+                .map(|v| {
+                    v.split(',')
+                        .map(|d| d.trim().to_ascii_lowercase())
+                        .filter(|d| !d.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            d1_read_replicas: flag("D1_READ_REPLICAS"),
+            d1_locked_retries: get("D1_LOCKED_RETRIES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.d1_locked_retries),
+            d1_locked_retry_after: get("D1_LOCKED_RETRY_AFTER")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.d1_locked_retry_after),
+            compute_require_api_key: flag("COMPUTE_REQUIRE_API_KEY"),
+            compute_max_concurrency: get("COMPUTE_MAX_CONCURRENCY")
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0),
+            compute_usage: flag("COMPUTE_USAGE"),
+            compute_default_operation: get("COMPUTE_DEFAULT_OPERATION")
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .unwrap_or(defaults.compute_default_operation),
+            debug_echo: flag("DEBUG_ECHO"),
+            csrf_protection: flag("CSRF_PROTECTION"),
+            method_override: flag("METHOD_OVERRIDE"),
+            trust_request_id: get("TRUST_REQUEST_ID")
+                .map(|v| matches!(v.as_str(), "true" | "1"))
+                .unwrap_or(defaults.trust_request_id),
+            omit_null_fields: flag("OMIT_NULL_FIELDS"),
+            security_headers: get("SECURITY_HEADERS")
+                .map(|v| matches!(v.as_str(), "true" | "1"))
+                .unwrap_or(defaults.security_headers),
+            content_security_policy: get("CONTENT_SECURITY_POLICY")
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .unwrap_or(defaults.content_security_policy),
+            background_task_budget_ms: get("BACKGROUND_TASK_BUDGET_MS")
+                .and_then(|v| v.parse().ok())
+                .filter(|&ms| ms > 0)
+                .unwrap_or(defaults.background_task_budget_ms),
+            request_deadline_ms: get("REQUEST_DEADLINE_MS")
+                .and_then(|v| v.parse().ok())
+                .filter(|&ms| ms > 0)
+                .unwrap_or(defaults.request_deadline_ms),
+            health_probe_timeout_ms: get("HEALTH_PROBE_TIMEOUT_MS")
+                .and_then(|v| v.parse().ok())
+                .filter(|&ms| ms > 0)
+                .unwrap_or(defaults.health_probe_timeout_ms),
+            retention_deleted_days: get("RETENTION_DELETED_DAYS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.retention_deleted_days),
+            retention_inactive_days: get("RETENTION_INACTIVE_DAYS").and_then(|v| v.parse().ok()),
+            retention_max_deleted: get("RETENTION_MAX_DELETED")
+                .and_then(|v| v.parse().ok())
+                .filter(|&max| max > 0),
+            retention_cap_ignores_window: flag("RETENTION_CAP_IGNORES_WINDOW"),
+            index_check_on_start: flag("INDEX_CHECK_ON_START"),
         }
-        user.name = name.trim().to_string();
     }
 
-    if let Some(email) = input.email {
-        if !email.contains('@') {
-            return Response::from_json(&ApiResponse::<()> {
-                success: false,
-                data: None,
-                error: Some("Invalid email".to_string()),
-            })
-            .map(|r| r.with_status(400));
+    // Combinations that would produce broken behavior are rejected up front
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.cors_allow_credentials && self.cors_allowed_origins.iter().any(|o| o == "*") {
+            return Err(
+                "CORS_ALLOW_CREDENTIALS requires explicit CORS_ALLOWED_ORIGINS; browsers reject \"*\" with credentials"
+                    .to_string(),
+            );
+        }
+        if self.response_soft_limit_bytes > self.response_hard_limit_bytes {
+            return Err(
+                "RESPONSE_SOFT_LIMIT_BYTES must not be above RESPONSE_HARD_LIMIT_BYTES".to_string(),
+            );
+        }
+        // Otherwise the background budget cuts delivery off before the dead letter is written
+        if self.webhook_url.is_some()
+            && webhook_worst_case_ms(self.webhook_timeout_ms) >= self.background_task_budget_ms
+        {
+            return Err(format!(
+                "WEBHOOK_TIMEOUT_MS is too long: {} attempts plus backoff must fit in \
+                 BACKGROUND_TASK_BUDGET_MS",
+                WEBHOOK_MAX_ATTEMPTS
+            ));
         }
-        user.email = email.to_lowercase();
+        // A hung probe must be reported, not turned into a 504 for the whole check
+        if self.health_probe_timeout_ms >= self.request_deadline_ms {
+            return Err("HEALTH_PROBE_TIMEOUT_MS must be below REQUEST_DEADLINE_MS".to_string());
+        }
+        if !(1..=CACHE_SHARD_BINDINGS.len()).contains(&self.cache_shards) {
+            return Err(format!(
+                "CACHE_SHARDS must be between 1 and {}",
+                CACHE_SHARD_BINDINGS.len()
+            ));
+        }
+        if !COMPUTE_OPERATIONS.contains(&self.compute_default_operation.as_str()) {
+            return Err(format!(
+                "COMPUTE_DEFAULT_OPERATION must be one of: {}",
+                COMPUTE_OPERATIONS.join(", ")
+            ));
+        }
+        if let Some(inactive) = self.retention_inactive_days {
+            if inactive <= self.retention_deleted_days {
+                return Err(
+                    "RETENTION_INACTIVE_DAYS must be longer than RETENTION_DELETED_DAYS"
+                        .to_string(),
+                );
+            }
+        }
+        Ok(())
     }
+}
 
-    // Update in database
-    db.prepare("UPDATE users SET name = ?, email = ? WHERE id = ?")
-        .bind(&[user.name.clone().into(), user.email.clone().into(), id.into()])?
-        .run()
-        .await?;
+// Per-request state handed to every handler through the router
+struct ReqCtx {
+    config: Config,
+    // Serialization picked from the Accept header
+    format: Format,
+    // OMIT_NULL_FIELDS, or the request's Prefer header
+    omit_nulls: bool,
+    // For background work; go through spawn_background rather than wait_until
+    worker_ctx: Context,
+    // When the request's REQUEST_DEADLINE_MS runs out; see within_deadline
+    deadline: Deadline,
+    // Ids tying background work (webhook deliveries) back to this request
+    correlation: Correlation,
+}
 
-    Response::from_json(&ApiResponse {
-        success: true,
-        data: Some(user),
-        error: None,
-    })
+// ============================================
+// RESPONSE FORMATS
+// ============================================
+
+// Structured responses go through `respond`, which serializes with the format
+// negotiated from the request's Accept header. Adding a format means adding an
+// impl and a `Format` variant; handlers don't change.
+trait ResponseFormat {
+    fn content_type(&self) -> &'static str;
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
 }
 
-async fn handle_delete_user(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let id = ctx.param("id").unwrap();
-    let db = ctx.env.d1("DB")?;
+struct JsonFormat;
 
-    let result = db
-        .prepare("DELETE FROM users WHERE id = ?")
-        .bind(&[id.into()])?
-        .run()
-        .await?;
+impl ResponseFormat for JsonFormat {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
 
-    if result.meta().map(|m| m.changes).unwrap_or(0) == 0 {
-        return Response::from_json(&ApiResponse::<()> {
-            success: false,
-            data: None,
-            error: Some("User not found".to_string()),
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+}
+
+// `?pretty=true` for reading responses in a terminal. Same media type as
+// JsonFormat; only whitespace differs.
+struct PrettyJsonFormat;
+
+impl ResponseFormat for PrettyJsonFormat {
+    fn content_type(&self) -> &'static str {
+        JsonFormat.content_type()
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(value)?)
+    }
+}
+
+struct MessagePackFormat;
+
+impl ResponseFormat for MessagePackFormat {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        // Named fields, so maps decode the same shape as the JSON objects
+        rmp_serde::to_vec_named(value).map_err(|e| Error::RustError(e.to_string()))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Format {
+    Json,
+    PrettyJson,
+    MessagePack,
+}
+
+impl Format {
+    // Pretty-printing only applies to JSON; binary formats ignore the flag
+    fn with_pretty(self, pretty: bool) -> Self {
+        match self {
+            Format::Json if pretty => Format::PrettyJson,
+            format => format,
+        }
+    }
+}
+
+impl ResponseFormat for Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => JsonFormat.content_type(),
+            Format::PrettyJson => PrettyJsonFormat.content_type(),
+            Format::MessagePack => MessagePackFormat.content_type(),
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Format::Json => JsonFormat.serialize(value),
+            Format::PrettyJson => PrettyJsonFormat.serialize(value),
+            Format::MessagePack => MessagePackFormat.serialize(value),
+        }
+    }
+}
+
+// Highest-q supported media type wins; JSON when nothing matches
+fn negotiate_format(accept: Option<&str>) -> Format {
+    let Some(accept) = accept else {
+        return Format::Json;
+    };
+
+    let mut ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .map(|range| {
+            let mut parts = range.split(';');
+            let media = parts.next().unwrap_or("").trim();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (media, q)
         })
-        .map(|r| r.with_status(404));
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    for (media, _) in ranges {
+        match media.to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => return Format::Json,
+            "application/msgpack" | "application/x-msgpack" => return Format::MessagePack,
+            _ => {}
+        }
     }
+    Format::Json
+}
 
-    Response::from_json(&ApiResponse::<()> {
-        success: true,
-        data: None,
-        error: None,
-    })
+// `Prefer: nulls=omit` or `nulls=include` (RFC 7240 preference syntax) picks
+// the shape for one request; anything else leaves the configured default
+fn prefer_omit_nulls(prefer: Option<&str>, default: bool) -> bool {
+    prefer
+        .into_iter()
+        .flat_map(|header| header.split([',', ';']))
+        .filter_map(|pref| pref.trim().split_once('='))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("nulls"))
+        .find_map(|(_, value)| match value.trim().trim_matches('"') {
+            "omit" => Some(true),
+            "include" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(default)
+}
+
+// Under OMIT_NULL_FIELDS / `Prefer: nulls=omit` the body goes through a
+// Value and only its top-level nulls are dropped; nulls inside `data` are
+// part of the resource (a null `deleted_at` means not deleted) and stay.
+// Keys come out sorted on that path.
+fn serialize_body<T: Serialize>(format: &Format, value: &T, omit_nulls: bool) -> Result<Vec<u8>> {
+    if !omit_nulls {
+        return format.serialize(value);
+    }
+    let mut value = serde_json::to_value(value)?;
+    if let serde_json::Value::Object(fields) = &mut value {
+        fields.retain(|_, field| !field.is_null());
+    }
+    format.serialize(&value)
+}
+
+// Rejections made in `fetch` before the router runs, serialized the way
+// `error_response` would
+fn early_error(
+    format: Format,
+    omit_nulls: bool,
+    code: ErrorCode,
+    message: impl Into<String>,
+) -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Content-Type", format.content_type())?;
+    let body = serialize_body(&format, &error_body(code, message), omit_nulls)?;
+    Ok(Response::from_bytes(body)?
+        .with_headers(headers)
+        .with_status(code.status()))
+}
+
+fn respond<T: Serialize>(ctx: &RouteContext<ReqCtx>, value: &T) -> Result<Response> {
+    let format = &ctx.data.format;
+    let config = &ctx.data.config;
+    let omit_nulls = ctx.data.omit_nulls;
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", format.content_type())?;
+
+    let body = serialize_body(format, value, omit_nulls)?;
+    match response_size(
+        body.len(),
+        config.response_soft_limit_bytes,
+        config.response_hard_limit_bytes,
+    ) {
+        ResponseSize::Normal => {}
+        ResponseSize::OverSoft => {
+            console_warn!("large response: {} bytes", body.len());
+            // Still sent whole; the header says so for clients checking
+            headers.set("X-Response-Truncated", "false")?;
+        }
+        ResponseSize::OverHard => {
+            console_error!(
+                "response of {} bytes over the {} byte limit",
+                body.len(),
+                config.response_hard_limit_bytes
+            );
+            let code = ErrorCode::ResponseTooLarge;
+            let error = error_body(code, "Response too large; request fewer items per page");
+            return Ok(
+                Response::from_bytes(serialize_body(format, &error, omit_nulls)?)?
+                    .with_headers(headers)
+                    .with_status(code.status()),
+            );
+        }
+    }
+
+    Ok(Response::from_bytes(body)?.with_headers(headers))
+}
+
+// Only bodies built by `respond` are measured; streamed bodies (files,
+// proxied responses, SSE) never pass through it
+#[derive(Debug, PartialEq)]
+enum ResponseSize {
+    Normal,
+    OverSoft,
+    OverHard,
+}
+
+fn response_size(len: usize, soft: u64, hard: u64) -> ResponseSize {
+    let len = len as u64;
+    if len > hard {
+        ResponseSize::OverHard
+    } else if len > soft {
+        ResponseSize::OverSoft
+    } else {
+        ResponseSize::Normal
+    }
 }
 
 // ============================================
-// KV CACHE HANDLERS
+// MAIN ENTRY POINT
 // ============================================
 
-async fn handle_cache_get(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let key = ctx.param("key").unwrap();
-    let kv = ctx.kv("CACHE")?;
+#[event(fetch)]
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    // Set up panic hook for debugging
+    console_error_panic_hook::set_once();
+
+    let config = Config::from_env(&env);
+    if let Err(message) = config.validate() {
+        console_error!("invalid configuration: {}", message);
+        return Response::error(format!("Configuration error: {}", message), 500);
+    }
+    let check = SELF_CHECK.get_or_init(|| {
+        let check = run_self_check(&config, |name, kind| probe_binding(&env, name, kind));
+        if check.missing_critical.is_empty() {
+            console_log!("{}", check.summary());
+        } else {
+            console_error!("{}", check.summary());
+        }
+        check
+    });
+    if let Some(message) = check.error() {
+        return Response::error(format!("Configuration error: {}", message), 500);
+    }
 
-    let value = kv.get(key).text().await?;
+    // Negotiated up front so rejections below match handler responses
+    let url = req.url()?;
+    let pretty = url
+        .query_pairs()
+        .any(|(name, value)| name == "pretty" && value == "true");
+    let format = negotiate_format(req.headers().get("Accept")?.as_deref()).with_pretty(pretty);
+    let omit_nulls = prefer_omit_nulls(
+        req.headers().get("Prefer")?.as_deref(),
+        config.omit_null_fields,
+    );
+    let reject = |code: ErrorCode, message: String| early_error(format, omit_nulls, code, message);
 
-    match value {
-        Some(v) => Response::ok(v),
-        None => Response::error("Not found", 404),
+    if let Some(message) = header_limit_error(
+        req.headers().entries(),
+        config.max_request_headers,
+        config.max_request_header_bytes,
+    ) {
+        return reject(ErrorCode::HeaderFieldsTooLarge, message);
+    }
+    if config.index_check_on_start && INDEX_CHECK_STARTED.set(()).is_ok() {
+        if let Ok(db) = env.d1("DB") {
+            ctx.wait_until(async move {
+                match index_names(&db).await {
+                    Ok(names) => {
+                        for warning in index_warnings(&index_report(EXPECTED_INDEXES, &names)) {
+                            console_warn!("{}", warning);
+                        }
+                    }
+                    Err(e) => console_error!("index check failed: {}", e),
+                }
+            });
+        }
     }
-}
 
-async fn handle_cache_set(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let key = ctx.param("key").unwrap();
-    let kv = ctx.kv("CACHE")?;
+    let req = if config.method_override {
+        match method_override(
+            &req.method(),
+            req.headers().get(METHOD_OVERRIDE_HEADER)?.as_deref(),
+        ) {
+            Ok(Some(method)) => with_method(&req, method)?,
+            Ok(None) => req,
+            Err(message) => return reject(ErrorCode::ValidationFailed, message),
+        }
+    } else {
+        req
+    };
 
-    let body = req.text().await?;
+    let cors = cors_for(&config, req.headers().get("Origin")?.as_deref());
+    // A per-origin Allow-Origin must not be served from a shared cache to other origins
+    let vary_origin = !config.cors_allowed_origins.iter().any(|o| o == "*");
+    if req.method() == Method::Options {
+        return match cors {
+            Some(cors) => Response::empty()?
+                .with_status(204)
+                .with_cors(&cors.with_max_age(config.cors_max_age)),
+            None => reject(
+                ErrorCode::OriginNotAllowed,
+                "Origin not allowed".to_string(),
+            ),
+        };
+    }
 
-    // Set with 1 hour expiration
-    kv.put(key, body)?
-        .expiration_ttl(3600)
-        .execute()
-        .await?;
+    let cookie_header = req.headers().get("Cookie")?;
+    let csrf_ok = !config.csrf_protection
+        || csrf_check(
+            &req.method(),
+            cookie_header.as_deref(),
+            req.headers().get(CSRF_HEADER)?.as_deref(),
+            req.headers().has("Authorization")? || req.headers().has("X-API-Key")?,
+        );
+    let issue_csrf_cookie = config.csrf_protection
+        && cookie_header
+            .as_deref()
+            .and_then(|cookies| cookie_value(cookies, CSRF_COOKIE))
+            .is_none();
 
-    Response::ok("Cached")
+    let started = Date::now().as_millis();
+    let admin_key = env.secret("ADMIN_KEY").ok().map(|k| k.to_string());
+    let mut access = AccessLog {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        request_id: resolve_request_id(
+            req.headers()
+                .get(REQUEST_ID_HEADER)?
+                .filter(|_| config.trust_request_id)
+                .as_deref(),
+            // cf-ray is unique per request at the edge; fall back for local dev
+            || {
+                req.headers()
+                    .get("cf-ray")
+                    .ok()
+                    .flatten()
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+            },
+        ),
+        method: req.method().to_string(),
+        path: url.path().to_string(),
+        status: 0,
+        duration_ms: 0,
+        country: req.cf().and_then(|cf| cf.country()),
+        subject: admin_key
+            .filter(|key| {
+                bearer_matches(
+                    req.headers().get("Authorization").ok().flatten().as_deref(),
+                    key,
+                )
+            })
+            .map(|_| "admin".to_string()),
+    };
+    let log_format = config.log_format;
+    let (log_sample_rate, log_slow_ms) = (config.log_sample_rate, config.log_slow_ms);
+    let cache_rules = config.route_cache_control.clone();
+    let locked_retry_after = config.d1_locked_retry_after;
+    let csp = config
+        .security_headers
+        .then(|| config.content_security_policy.clone());
+    let cacheable = matches!(req.method(), Method::Get | Method::Head);
+
+    let runtime_disabled = if config.route_toggles {
+        runtime_disabled_routes(&env).await
+    } else {
+        Vec::new()
+    };
+    let route_rejected = disabled_route(&config.disabled_routes, &runtime_disabled, &access.path)
+        .map(|_| {
+            (
+                ErrorCode::RouteDisabled,
+                format!("{} is temporarily disabled", access.path),
+            )
+        });
+    let query_rejected = if config.strict_query_params && cacheable {
+        let names: Vec<String> = url
+            .query_pairs()
+            .map(|(name, _)| name.into_owned())
+            .collect();
+        unknown_query_param(&access.path, &names).map(|name| {
+            (
+                ErrorCode::ValidationFailed,
+                format!("Unknown query parameter: {}", name),
+            )
+        })
+    } else {
+        None
+    };
+    let body_limit = body_limit_for(&config.route_body_limits, &access.path)
+        .filter(|_| !matches!(req.method(), Method::Get | Method::Head));
+    let (req, body_rejected) = match body_limit {
+        Some(max) => limit_body(req, max).await?,
+        None => (req, None),
+    };
+
+    let deadline = Deadline::after(started, config.request_deadline_ms);
+    let correlation = Correlation {
+        request_id: access.request_id.clone(),
+        trace_id: trace_id_from(req.headers().get("traceparent")?.as_deref()),
+    };
+    let analytics = analytics_dataset(&env);
+    let data = ReqCtx {
+        config,
+        format,
+        omit_nulls,
+        worker_ctx: ctx,
+        deadline,
+        correlation,
+    };
+
+    // Router with all routes
+    let router = Router::with_data(data)
+        // Health check
+        .get("/health", handle_health)
+        // User CRUD
+        .get("/api/users", handle_list_users)
+        .get("/api/users/cursor", handle_list_users_cursor)
+        .get("/api/users/verify", handle_verify_email)
+        .post("/api/users", handle_create_user)
+        .post("/api/users/validate", handle_validate)
+        .post("/api/users/batch", handle_batch_get_users)
+        .post("/api/users/batch/delete", handle_batch_delete_users)
+        .post("/api/users/count/reconcile", handle_reconcile_user_count)
+        // Admin
+        .get("/admin/schema", handle_schema_status)
+        .get("/admin/indexes", handle_index_check)
+        .get("/admin/backup", handle_backup)
+        .get("/admin/dlq", handle_dead_letters)
+        .post("/admin/cache/version", handle_bump_user_cache_version)
+        .get("/admin/routes", handle_disabled_routes)
+        .put("/admin/routes", handle_toggle_route)
+        // API keys
+        .post("/api/keys", handle_create_api_key)
+        .get("/api/users/:id", handle_get_user)
+        .put("/api/users/:id", handle_update_user)
+        .patch("/api/users/:id", handle_update_user)
+        .delete("/api/users/:id", handle_delete_user)
+        .post("/api/users/:id/restore", handle_restore_user)
+        .get("/api/users/:id/events", handle_user_events)
+        .post("/api/users/:id/email/verify/send", handle_send_verification)
+        // Cache example
+        .get("/api/cached/:key", handle_cache_get)
+        .put("/api/cached/:key", handle_cache_set)
+        .post("/api/cached/touch", handle_cache_touch)
+        .delete("/api/cached", handle_cache_flush)
+        // Storage example
+        .get("/api/files/:key", handle_file_get)
+        .put("/api/files/:key", handle_file_upload)
+        .post("/api/files/batch", handle_file_batch_upload)
+        .post("/api/blobs", handle_blob_upload)
+        // CPU-intensive
+        .post("/api/compute", handle_compute)
+        .post("/api/compute/batch", handle_compute_batch)
+        .get("/api/usage", handle_compute_usage)
+        // Upstream proxy
+        .on("/api/proxy/*path", handle_proxy)
+        // Debugging
+        .on("/api/debug/echo", handle_debug_echo)
+        // Default
+        .get("/", handle_index)
+        .head("/", handle_index)
+        .run(req, env);
+    // Operations check the deadline themselves; this catches whatever runs
+    // between them (or doesn't go through within_deadline)
+    let rejected = route_rejected.or(query_rejected).or(body_rejected);
+    let response = if let Some((code, message)) = rejected {
+        reject(code, message)
+    } else if csrf_ok {
+        race_deadline(
+            router,
+            deadline.remaining_ms(Date::now().as_millis()),
+            |ms| Delay::from(std::time::Duration::from_millis(ms)),
+        )
+        .await
+    } else {
+        reject(
+            ErrorCode::CsrfFailed,
+            "Missing or invalid X-CSRF-Token".to_string(),
+        )
+    };
+    let response = match response {
+        Err(e) if classify_d1_error(&e.to_string()) == D1ErrorKind::Locked => {
+            console_warn!("D1 still locked after retries: {}", e);
+            database_busy_response(format, omit_nulls, locked_retry_after)
+        }
+        Err(e) if is_deadline_error(&e) => {
+            console_warn!("{} {} ran past its deadline", access.method, access.path);
+            reject(
+                ErrorCode::DeadlineExceeded,
+                "Request took too long".to_string(),
+            )
+        }
+        response => response,
+    };
+
+    access.duration_ms = Date::now().as_millis().saturating_sub(started);
+    access.status = response.as_ref().map_or(500, |r| r.status_code());
+    if log_in_full(&access, log_sample_rate, log_slow_ms) {
+        console_log!("{}", access.format(log_format));
+    } else {
+        console_log!("{}", access.format_minimal(log_format));
+    }
+    if let Some(dataset) = &analytics {
+        // Analytics are best-effort; the response goes out regardless
+        if let Err(e) = write_data_point(dataset, &AnalyticsPoint::from_access(&access)) {
+            console_warn!("analytics data point not written: {}", e);
+        }
+    }
+    let mut response = response?;
+    response
+        .headers_mut()
+        .set(REQUEST_ID_HEADER, &access.request_id)?;
+    if let Some(csp) = &csp {
+        let content_type = response.headers().get("Content-Type")?;
+        for (name, value) in security_headers(csp, content_type.as_deref()) {
+            // A handler's own value (say, a stricter CSP) is kept
+            if !response.headers().has(name)? {
+                response.headers_mut().set(name, &value)?;
+            }
+        }
+    }
+
+    if cacheable {
+        let cache_control = cache_control_for(
+            &cache_rules,
+            &access.path,
+            response.status_code(),
+            response.headers().get("Cache-Control")?.as_deref(),
+        );
+        response
+            .headers_mut()
+            .set("Cache-Control", &cache_control)?;
+    }
+
+    let mut response = match cors {
+        Some(cors) => response.with_cors(&cors)?,
+        None => response,
+    };
+    if vary_origin {
+        response.headers_mut().append("Vary", "Origin")?;
+    }
+    if issue_csrf_cookie {
+        response
+            .headers_mut()
+            .append("Set-Cookie", &csrf_set_cookie())?;
+    }
+    Ok(response)
 }
 
 // ============================================
-// R2 STORAGE HANDLERS
+// REQUEST HEADER LIMITS
 // ============================================
 
-async fn handle_file_get(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let key = ctx.param("key").unwrap();
-    let bucket = ctx.bucket("STORAGE")?;
+// Checked first thing in `fetch`, before headers are parsed for anything
+// else. The size is name plus value bytes, without separators, and includes
+// the headers Cloudflare adds itself (cf-ray, cf-connecting-ip, ...).
+fn header_limit_error(
+    headers: impl Iterator<Item = (String, String)>,
+    max_count: usize,
+    max_bytes: usize,
+) -> Option<String> {
+    let (mut count, mut bytes) = (0, 0);
+    for (name, value) in headers {
+        count += 1;
+        bytes += name.len() + value.len();
+        if count > max_count {
+            return Some(format!(
+                "Too many request headers; the limit is {}",
+                max_count
+            ));
+        }
+        if bytes > max_bytes {
+            return Some(format!(
+                "Request headers too large; the limit is {} bytes",
+                max_bytes
+            ));
+        }
+    }
+    None
+}
 
-    let object = bucket.get(key).execute().await?;
+// ============================================
+// DISABLED ROUTES
+// ============================================
 
-    match object {
-        Some(obj) => {
-            let body = obj.body().unwrap();
-            let bytes = body.bytes().await?;
+// A route can be switched off without a redeploy, e.g. /api/compute during
+// an incident: DISABLED_ROUTES in the vars, or at runtime through
+// PUT /admin/routes when ROUTE_TOGGLES is on. Patterns are the ones
+// ROUTE_BODY_LIMITS uses (`/api/users/:id`, `/api/proxy/*`). The runtime list
+// is read once per request and cached at the edge for a minute, so a toggle
+// takes up to that long to reach every location. Reading it fails open: if
+// KV is unavailable, only the DISABLED_ROUTES ones are off. Admin routes can't
+// be disabled, so a toggle can always be undone.
+const ROUTE_TOGGLE_CACHE_TTL: u64 = 60;
 
-            let content_type = obj
-                .http_metadata()
-                .content_type
-                .unwrap_or("application/octet-stream".to_string());
+fn disabled_routes_key() -> String {
+    CacheKey::new(KeySpace::Meta)
+        .part("disabled_routes")
+        .build()
+}
 
-            let mut headers = Headers::new();
-            headers.set("Content-Type", &content_type)?;
+// The pattern that disables `path`, if any
+fn disabled_route<'a>(
+    configured: &'a [String],
+    runtime: &'a [String],
+    path: &str,
+) -> Option<&'a str> {
+    if path.starts_with("/admin/") {
+        return None;
+    }
+    configured
+        .iter()
+        .chain(runtime)
+        .map(String::as_str)
+        .find(|pattern| route_specificity(pattern, path).is_some())
+}
 
-            Ok(Response::from_bytes(bytes)?.with_headers(headers))
+async fn runtime_disabled_routes(env: &Env) -> Vec<String> {
+    let routes = async {
+        env.kv("CACHE")?
+            .get(&disabled_routes_key())
+            .cache_ttl(ROUTE_TOGGLE_CACHE_TTL)
+            .json::<Vec<String>>()
+            .await
+            .map_err(Error::from)
+    };
+    match routes.await {
+        Ok(routes) => routes.unwrap_or_default(),
+        Err(e) => {
+            console_warn!("disabled routes unavailable, none applied: {}", e);
+            Vec::new()
         }
-        None => Response::error("Not found", 404),
     }
 }
 
-async fn handle_file_upload(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let key = ctx.param("key").unwrap();
-    let bucket = ctx.bucket("STORAGE")?;
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteToggle {
+    route: String,
+    disabled: bool,
+}
 
-    let content_type = req
-        .headers()
-        .get("Content-Type")?
-        .unwrap_or("application/octet-stream".to_string());
+#[derive(Serialize)]
+struct DisabledRoutes {
+    // From DISABLED_ROUTES; only a redeploy changes these
+    configured: Vec<String>,
+    runtime: Vec<String>,
+    // False means `runtime` is stored but not applied (ROUTE_TOGGLES is off)
+    toggles_enabled: bool,
+}
 
-    let bytes = req.bytes().await?;
+fn disabled_routes_response(ctx: &RouteContext<ReqCtx>, runtime: Vec<String>) -> Result<Response> {
+    respond(
+        ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(DisabledRoutes {
+                configured: ctx.data.config.disabled_routes.clone(),
+                runtime,
+                toggles_enabled: ctx.data.config.route_toggles,
+            }),
+            error: None,
+            code: None,
+        },
+    )
+}
 
-    bucket
-        .put(key, bytes)
-        .http_metadata(worker::HttpMetadata {
-            content_type: Some(content_type),
-            ..Default::default()
-        })
+async fn handle_disabled_routes(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let runtime = ctx
+        .kv("CACHE")?
+        .get(&disabled_routes_key())
+        .json::<Vec<String>>()
+        .await?
+        .unwrap_or_default();
+    disabled_routes_response(&ctx, runtime)
+}
+
+// Read-modify-write: two toggles racing can lose one, so check the result
+async fn handle_toggle_route(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let toggle: RouteToggle = match parse_json_body(&req.text().await?) {
+        Ok(toggle) => toggle,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    let route = toggle.route.trim().to_string();
+    if !route.starts_with('/') || route.starts_with("/admin/") {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            "route must be a path pattern outside /admin/",
+        );
+    }
+
+    let kv = ctx.kv("CACHE")?;
+    let mut runtime = kv
+        .get(&disabled_routes_key())
+        .json::<Vec<String>>()
+        .await?
+        .unwrap_or_default();
+    runtime.retain(|r| *r != route);
+    if toggle.disabled {
+        runtime.push(route.clone());
+    }
+    kv.put(&disabled_routes_key(), serde_json::to_string(&runtime)?)?
         .execute()
         .await?;
-
-    Response::ok("Uploaded")
+    console_warn!(
+        "route {} {}",
+        route,
+        if toggle.disabled {
+            "disabled"
+        } else {
+            "enabled"
+        }
+    );
+    disabled_routes_response(&ctx, runtime)
 }
 
 // ============================================
-// CPU-INTENSIVE COMPUTATION
+// SELF-CHECK
 // ============================================
 
-#[derive(Deserialize)]
-struct ComputeRequest {
-    data: Vec<f64>,
-    operation: String,
+// Runs once per isolate, on its first request, so a misconfigured deploy is
+// one clear log line rather than scattered binding errors. The result is kept:
+// with a critical binding missing every request gets a 500, but the summary
+// is only logged the once. Missing optional pieces are warnings; the features
+// using them degrade (admin routes close, counters fall back to D1).
+static SELF_CHECK: std::sync::OnceLock<SelfCheck> = std::sync::OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BindingKind {
+    D1,
+    Kv,
+    R2,
+    DurableObject,
+    Secret,
 }
 
-#[derive(Serialize)]
-struct ComputeResult {
-    result: f64,
-    operation: String,
-    count: usize,
+#[derive(Debug, Default, PartialEq)]
+struct SelfCheck {
+    present: Vec<&'static str>,
+    missing_critical: Vec<&'static str>,
+    missing_optional: Vec<&'static str>,
 }
 
-async fn handle_compute(mut req: Request, _ctx: RouteContext<()>) -> Result<Response> {
-    let input: ComputeRequest = match req.json().await {
-        Ok(data) => data,
-        Err(_) => {
-            return Response::from_json(&ApiResponse::<()> {
-                success: false,
-                data: None,
-                error: Some("Invalid JSON".to_string()),
-            })
-            .map(|r| r.with_status(400));
-        }
-    };
+impl SelfCheck {
+    fn summary(&self) -> String {
+        format!(
+            "self-check: present [{}], missing critical [{}], missing optional [{}]",
+            self.present.join(", "),
+            self.missing_critical.join(", "),
+            self.missing_optional.join(", ")
+        )
+    }
 
-    if input.data.is_empty() {
-        return Response::from_json(&ApiResponse::<()> {
-            success: false,
-            data: None,
-            error: Some("Data array is empty".to_string()),
-        })
-        .map(|r| r.with_status(400));
+    fn error(&self) -> Option<String> {
+        (!self.missing_critical.is_empty())
+            .then(|| format!("missing bindings: {}", self.missing_critical.join(", ")))
     }
+}
 
-    let result = match input.operation.as_str() {
-        "sum" => input.data.iter().sum(),
-        "mean" => input.data.iter().sum::<f64>() / input.data.len() as f64,
-        "max" => input.data.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
-        "min" => input.data.iter().cloned().fold(f64::INFINITY, f64::min),
-        "std" => {
-            let mean = input.data.iter().sum::<f64>() / input.data.len() as f64;
-            let variance = input
-                .data
-                .iter()
-                .map(|x| (x - mean).powi(2))
-                .sum::<f64>()
-                / input.data.len() as f64;
-            variance.sqrt()
-        }
-        _ => {
-            return Response::from_json(&ApiResponse::<()> {
-                success: false,
-                data: None,
-                error: Some(format!("Unknown operation: {}", input.operation)),
-            })
-            .map(|r| r.with_status(400));
+// (name, kind, critical). Durable Objects and secrets are only listed when
+// the config turns on something that uses them.
+fn self_check_requirements(config: &Config) -> Vec<(&'static str, BindingKind, bool)> {
+    let mut required = vec![
+        ("DB", BindingKind::D1, true),
+        ("CACHE", BindingKind::Kv, true),
+        ("STORAGE", BindingKind::R2, true),
+        ("USER_EVENTS", BindingKind::DurableObject, false),
+        ("ADMIN_KEY", BindingKind::Secret, false),
+    ];
+    if config.user_counter {
+        required.push(("USER_COUNTER", BindingKind::DurableObject, false));
+    }
+    if config.compute_max_concurrency.is_some() {
+        required.push(("COMPUTE_SLOTS", BindingKind::DurableObject, false));
+    }
+    if config.compute_usage {
+        required.push(("COMPUTE_USAGE", BindingKind::DurableObject, false));
+    }
+    if config.webhook_url.is_some() {
+        required.push(("SIGNING_SECRET", BindingKind::Secret, false));
+    }
+    if config.email_encryption {
+        required.push(("EMAIL_ENCRYPTION_KEY", BindingKind::Secret, true));
+    }
+    for name in &CACHE_SHARD_BINDINGS[1..config.cache_shards.max(1)] {
+        required.push((name, BindingKind::Kv, true));
+    }
+    required
+}
+
+fn run_self_check(config: &Config, probe: impl Fn(&str, BindingKind) -> bool) -> SelfCheck {
+    let mut check = SelfCheck::default();
+    for (name, kind, critical) in self_check_requirements(config) {
+        if probe(name, kind) {
+            check.present.push(name);
+        } else if critical {
+            check.missing_critical.push(name);
+        } else {
+            check.missing_optional.push(name);
         }
-    };
+    }
+    check
+}
 
-    Response::from_json(&ApiResponse {
-        success: true,
-        data: Some(ComputeResult {
-            result,
-            operation: input.operation,
-            count: input.data.len(),
-        }),
-        error: None,
-    })
+fn probe_binding(env: &Env, name: &str, kind: BindingKind) -> bool {
+    match kind {
+        BindingKind::D1 => env.d1(name).is_ok(),
+        BindingKind::Kv => env.kv(name).is_ok(),
+        BindingKind::R2 => env.bucket(name).is_ok(),
+        BindingKind::DurableObject => env.durable_object(name).is_ok(),
+        BindingKind::Secret => env.secret(name).is_ok(),
+    }
 }
 
 // ============================================
-// TESTS
+// ACCESS LOG
 // ============================================
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
-    #[test]
-    fn test_compute_sum() {
-        let data = vec![1.0, 2.0, 3.0, 4.0, 5.0];
-        let sum: f64 = data.iter().sum();
-        assert_eq!(sum, 15.0);
+// One line per completed request. Only verified identities are recorded as
+// `subject`; nothing from an unauthenticated header is trusted here.
+#[derive(Serialize)]
+struct AccessLog {
+    timestamp: String,
+    request_id: String,
+    method: String,
+    path: String,
+    status: u16,
+    // Workers only advance the clock across I/O, so CPU-only time reads as 0
+    duration_ms: u64,
+    country: Option<String>,
+    subject: Option<String>,
+}
+
+// A caller's X-Request-Id is reused (with TRUST_REQUEST_ID) so one id follows
+// the request across services; either way the id is echoed on the response.
+// It lands in logs verbatim, so anything long or outside a conservative
+// charset is replaced rather than cleaned up.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const REQUEST_ID_MAX_LEN: usize = 128;
+
+fn resolve_request_id(inbound: Option<&str>, generate: impl FnOnce() -> String) -> String {
+    match inbound {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= REQUEST_ID_MAX_LEN
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)) =>
+        {
+            id.to_string()
+        }
+        _ => generate(),
     }
+}
 
-    #[test]
-    fn test_compute_mean() {
-        let data = vec![1.0, 2.0, 3.0, 4.0, 5.0];
-        let mean = data.iter().sum::<f64>() / data.len() as f64;
-        assert_eq!(mean, 3.0);
+// Carried into work that outlives the request, so the log lines and payloads
+// it produces can be matched with the access log line that started it
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Correlation {
+    #[serde(default)]
+    request_id: String,
+    // From a W3C `traceparent` header, when the caller sent a valid one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace_id: Option<String>,
+}
+
+impl Correlation {
+    // Appended to log lines, in the access log's `req=` form
+    fn log_fields(&self) -> String {
+        match &self.trace_id {
+            Some(trace_id) => format!("req={} trace={}", self.request_id, trace_id),
+            None => format!("req={}", self.request_id),
+        }
+    }
+}
+
+// `00-<trace id>-<parent id>-<flags>`; the trace id is 32 lowercase hex
+// digits and never all zeros
+fn trace_id_from(traceparent: Option<&str>) -> Option<String> {
+    let mut parts = traceparent?.trim().split('-');
+    let (_version, trace_id) = (parts.next()?, parts.next()?);
+    let valid = trace_id.len() == 32
+        && trace_id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && trace_id.bytes().any(|b| b != b'0');
+    valid.then(|| trace_id.to_string())
+}
+
+// With LOG_SAMPLE_RATE = n, one request in n gets the full line. The choice
+// hashes the request id rather than rolling a die, so with a propagated
+// X-Request-Id every service samples the same requests.
+fn log_in_full(access: &AccessLog, sample_rate: u32, slow_ms: u64) -> bool {
+    access.status >= 400
+        || access.duration_ms >= slow_ms
+        || fnv1a_64(access.request_id.as_bytes()).is_multiple_of(u64::from(sample_rate.max(1)))
+}
+
+impl AccessLog {
+    // Enough to count requests per route and status, nothing more
+    fn format_minimal(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json => serde_json::json!({
+                "method": self.method,
+                "path": self.path,
+                "status": self.status,
+            })
+            .to_string(),
+            LogFormat::Text => format!("{} {} {}", self.method, self.path, self.status),
+        }
+    }
+
+    fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+            LogFormat::Text => format!(
+                "{} {} {} {}ms req={}{}",
+                self.method,
+                self.path,
+                self.status,
+                self.duration_ms,
+                self.request_id,
+                self.subject
+                    .as_ref()
+                    .map(|s| format!(" subject={}", s))
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
+// One Analytics Engine data point per request, next to the access log line:
+//
+//   blob1 = path, blob2 = method, blob3 = country ("" when unknown)
+//   double1 = duration_ms, double2 = status
+//
+// so `SELECT blob1, avg(double1) FROM requests GROUP BY blob1` works from the
+// SQL API. The ANALYTICS binding is optional; without it nothing is written.
+// worker 0.3 has no Analytics Engine wrapper, so `writeDataPoint` is called
+// on the binding directly.
+#[derive(Debug, PartialEq, Serialize)]
+struct AnalyticsPoint {
+    blobs: [String; 3],
+    doubles: [f64; 2],
+}
+
+impl AnalyticsPoint {
+    fn from_access(access: &AccessLog) -> Self {
+        AnalyticsPoint {
+            blobs: [
+                access.path.clone(),
+                access.method.clone(),
+                access.country.clone().unwrap_or_default(),
+            ],
+            doubles: [access.duration_ms as f64, f64::from(access.status)],
+        }
+    }
+}
+
+// None when the binding isn't configured
+fn analytics_dataset(env: &Env) -> Option<wasm_bindgen::JsValue> {
+    js_sys::Reflect::get(env.as_ref(), &"ANALYTICS".into())
+        .ok()
+        .filter(|binding| !binding.is_undefined() && !binding.is_null())
+}
+
+fn write_data_point(dataset: &wasm_bindgen::JsValue, point: &AnalyticsPoint) -> Result<()> {
+    use wasm_bindgen::JsCast;
+
+    let point = js_sys::JSON::parse(&serde_json::to_string(point)?)?;
+    let write: js_sys::Function =
+        js_sys::Reflect::get(dataset, &"writeDataPoint".into())?.dyn_into()?;
+    write.call1(dataset, &point)?;
+    Ok(())
+}
+
+// Cron trigger (see "triggers" in wrangler.jsonc) for data retention
+#[event(scheduled)]
+async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+
+    let config = Config::from_env(&env);
+    if let Err(message) = config.validate() {
+        console_error!("invalid configuration: {}", message);
+        return;
+    }
+    // Retention only hard-deletes rows that are already soft-deleted, so it
+    // never moves the counter; the cron is just a convenient place to fix drift
+    if config.user_counter {
+        match reconcile_user_count(&env).await {
+            Ok(r) if r.drift != Some(0) => console_warn!(
+                "user counter drift corrected: stored {:?}, actual {}",
+                r.previous,
+                r.actual
+            ),
+            Ok(_) => {}
+            Err(e) => console_error!("user counter reconcile failed: {}", e),
+        }
+    }
+    match enforce_retention(&env, &config).await {
+        Ok(report) => console_log!(
+            "retention: hard-deleted {} user(s) ({} over the soft-delete cap), \
+             anonymized {} user(s)",
+            report.deleted + report.capped,
+            report.capped,
+            report.anonymized
+        ),
+        Err(e) => console_error!("retention run failed: {}", e),
+    }
+}
+
+// ============================================
+// CORS
+// ============================================
+
+// The Allow-Origin value for a request: "*" when any origin is allowed,
+// otherwise the request's own origin if it's on the list
+fn allowed_origin(config: &Config, origin: Option<&str>) -> Option<String> {
+    if config.cors_allowed_origins.iter().any(|o| o == "*") {
+        return Some("*".to_string());
+    }
+    let origin = origin?;
+    config
+        .cors_allowed_origins
+        .iter()
+        .any(|o| o == origin)
+        .then(|| origin.to_string())
+}
+
+fn cors_for(config: &Config, origin: Option<&str>) -> Option<Cors> {
+    let allowed = allowed_origin(config, origin)?;
+    Some(
+        Cors::new()
+            .with_origins(vec![allowed])
+            .with_credentials(config.cors_allow_credentials)
+            .with_methods(vec![
+                Method::Get,
+                Method::Head,
+                Method::Post,
+                Method::Put,
+                Method::Patch,
+                Method::Delete,
+                Method::Options,
+            ])
+            .with_allowed_headers(vec![
+                "Content-Type",
+                "Authorization",
+                D1_BOOKMARK_HEADER,
+                CSRF_HEADER,
+                METHOD_OVERRIDE_HEADER,
+                REQUEST_ID_HEADER,
+            ])
+            .with_exposed_headers(vec![D1_BOOKMARK_HEADER, REQUEST_ID_HEADER]),
+    )
+}
+
+// ============================================
+// SECURITY HEADERS
+// ============================================
+
+// HSTS only takes effect over HTTPS, which workers.dev and custom domains
+// always are. Two years with subdomains, but no `preload`: that's a
+// commitment the domain owner has to opt into.
+const HSTS_VALUE: &str = "max-age=63072000; includeSubDomains";
+
+// Response types that are only ever read by code, never rendered; they get
+// the transport and sniffing headers but not framing or CSP
+const API_CONTENT_TYPES: &[&str] = &[
+    "application/json",
+    "application/msgpack",
+    "application/x-msgpack",
+    "application/gzip",
+    "text/event-stream",
+];
+
+fn security_headers(csp: &str, content_type: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("Strict-Transport-Security", HSTS_VALUE.to_string()),
+        ("X-Content-Type-Options", "nosniff".to_string()),
+        ("Referrer-Policy", "no-referrer".to_string()),
+    ];
+    let api = content_type
+        .map(media_essence)
+        .is_some_and(|t| API_CONTENT_TYPES.contains(&t.as_str()));
+    if !api {
+        headers.push(("X-Frame-Options", "DENY".to_string()));
+        headers.push(("Content-Security-Policy", csp.to_string()));
+    }
+    headers
+}
+
+// ============================================
+// CSRF
+// ============================================
+
+// Double-submit cookie: every response to a browser without the cookie sets
+// a random `csrf_token` (readable by the page's JS, so not HttpOnly), and
+// state-changing requests have to echo it in X-CSRF-Token. Another site can
+// make the browser send the cookie but can't read it to set the header.
+//
+// Only requests that carry cookies are checked. Bearer and X-API-Key
+// credentials are attached by the caller, not the browser, so a cross-site
+// form can't send them and those requests skip the check.
+const CSRF_COOKIE: &str = "csrf_token";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+fn cookie_value<'a>(cookie_header: &'a str, name: &str) -> Option<&'a str> {
+    cookie_header.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+// True when the request may go ahead
+fn csrf_check(
+    method: &Method,
+    cookie_header: Option<&str>,
+    csrf_header: Option<&str>,
+    has_credentials_header: bool,
+) -> bool {
+    if !matches!(
+        method,
+        Method::Post | Method::Put | Method::Patch | Method::Delete
+    ) || has_credentials_header
+    {
+        return true;
+    }
+    let Some(cookies) = cookie_header else {
+        return true;
+    };
+    match (cookie_value(cookies, CSRF_COOKIE), csrf_header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() => {
+            constant_time_eq(cookie.as_bytes(), header.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+fn csrf_set_cookie() -> String {
+    format!(
+        "{}={}; Path=/; Secure; SameSite=Strict",
+        CSRF_COOKIE,
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+// ============================================
+// METHOD OVERRIDE
+// ============================================
+
+// With METHOD_OVERRIDE on, a POST carrying X-HTTP-Method-Override is rebuilt
+// with that method before anything else sees it, so routing, CSRF and the
+// access log all use the overridden method. Only POST can be overridden, and
+// only to PUT, PATCH or DELETE: turning a POST into a GET would let a form
+// reach handlers that assume safe methods.
+const METHOD_OVERRIDE_HEADER: &str = "X-HTTP-Method-Override";
+
+// Ok(None) when the request goes through as sent
+fn method_override(
+    method: &Method,
+    header: Option<&str>,
+) -> std::result::Result<Option<Method>, String> {
+    let Some(value) = header.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    if *method != Method::Post {
+        return Err(format!(
+            "{} is only accepted on POST",
+            METHOD_OVERRIDE_HEADER
+        ));
+    }
+    match value.to_ascii_uppercase().as_str() {
+        "PUT" => Ok(Some(Method::Put)),
+        "PATCH" => Ok(Some(Method::Patch)),
+        "DELETE" => Ok(Some(Method::Delete)),
+        _ => Err(format!(
+            "{} must be PUT, PATCH or DELETE",
+            METHOD_OVERRIDE_HEADER
+        )),
+    }
+}
+
+// Same URL, headers and (still unread) body under a different method
+fn with_method(req: &Request, method: Method) -> Result<Request> {
+    let body = js_sys::Reflect::get(req.inner(), &"body".into())?;
+    let mut init = RequestInit::new();
+    init.with_method(method)
+        .with_headers(req.headers().clone())
+        .with_body((!body.is_null() && !body.is_undefined()).then_some(body));
+    Request::new_with_init(req.url()?.as_str(), &init)
+}
+
+// ============================================
+// CACHE CONTROL
+// ============================================
+
+// A `:param` pattern also covers literal routes beside it (`/api/users/:id`
+// matches `/api/users/cursor`), so those need entries of their own.
+const DEFAULT_ROUTE_CACHE_CONTROL: &str = "/=public, max-age=3600; \
+     /api/users/:id=private, max-age=30; \
+     /api/users/cursor=no-store; \
+     /api/users/verify=no-store";
+
+// Entries without a '=' are skipped. Values may contain '=' (max-age=30), so
+// only the first one separates the pattern.
+fn parse_route_cache_control(raw: &str) -> Vec<(String, String)> {
+    raw.split(';')
+        .filter_map(|entry| {
+            let (pattern, value) = entry.split_once('=')?;
+            let (pattern, value) = (pattern.trim(), value.trim());
+            (!pattern.is_empty() && !value.is_empty())
+                .then(|| (pattern.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// Router-style pattern match: `:name` is one segment, `*name` the rest of the
+// path. Returns the number of literal segments, so the most specific rule wins.
+fn route_specificity(pattern: &str, path: &str) -> Option<usize> {
+    let mut path_segments = path.trim_matches('/').split('/');
+    let mut literals = 0;
+    for segment in pattern.trim_matches('/').split('/') {
+        if segment.starts_with('*') {
+            return Some(literals);
+        }
+        let actual = path_segments.next()?;
+        if segment.starts_with(':') {
+            if actual.is_empty() {
+                return None;
+            }
+        } else if segment == actual {
+            literals += 1;
+        } else {
+            return None;
+        }
+    }
+    path_segments.next().is_none().then_some(literals)
+}
+
+// Query params each user GET route reads, for STRICT_QUERY_PARAMS. The most
+// specific matching pattern applies, as for ROUTE_BODY_LIMITS; routes not
+// listed here accept anything. Keep in step with the handlers.
+const ROUTE_QUERY_PARAMS: &[(&str, &[&str])] = &[
+    ("/api/users", &["page", "limit"]),
+    ("/api/users/cursor", &["limit", "cursor"]),
+    ("/api/users/verify", &["token"]),
+    ("/api/users/:id", &[]),
+];
+
+// Read by `fetch` itself, so valid on every route
+const GLOBAL_QUERY_PARAMS: &[&str] = &["pretty"];
+
+fn unknown_query_param<'a>(path: &str, names: &'a [String]) -> Option<&'a str> {
+    let (_, allowed) = ROUTE_QUERY_PARAMS
+        .iter()
+        .filter_map(|(pattern, allowed)| route_specificity(pattern, path).map(|n| (n, allowed)))
+        .max_by_key(|(n, _)| *n)?;
+    names
+        .iter()
+        .map(String::as_str)
+        .find(|name| !allowed.contains(name) && !GLOBAL_QUERY_PARAMS.contains(name))
+}
+
+// For GET/HEAD responses. A configured route always wins; otherwise a header
+// the handler set itself (R2 object metadata, SSE) is kept, and anything else
+// is `no-store`. Errors are never cached.
+fn cache_control_for(
+    rules: &[(String, String)],
+    path: &str,
+    status: u16,
+    existing: Option<&str>,
+) -> String {
+    if status >= 400 {
+        return "no-store".to_string();
+    }
+    rules
+        .iter()
+        .filter_map(|(pattern, value)| route_specificity(pattern, path).map(|n| (n, value)))
+        .max_by_key(|(n, _)| *n)
+        .map(|(_, value)| value.as_str())
+        .or(existing)
+        .unwrap_or("no-store")
+        .to_string()
+}
+
+// ============================================
+// BODY LIMITS
+// ============================================
+
+// Checked in `fetch` before routing, so a multi-MB POST /api/users is turned
+// away without a handler parsing it. A declared Content-Length over the
+// limit is rejected unread; otherwise the body is read up to the limit (a
+// chunked or understated body is cut off there) and the request is rebuilt
+// around the buffered bytes. Uploads have no entry here: they stream to R2
+// under MAX_UPLOAD_BYTES instead.
+const DEFAULT_ROUTE_BODY_LIMITS: &str = "/api/users=16384; \
+     /api/users/:id=16384; \
+     /api/users/validate=1048576; \
+     /api/compute=10485760; \
+     /api/compute/batch=10485760";
+
+// Entries without a numeric limit are skipped
+fn parse_route_body_limits(raw: &str) -> Vec<(String, u64)> {
+    parse_route_cache_control(raw)
+        .into_iter()
+        .filter_map(|(pattern, value)| Some((pattern, value.parse().ok()?)))
+        .collect()
+}
+
+// The most specific matching pattern wins, as for Cache-Control
+fn body_limit_for(rules: &[(String, u64)], path: &str) -> Option<u64> {
+    rules
+        .iter()
+        .filter_map(|(pattern, max)| route_specificity(pattern, path).map(|n| (n, *max)))
+        .max_by_key(|(n, _)| *n)
+        .map(|(_, max)| max)
+}
+
+// The request comes back either way; with an error it must not be routed
+async fn limit_body(mut req: Request, max: u64) -> Result<(Request, Option<(ErrorCode, String)>)> {
+    let chunked = req
+        .headers()
+        .get("Transfer-Encoding")?
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    if let Err(rejected) = check_upload_length(
+        req.headers().get("Content-Length")?.as_deref(),
+        chunked,
+        max,
+        false,
+    ) {
+        return Ok((req, Some(rejected)));
+    }
+    let Some(body) = read_body_limited(&mut req, max).await? else {
+        return Ok((
+            req,
+            Some((
+                ErrorCode::PayloadTooLarge,
+                format!("Request body exceeds {} bytes", max),
+            )),
+        ));
+    };
+    let mut init = RequestInit::new();
+    init.with_method(req.method())
+        .with_headers(req.headers().clone())
+        .with_body(Some(js_sys::Uint8Array::from(body.as_slice()).into()));
+    Ok((Request::new_with_init(req.url()?.as_str(), &init)?, None))
+}
+
+// ============================================
+// ROUTE HANDLERS
+// ============================================
+
+const INDEX_BODY: &str = "Rust Worker API v1.0";
+
+// Monitoring tools often HEAD the root, so HEAD gets the same headers with no body
+fn index_parts(head: bool) -> (Vec<(&'static str, String)>, Vec<u8>) {
+    let headers = vec![
+        ("Content-Type", "text/plain; charset=utf-8".to_string()),
+        ("Content-Length", INDEX_BODY.len().to_string()),
+    ];
+    let body = if head {
+        Vec::new()
+    } else {
+        INDEX_BODY.as_bytes().to_vec()
+    };
+    (headers, body)
+}
+
+async fn handle_index(req: Request, _ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let (parts, body) = index_parts(req.method() == Method::Head);
+
+    let mut headers = Headers::new();
+    for (name, value) in parts {
+        headers.set(name, &value)?;
+    }
+
+    Ok(Response::from_bytes(body)?.with_headers(headers))
+}
+
+// Ordered best to worst, so the overall status is the max of the checks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum HealthStatus {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+// A check that succeeds but takes longer than this is degraded
+const HEALTH_SLOW_MS: u64 = 1000;
+
+#[derive(Debug, Serialize)]
+struct DependencyCheck {
+    name: &'static str,
+    status: HealthStatus,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl DependencyCheck {
+    // A probe that hung is unhealthy, with latency reported as the timeout
+    fn from_probe(
+        name: &'static str,
+        latency_ms: u64,
+        timeout_ms: u64,
+        outcome: BackgroundOutcome,
+    ) -> Self {
+        match outcome {
+            BackgroundOutcome::Done => Self::from_outcome(name, latency_ms, Ok(())),
+            BackgroundOutcome::Failed(e) => {
+                Self::from_outcome(name, latency_ms, Err(Error::RustError(e)))
+            }
+            BackgroundOutcome::TimedOut => DependencyCheck {
+                name,
+                status: HealthStatus::Unhealthy,
+                latency_ms: timeout_ms,
+                error: Some("timeout".to_string()),
+            },
+        }
+    }
+
+    fn from_outcome(name: &'static str, latency_ms: u64, outcome: Result<()>) -> Self {
+        let (status, error) = match outcome {
+            Ok(()) if latency_ms > HEALTH_SLOW_MS => (HealthStatus::Degraded, None),
+            Ok(()) => (HealthStatus::Healthy, None),
+            Err(e) => (HealthStatus::Unhealthy, Some(e.to_string())),
+        };
+        DependencyCheck {
+            name,
+            status,
+            latency_ms,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HealthReport {
+    status: HealthStatus,
+    timestamp: String,
+    checks: Vec<DependencyCheck>,
+}
+
+impl HealthReport {
+    fn new(timestamp: String, checks: Vec<DependencyCheck>) -> Self {
+        let status = checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(HealthStatus::Healthy);
+        HealthReport {
+            status,
+            timestamp,
+            checks,
+        }
+    }
+}
+
+// Probes run concurrently, each against its own HEALTH_PROBE_TIMEOUT_MS, so
+// one hung dependency shows up as a timeout without holding the others and
+// the whole check is done in about one timeout
+async fn timed_check(
+    name: &'static str,
+    timeout_ms: u64,
+    check: impl std::future::Future<Output = Result<()>>,
+) -> DependencyCheck {
+    let started = Date::now().as_millis();
+    let timeout = Delay::from(std::time::Duration::from_millis(timeout_ms));
+    let outcome = run_with_budget(check, timeout).await;
+    DependencyCheck::from_probe(
+        name,
+        Date::now().as_millis().saturating_sub(started),
+        timeout_ms,
+        outcome,
+    )
+}
+
+async fn handle_health(_req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let timeout_ms = ctx.data.config.health_probe_timeout_ms;
+    let probes: Vec<std::pin::Pin<Box<dyn std::future::Future<Output = DependencyCheck>>>> = vec![
+        Box::pin(timed_check("d1", timeout_ms, async {
+            ctx.env
+                .d1("DB")?
+                .prepare("SELECT 1")
+                .first::<serde_json::Value>(None)
+                .await?;
+            Ok(())
+        })),
+        Box::pin(timed_check("kv", timeout_ms, async {
+            ctx.kv("CACHE")?.get("health:probe").text().await?;
+            Ok(())
+        })),
+        Box::pin(timed_check("r2", timeout_ms, async {
+            ctx.bucket("STORAGE")?.head("health/probe").await?;
+            Ok(())
+        })),
+    ];
+    let checks = futures::future::join_all(probes).await;
+    let report = HealthReport::new(chrono::Utc::now().to_rfc3339(), checks);
+    let status = if report.status == HealthStatus::Unhealthy {
+        503
+    } else {
+        200
+    };
+    respond(&ctx, &report).map(|r| r.with_status(status))
+}
+
+// ============================================
+// D1 SESSIONS
+// ============================================
+
+// With D1_READ_REPLICAS on, user queries run in a D1 session. The bookmark
+// flow gives read-your-writes across requests:
+//
+//   1. Every user route returns the session's latest bookmark in
+//      `x-d1-bookmark`, writes included.
+//   2. The client sends the last bookmark it saw back in `x-d1-bookmark`.
+//   3. The session starts from that bookmark, so whichever replica serves
+//      the read is at least as fresh as the client's last write.
+//
+// With no bookmark, reads may go to any replica and writes start on the
+// primary. worker 0.3 has no sessions API yet, so `withSession` is called
+// on the binding directly; the session object has the same `prepare`/`batch`
+// surface as the database, which is all the handlers use.
+const D1_BOOKMARK_HEADER: &str = "x-d1-bookmark";
+
+fn session_constraint(bookmark: Option<&str>, write: bool) -> &str {
+    match bookmark {
+        Some(bookmark) if !bookmark.is_empty() => bookmark,
+        _ if write => "first-primary",
+        _ => "first-unconstrained",
+    }
+}
+
+fn d1_session(req: &Request, ctx: &RouteContext<ReqCtx>, write: bool) -> Result<D1Database> {
+    use wasm_bindgen::JsCast;
+
+    let db = ctx.env.d1("DB")?;
+    if !ctx.data.config.d1_read_replicas {
+        return Ok(db);
+    }
+    let bookmark = req.headers().get(D1_BOOKMARK_HEADER)?;
+    let with_session: js_sys::Function =
+        js_sys::Reflect::get(db.as_ref(), &"withSession".into())?.dyn_into()?;
+    let session = with_session.call1(
+        db.as_ref(),
+        &session_constraint(bookmark.as_deref(), write).into(),
+    )?;
+    Ok(session.unchecked_into())
+}
+
+// Plain database handles have no getBookmark, so this is a no-op without sessions
+fn with_bookmark(db: &D1Database, mut response: Response) -> Result<Response> {
+    use wasm_bindgen::JsCast;
+
+    let get_bookmark = js_sys::Reflect::get(db.as_ref(), &"getBookmark".into())?;
+    if let Some(get_bookmark) = get_bookmark.dyn_ref::<js_sys::Function>() {
+        if let Some(bookmark) = get_bookmark.call0(db.as_ref())?.as_string() {
+            response.headers_mut().set(D1_BOOKMARK_HEADER, &bookmark)?;
+        }
+    }
+    Ok(response)
+}
+
+// ============================================
+// SQL HELPERS
+// ============================================
+
+// D1 allows at most 100 bound parameters per statement
+const D1_MAX_BOUND_PARAMS: usize = 100;
+
+// `(?, ?, ...)` with `n` placeholders. `IN ()` is invalid SQL, so n = 0 gives
+// None.
+fn build_in_clause(n: usize) -> Option<String> {
+    (n > 0).then(|| format!("({})", vec!["?"; n].join(", ")))
+}
+
+struct InClause {
+    sql: String,
+    params: Vec<String>,
+}
+
+// Placeholders plus the values to bind, in order. None for no values, which
+// callers treat as an empty result without querying.
+fn in_clause(values: &[String]) -> Option<InClause> {
+    Some(InClause {
+        sql: build_in_clause(values.len())?,
+        params: values.to_vec(),
+    })
+}
+
+impl InClause {
+    fn bind_values(&self) -> Vec<wasm_bindgen::JsValue> {
+        self.params.iter().map(|v| v.as_str().into()).collect()
+    }
+}
+
+// Deduplicated ids from a batch body, or the validation message. `max` is
+// what's left of the parameter budget after the statement's other binds.
+fn batch_ids(ids: Vec<String>, max: usize) -> std::result::Result<Vec<String>, String> {
+    let mut unique: Vec<String> = Vec::with_capacity(ids.len());
+    for id in ids {
+        if !unique.contains(&id) {
+            unique.push(id);
+        }
+    }
+    if unique.len() > max {
+        return Err(format!("At most {} ids per batch", max));
+    }
+    Ok(unique)
+}
+
+// ============================================
+// TENANT ISOLATION
+// ============================================
+
+// With TENANT_ISOLATION on, every user route resolves a TenantScope from the
+// caller's X-API-Key (`tenant_scope`) and every users query it runs carries
+// `scope.clause()` at the end of its WHERE, with the tenant bound in place by
+// `scope.bind`. A key without a tenant gets 401, never an unscoped view.
+// Admin-only work (restore, retention, counter reconcile) stays unscoped.
+// Email uniqueness is per tenant too (migration 0008), so a 409
+// email_conflict never reveals an address held by another tenant.
+#[derive(Clone, Debug, PartialEq)]
+enum TenantScope {
+    // Isolation off, or admin-only paths
+    All,
+    Tenant(String),
+}
+
+impl TenantScope {
+    // Goes at the end of a WHERE clause
+    fn clause(&self) -> &'static str {
+        match self {
+            TenantScope::All => "",
+            TenantScope::Tenant(_) => " AND tenant_id = ?",
+        }
+    }
+
+    // Bind values with the tenant between the params before the clause and
+    // the ones after it (LIMIT, OFFSET)
+    fn bind(
+        &self,
+        before: Vec<wasm_bindgen::JsValue>,
+        after: Vec<wasm_bindgen::JsValue>,
+    ) -> Vec<wasm_bindgen::JsValue> {
+        let mut params = before;
+        if let TenantScope::Tenant(tenant) = self {
+            params.push(tenant.as_str().into());
+        }
+        params.extend(after);
+        params
+    }
+
+    // Params the clause takes from D1_MAX_BOUND_PARAMS
+    fn param_count(&self) -> usize {
+        match self {
+            TenantScope::All => 0,
+            TenantScope::Tenant(_) => 1,
+        }
+    }
+
+    fn tenant_id(&self) -> Option<&str> {
+        match self {
+            TenantScope::All => None,
+            TenantScope::Tenant(tenant) => Some(tenant),
+        }
+    }
+
+    // For users read from outside D1 (the KV cache), which the clause can't cover
+    fn allows(&self, user: &User) -> bool {
+        match self {
+            TenantScope::All => true,
+            TenantScope::Tenant(tenant) => user.tenant_id.as_deref() == Some(tenant.as_str()),
+        }
+    }
+}
+
+// Err(response) when isolation is on and the caller has no tenant key
+async fn tenant_scope(
+    req: &Request,
+    ctx: &RouteContext<ReqCtx>,
+) -> Result<std::result::Result<TenantScope, Response>> {
+    if !ctx.data.config.tenant_isolation {
+        return Ok(Ok(TenantScope::All));
+    }
+    let caller = lookup_api_key(req, ctx).await?;
+    match caller_tenant_scope(true, &caller) {
+        Some(scope) => Ok(Ok(scope)),
+        None => error_response(
+            ctx,
+            ErrorCode::Unauthorized,
+            "X-API-Key for a tenant required",
+        )
+        .map(Err),
+    }
+}
+
+// The scope for an already resolved caller; None when isolation is on and
+// the caller has no tenant key
+fn caller_tenant_scope(tenant_isolation: bool, caller: &ApiCaller) -> Option<TenantScope> {
+    if !tenant_isolation {
+        return Some(TenantScope::All);
+    }
+    match caller {
+        ApiCaller::Key(ApiKeyRecord {
+            tenant_id: Some(tenant),
+            ..
+        }) => Some(TenantScope::Tenant(tenant.clone())),
+        _ => None,
+    }
+}
+
+// ============================================
+// D1 ERRORS
+// ============================================
+
+#[derive(Debug, PartialEq)]
+enum D1ErrorKind {
+    // SQLITE_BUSY surfaces as "D1_ERROR: database is locked"; the write never
+    // happened, so it is safe to run again
+    Locked,
+    Other,
+}
+
+fn classify_d1_error(message: &str) -> D1ErrorKind {
+    let message = message.to_ascii_lowercase();
+    if message.contains("database is locked") || message.contains("sqlite_busy") {
+        D1ErrorKind::Locked
+    } else {
+        D1ErrorKind::Other
+    }
+}
+
+// 50ms, 100ms, 200ms, ... before each retry
+fn d1_retry_delay_ms(retry: u32) -> u64 {
+    50u64 << retry.min(6)
+}
+
+// Runs `op`, retrying only "database is locked" failures up to `retries`
+// times with backoff. Other errors, and the last locked one, are returned.
+async fn retry_locked<T, F, Fut, S, SFut>(retries: u32, mut op: F, sleep: S) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+    S: Fn(u64) -> SFut,
+    SFut: std::future::Future<Output = ()>,
+{
+    let mut retry = 0;
+    loop {
+        match op().await {
+            Err(e)
+                if retry < retries && classify_d1_error(&e.to_string()) == D1ErrorKind::Locked =>
+            {
+                sleep(d1_retry_delay_ms(retry)).await;
+                retry += 1;
+            }
+            outcome => return outcome,
+        }
+    }
+}
+
+// For D1 writes. A write still locked after the retries propagates as an
+// error; `fetch` turns that into a 503 with Retry-After.
+async fn d1_retry<T, F, Fut>(ctx: &RouteContext<ReqCtx>, op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    within_deadline(
+        ctx,
+        retry_locked(ctx.data.config.d1_locked_retries, op, |ms| {
+            Delay::from(std::time::Duration::from_millis(ms))
+        }),
+    )
+    .await
+}
+
+fn database_busy_response(format: Format, omit_nulls: bool, retry_after: u32) -> Result<Response> {
+    let mut response = early_error(
+        format,
+        omit_nulls,
+        ErrorCode::DatabaseBusy,
+        "Database is busy, retry shortly",
+    )?;
+    response
+        .headers_mut()
+        .set("Retry-After", &retry_after.to_string())?;
+    Ok(response)
+}
+
+// ============================================
+// USER CRUD HANDLERS
+// ============================================
+
+// Absent params take their default; present ones must be positive integers so a
+// client typo is reported instead of silently replaced
+fn positive_param(url: &Url, name: &str, default: u32) -> std::result::Result<u32, String> {
+    match url.query_pairs().find(|(key, _)| key == name) {
+        None => Ok(default),
+        Some((_, value)) => match value.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("{} must be a positive integer", name)),
+        },
+    }
+}
+
+// `id` breaks ties between rows created in the same instant, so every page
+// boundary is deterministic. Backed by idx_users_created_at_id.
+const USER_LIST_ORDER: &str = "created_at DESC, id DESC";
+
+// Must match the route registered in `fetch`
+const USER_CURSOR_ENDPOINT: &str = "/api/users/cursor";
+
+// Deep OFFSETs make D1 walk and discard every skipped row, so past
+// `max_offset` clients are sent to keyset pagination instead
+fn parse_list_query(url: &Url, max_offset: u32) -> std::result::Result<Paginator, String> {
+    let query = Paginator::new(
+        positive_param(url, "page", 1)?,
+        positive_param(url, "limit", DEFAULT_PAGE_LIMIT)?,
+    );
+    if query.offset() > max_offset {
+        return Err(format!(
+            "page too deep, use cursor pagination: GET {}",
+            USER_CURSOR_ENDPOINT
+        ));
+    }
+    Ok(query)
+}
+
+// Opaque, URL-safe position after the last row of a page (hex of created_at and id)
+fn encode_user_cursor(created_at: &str, id: &str) -> String {
+    hex(format!("{}\n{}", created_at, id).as_bytes())
+}
+
+fn decode_user_cursor(cursor: &str) -> Option<(String, String)> {
+    let text = String::from_utf8(unhex(cursor)?).ok()?;
+    let (created_at, id) = text.split_once('\n')?;
+    Some((created_at.to_string(), id.to_string()))
+}
+
+// Keyset pagination in USER_LIST_ORDER: cost doesn't grow with depth
+async fn handle_list_users_cursor(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let url = req.url()?;
+    let limit = match positive_param(&url, "limit", DEFAULT_PAGE_LIMIT) {
+        Ok(limit) => limit.min(MAX_PAGE_LIMIT),
+        Err(message) => return error_response(&ctx, ErrorCode::ValidationFailed, message),
+    };
+    let after = match url.query_pairs().find(|(key, _)| key == "cursor") {
+        None => None,
+        Some((_, cursor)) => match decode_user_cursor(&cursor) {
+            Some(position) => Some(position),
+            None => return error_response(&ctx, ErrorCode::ValidationFailed, "Invalid cursor"),
+        },
+    };
+
+    let db = d1_session(&req, &ctx, false)?;
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+    // One extra row tells us whether there is a next page
+    let statement = match &after {
+        None => db
+            .prepare(format!(
+                "SELECT * FROM users WHERE deleted_at IS NULL{} ORDER BY {} LIMIT ?",
+                scope.clause(),
+                USER_LIST_ORDER
+            ))
+            .bind(&scope.bind(Vec::new(), vec![(limit + 1).into()]))?,
+        Some((created_at, id)) => db
+            .prepare(format!(
+                "SELECT * FROM users WHERE deleted_at IS NULL \
+                 AND (created_at < ? OR (created_at = ? AND id < ?)){} ORDER BY {} LIMIT ?",
+                scope.clause(),
+                USER_LIST_ORDER
+            ))
+            .bind(&scope.bind(
+                vec![
+                    created_at.as_str().into(),
+                    created_at.as_str().into(),
+                    id.as_str().into(),
+                ],
+                vec![(limit + 1).into()],
+            ))?,
+    };
+    let mut users = statement.all().await?.results::<User>()?;
+    open_users(
+        email_cipher(&ctx.env, &ctx.data.config)?.as_ref(),
+        &mut users,
+    )?;
+
+    let next_cursor = if users.len() > limit as usize {
+        users.truncate(limit as usize);
+        users
+            .last()
+            .map(|u| encode_user_cursor(&u.created_at, &u.id))
+    } else {
+        None
+    };
+
+    respond(
+        &ctx,
+        &CursorPage {
+            data: users,
+            next_cursor,
+        },
+    )
+    .and_then(|r| with_bookmark(&db, r))
+}
+
+async fn handle_list_users(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let paginator = match parse_list_query(&req.url()?, ctx.data.config.max_offset) {
+        Ok(paginator) => paginator,
+        Err(message) => return error_response(&ctx, ErrorCode::ValidationFailed, message),
+    };
+
+    let db = d1_session(&req, &ctx, false)?;
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+
+    let url = req.url()?;
+    let mut etag = None;
+    let mut version_count = None;
+    if ctx.data.config.list_etags {
+        let version = list_version(&db, &scope).await?;
+        let tag = list_etag(&url, ctx.data.format.content_type(), &version);
+        if let Some(if_none_match) = req.headers().get("If-None-Match")? {
+            if compare_etag(&if_none_match, &tag, false) {
+                let mut headers = Headers::new();
+                headers.set("ETag", &tag)?;
+                let response = Response::empty()?.with_status(304).with_headers(headers);
+                return with_bookmark(&db, response);
+            }
+        }
+        version_count = Some(version.count);
+        etag = Some(tag);
+    }
+
+    // Get users with pagination
+    let mut users = db
+        .prepare(format!(
+            "SELECT * FROM users WHERE deleted_at IS NULL{} ORDER BY {} LIMIT ? OFFSET ?",
+            scope.clause(),
+            USER_LIST_ORDER
+        ))
+        .bind(&scope.bind(
+            Vec::new(),
+            vec![paginator.limit.into(), paginator.offset().into()],
+        ))?
+        .all()
+        .await?
+        .results::<User>()?;
+    open_users(
+        email_cipher(&ctx.env, &ctx.data.config)?.as_ref(),
+        &mut users,
+    )?;
+
+    // The counter only holds the global total
+    let count = if ctx.data.config.user_counter && scope == TenantScope::All {
+        user_count(&ctx.env, &db).await?
+    } else if let Some(count) = version_count {
+        count
+    } else {
+        count_active_users(&db, &scope).await?
+    };
+
+    let mut response = respond(&ctx, &paginator.build(users, count))?;
+    if let Some(etag) = etag {
+        response.headers_mut().set("ETag", &etag)?;
+    }
+    with_bookmark(&db, response)
+}
+
+// What a list page depends on, short of the rows themselves: the active
+// count plus the latest timestamp each kind of change leaves behind
+// (creates/updates, deletes and restores, verification, anonymization).
+// Any write through the API moves at least one of them.
+#[derive(Debug, PartialEq)]
+struct ListVersion {
+    count: u64,
+    stamps: Vec<Option<String>>,
+}
+
+async fn list_version(db: &D1Database, scope: &TenantScope) -> Result<ListVersion> {
+    let row = db
+        .prepare(format!(
+            "SELECT COUNT(*) AS count, \
+             MAX(COALESCE(updated_at, created_at)) AS updated, \
+             MAX(email_verified_at) AS verified, \
+             MAX(anonymized_at) AS anonymized, \
+             (SELECT MAX(deleted_at) FROM users WHERE deleted_at IS NOT NULL{0}) AS deleted \
+             FROM users WHERE deleted_at IS NULL{0}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(scope.bind(Vec::new(), Vec::new()), Vec::new()))?
+        .first::<serde_json::Value>(None)
+        .await?;
+    let stamp = |name: &str| {
+        row.as_ref()
+            .and_then(|r| r.get(name))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+    Ok(ListVersion {
+        count: parse_count(row.as_ref())?,
+        stamps: vec![
+            stamp("updated"),
+            stamp("deleted"),
+            stamp("verified"),
+            stamp("anonymized"),
+        ],
+    })
+}
+
+// Weak, since the same rows can serialize differently. Every query param
+// goes in (sorted, so their order doesn't matter), and the response format,
+// so a page and its pretty or MessagePack twin never share a tag.
+fn list_etag(url: &Url, content_type: &str, version: &ListVersion) -> String {
+    let mut params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    params.sort();
+    let mut key = format!("{}\n{}", content_type, version.count);
+    for (name, value) in &params {
+        key.push_str(&format!("\n{}={}", name, value));
+    }
+    for stamp in &version.stamps {
+        key.push('\n');
+        key.push_str(stamp.as_deref().unwrap_or("-"));
+    }
+    format!("W/\"{}\"", &sha256_hex(&key)[..32])
+}
+
+async fn count_active_users(db: &D1Database, scope: &TenantScope) -> Result<u64> {
+    let row = db
+        .prepare(format!(
+            "SELECT COUNT(*) as count FROM users WHERE deleted_at IS NULL{}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(Vec::new(), Vec::new()))?
+        .first::<serde_json::Value>(None)
+        .await?;
+    parse_count(row.as_ref())
+}
+
+// Reads `count` from a COUNT(*) row. Values cross the JS boundary as f64, so
+// whole floats are accepted; anything else is an error rather than a silent 0.
+fn parse_count(row: Option<&serde_json::Value>) -> Result<u64> {
+    let value = row
+        .and_then(|r| r.get("count"))
+        .ok_or_else(|| Error::RustError("count query returned no count".into()))?;
+    if let Some(n) = value.as_u64() {
+        return Ok(n);
+    }
+    match value.as_f64() {
+        Some(f) if f >= 0.0 && f.fract() == 0.0 && f <= u64::MAX as f64 => Ok(f as u64),
+        _ => Err(Error::RustError(format!(
+            "count is not a non-negative integer: {}",
+            value
+        ))),
+    }
+}
+
+// One non-empty local part, and a dotted domain with no empty labels
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.trim().split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty())
+}
+
+// The key emails are unique on, per tenant (the `email_normalized`
+// column); the address as entered stays in `email` for display. Rows keep
+// the normalization that applied when they were written, so changing the
+// domains only affects new and updated emails.
+fn normalized_email(email: &str, plus_domains: &[String]) -> String {
+    let email = email.trim().to_lowercase();
+    let Some((local, domain)) = email.split_once('@') else {
+        return email;
+    };
+    if !plus_domains.iter().any(|d| d == "*" || d == domain) {
+        return email;
+    }
+    match local.split_once('+') {
+        Some((base, _)) if !base.is_empty() => format!("{}@{}", base, domain),
+        _ => email,
+    }
+}
+
+// ============================================
+// EMAIL ENCRYPTION
+// ============================================
+
+// With EMAIL_ENCRYPTION on, no plaintext email reaches D1:
+//
+//   email            AES-256-GCM ciphertext, "enc:v1:" + hex(nonce || ciphertext)
+//   email_normalized HMAC of the normalized email (uniqueness, lookups)
+//   email_hmac       HMAC of the exact email (verification checks)
+//
+// Key handling: EMAIL_ENCRYPTION_KEY is a secret of 32 random bytes in hex
+// (`openssl rand -hex 32`). It is never used directly; the AES key and the
+// HMAC key are derived from it with HMAC-SHA256 over fixed labels, so one
+// can't stand in for the other. Nonces are 96 bits from the OS RNG per write
+// (crypto.getRandomValues on Workers, via getrandom's "js" feature), so the
+// same email encrypts differently each time and only the HMACs are
+// deterministic. Rotating the key means re-encrypting every row and
+// recomputing both HMACs; the "v1" prefix leaves room for a second key.
+//
+// Rows without the prefix (written before encryption was turned on, or
+// anonymized) are read as plaintext, but their lookup columns are plaintext
+// too, so turn this on for an empty table or backfill first. Decrypted users
+// still go to the KV user cache and to webhooks.
+const EMAIL_CIPHERTEXT_PREFIX: &str = "enc:v1:";
+const EMAIL_NONCE_LEN: usize = 12;
+
+struct EmailCipher {
+    encryption_key: [u8; 32],
+    index_key: [u8; 32],
+}
+
+// How an email is written to the three columns
+struct StoredEmail {
+    email: String,
+    lookup: String,
+    hmac: Option<String>,
+}
+
+impl EmailCipher {
+    fn from_secret(secret: &str) -> Option<Self> {
+        use hmac::{Hmac, Mac};
+
+        let master = unhex(secret.trim()).filter(|key| key.len() == 32)?;
+        let derive = |label: &str| -> [u8; 32] {
+            let mut mac =
+                Hmac::<sha2::Sha256>::new_from_slice(&master).expect("HMAC accepts any key length");
+            mac.update(label.as_bytes());
+            mac.finalize().into_bytes().into()
+        };
+        Some(EmailCipher {
+            encryption_key: derive("email-encryption-v1"),
+            index_key: derive("email-index-v1"),
+        })
+    }
+
+    fn encrypt(&self, email: &str, nonce: [u8; EMAIL_NONCE_LEN]) -> String {
+        use aes_gcm::aead::{Aead, KeyInit};
+
+        let cipher = aes_gcm::Aes256Gcm::new(&self.encryption_key.into());
+        let sealed = cipher
+            .encrypt(&nonce.into(), email.as_bytes())
+            .expect("AES-GCM encrypts any length under 64 GiB");
+        format!("{}{}{}", EMAIL_CIPHERTEXT_PREFIX, hex(&nonce), hex(&sealed))
+    }
+
+    fn decrypt(&self, stored: &str) -> Result<String> {
+        use aes_gcm::aead::{Aead, KeyInit};
+
+        let Some(sealed) = stored.strip_prefix(EMAIL_CIPHERTEXT_PREFIX) else {
+            return Ok(stored.to_string());
+        };
+        let invalid = || Error::RustError("stored email could not be decrypted".to_string());
+        let bytes = unhex(sealed)
+            .filter(|b| b.len() > EMAIL_NONCE_LEN)
+            .ok_or_else(invalid)?;
+        let (nonce, ciphertext) = bytes.split_at(EMAIL_NONCE_LEN);
+        let cipher = aes_gcm::Aes256Gcm::new(&self.encryption_key.into());
+        let plain = cipher
+            .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| invalid())?;
+        String::from_utf8(plain).map_err(|_| invalid())
+    }
+
+    fn index(&self, value: &str) -> String {
+        hmac_sha256(&self.index_key, value)
+    }
+}
+
+// None with EMAIL_ENCRYPTION off; a missing or malformed key is an error
+fn email_cipher(env: &Env, config: &Config) -> Result<Option<EmailCipher>> {
+    if !config.email_encryption {
+        return Ok(None);
+    }
+    let secret = env.secret("EMAIL_ENCRYPTION_KEY")?.to_string();
+    EmailCipher::from_secret(&secret)
+        .map(Some)
+        .ok_or_else(|| Error::RustError("EMAIL_ENCRYPTION_KEY must be 64 hex characters".into()))
+}
+
+fn stored_email(cipher: Option<&EmailCipher>, email: &str, plus_domains: &[String]) -> StoredEmail {
+    let normalized = normalized_email(email, plus_domains);
+    match cipher {
+        None => StoredEmail {
+            email: email.to_string(),
+            lookup: normalized,
+            hmac: None,
+        },
+        Some(cipher) => {
+            use aes_gcm::aead::{AeadCore, OsRng};
+
+            let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+            StoredEmail {
+                email: cipher.encrypt(email, nonce.into()),
+                lookup: cipher.index(&normalized),
+                hmac: Some(cipher.index(email)),
+            }
+        }
+    }
+}
+
+// Decrypts emails in place after a read
+fn open_users<'a>(
+    cipher: Option<&EmailCipher>,
+    users: impl IntoIterator<Item = &'a mut User>,
+) -> Result<()> {
+    if let Some(cipher) = cipher {
+        for user in users {
+            user.email = cipher.decrypt(&user.email)?;
+        }
+    }
+    Ok(())
+}
+
+// Applies a merge update to a decrypted user. A changed email drops its
+// verification.
+fn apply_user_update(
+    user: &mut User,
+    input: UpdateUserRequest,
+) -> std::result::Result<(), &'static str> {
+    if let Some(name) = input.name {
+        if name.trim().is_empty() {
+            return Err("Name cannot be empty");
+        }
+        user.name = name.trim().to_string();
+    }
+
+    if let Some(email) = input.email {
+        if !is_valid_email(&email) {
+            return Err("Invalid email");
+        }
+        let email = email.to_lowercase();
+        if email != user.email {
+            user.email_verified_at = None;
+        }
+        user.email = email;
+    }
+    Ok(())
+}
+
+// Every rule a new user must pass, short of the email uniqueness check. All
+// failures are collected so bulk clients can fix a row in one pass.
+fn validate_new_user(input: &CreateUserRequest) -> std::result::Result<NewUser, Vec<String>> {
+    let mut errors = Vec::new();
+    if input.name.trim().is_empty() {
+        errors.push("Name is required".to_string());
+    }
+    if !is_valid_email(&input.email) {
+        errors.push("Invalid email".to_string());
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(NewUser {
+        name: input.name.trim().to_string(),
+        email: input.email.trim().to_lowercase(),
+    })
+}
+
+fn validate_batch(items: Vec<serde_json::Value>) -> BatchResult<NewUser> {
+    let results = items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let result = serde_json::from_value::<CreateUserRequest>(item)
+                .map_err(|e| vec![format!("Invalid user object: {}", e)])
+                .and_then(|input| validate_new_user(&input));
+            match result {
+                Ok(user) => ItemOutcome::ok(index, user),
+                Err(errors) => {
+                    ItemOutcome::err(index, ErrorCode::ValidationFailed, errors.join("; "))
+                }
+            }
+        })
+        .collect();
+    BatchResult::new(results)
+}
+
+// Checks a bulk payload without writing anything. Email uniqueness is left to
+// the actual import, since nothing here is persisted.
+async fn handle_validate(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let input: ValidateUsersRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((ErrorCode::InvalidJson, message)) => {
+            return error_response(
+                &ctx,
+                ErrorCode::InvalidJson,
+                format!("{}; expected {{ \"users\": [...] }}", message),
+            );
+        }
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+
+    batch_response(&ctx, validate_batch(input.users))
+}
+
+// The email conflict check, scoped like every other users query
+fn email_conflict_sql(scope: &TenantScope, exclude_self: bool) -> String {
+    format!(
+        "SELECT id FROM users WHERE email_normalized = ?{}{}",
+        if exclude_self { " AND id != ?" } else { "" },
+        scope.clause()
+    )
+}
+
+async fn handle_create_user(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    // Parse body
+    let input: CreateUserRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+
+    // Validate
+    let input = match validate_new_user(&input) {
+        Ok(user) => user,
+        Err(errors) => {
+            return error_response(&ctx, ErrorCode::ValidationFailed, errors.join("; "));
+        }
+    };
+
+    let db = d1_session(&req, &ctx, true)?;
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+    let stored = stored_email(
+        email_cipher(&ctx.env, &ctx.data.config)?.as_ref(),
+        &input.email,
+        &ctx.data.config.email_plus_normalize_domains,
+    );
+
+    // Check for existing email
+    let existing = db
+        .prepare(email_conflict_sql(&scope, false))
+        .bind(&scope.bind(vec![stored.lookup.as_str().into()], Vec::new()))?
+        .first::<serde_json::Value>(None)
+        .await?;
+
+    if existing.is_some() {
+        return error_response(&ctx, ErrorCode::EmailConflict, "Email already exists");
+    }
+
+    // Create user
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+
+    let insert = db
+        .prepare(
+            "INSERT INTO users (id, name, email, email_normalized, email_hmac, tenant_id, \
+             created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            id.clone().into(),
+            input.name.as_str().into(),
+            stored.email.into(),
+            stored.lookup.into(),
+            stored.hmac.into(),
+            scope.tenant_id().into(),
+            now.clone().into(),
+        ])?;
+    d1_retry(&ctx, || insert.run()).await?;
+    adjust_user_count(&ctx, 1);
+
+    let user = User {
+        id,
+        name: input.name,
+        email: input.email,
+        created_at: now,
+        deleted_at: None,
+        email_verified_at: None,
+        tenant_id: scope.tenant_id().map(str::to_string),
+    };
+
+    dispatch_webhook(&ctx, "user.created", &user.id, serde_json::to_value(&user)?);
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(user),
+            error: None,
+            code: None,
+        },
+    )
+    .map(|r| r.with_status(201))
+    .and_then(|r| with_bookmark(&db, r))
+}
+
+// Stampede protection for the read-through cache. The first miss for a user
+// takes a KV lock and fills the cache; misses that see the lock poll the
+// cache instead of querying D1, for at most USER_CACHE_LOCK_WAIT_MS. KV has no
+// compare-and-set, so two misses can both take the lock and both query D1:
+// this cuts a stampede down to a few queries, it doesn't guarantee one.
+//
+// Two concurrent misses, A and B:
+//   1. A misses, finds no live lock, writes one, and queries D1.
+//   2. B misses, finds A's lock, and polls the cache every USER_CACHE_POLL_MS.
+//   3. A responds, then writes the cache entry and deletes the lock.
+//   4. B's next poll finds the entry and responds from it.
+// If A never fills (crashed, user not found), B stops at the wait deadline
+// and queries D1 itself; A's lock stops counting after USER_CACHE_LOCK_TTL_MS.
+const USER_CACHE_POLL_MS: u64 = 50;
+
+// The lock value is its expiry in epoch ms. KV expirations can't go below
+// 60s, so liveness is decided from the value, not from whether the key exists.
+fn lock_is_live(value: Option<&str>, now: f64) -> bool {
+    value
+        .and_then(|v| v.parse::<f64>().ok())
+        .is_some_and(|until| now < until)
+}
+
+async fn take_cache_lock(kv: &kv::KvStore, key: &str, until: f64) -> bool {
+    let put = match kv.put(key, until.to_string()) {
+        Ok(put) => put.expiration_ttl(60).execute().await,
+        Err(e) => Err(e),
+    };
+    match put {
+        Ok(()) => true,
+        Err(e) => {
+            // Filling without the lock only costs the protection
+            console_warn!("cache lock {} not taken: {}", key, Error::from(e));
+            false
+        }
+    }
+}
+
+// Stale-while-error. Entries live in KV for USER_CACHE_TTL + USER_CACHE_STALE_TTL
+// but only count as hits until `fresh_until_ms`; after that a read goes to D1
+// like a miss, keeping the old copy. If that D1 read fails, the old copy is
+// served with a `Warning: 110` header instead of the error.
+//
+//   1. GET /users/u1 at t=0 fills the cache (fresh until t=300s).
+//   2. At t=400s the entry is stale: the read queries D1, which errors.
+//   3. The stale user is returned with 200 and `Warning: 110 - "Response is Stale"`.
+// Entries written before this format existed read as misses.
+#[derive(Serialize, Deserialize)]
+struct CachedUser {
+    user: User,
+    fresh_until_ms: u64,
+}
+
+impl CachedUser {
+    fn is_fresh(&self, now_ms: u64) -> bool {
+        now_ms < self.fresh_until_ms
+    }
+}
+
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+// Ok(Err((user, error))) when the read failed and a stale copy can stand in
+fn stale_on_error<T>(
+    result: Result<T>,
+    stale: Option<User>,
+) -> Result<std::result::Result<T, (User, Error)>> {
+    match (result, stale) {
+        (Ok(value), _) => Ok(Ok(value)),
+        (Err(e), Some(user)) => Ok(Err((user, e))),
+        (Err(e), None) => Err(e),
+    }
+}
+
+// Polls until `poll` yields a value or `wait_ms` has been spent sleeping
+async fn wait_for_fill<T, P, PF, S, SF>(mut poll: P, sleep: S, wait_ms: u64) -> Option<T>
+where
+    P: FnMut() -> PF,
+    PF: std::future::Future<Output = Option<T>>,
+    S: Fn(u64) -> SF,
+    SF: std::future::Future<Output = ()>,
+{
+    let mut waited = 0;
+    while waited < wait_ms {
+        let step = USER_CACHE_POLL_MS.min(wait_ms - waited);
+        sleep(step).await;
+        waited += step;
+        if let Some(value) = poll().await {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// With HIDE_USER_EXISTENCE on, a caller without a valid X-API-Key or the
+// admin key (or, under TENANT_ISOLATION, without a tenant key) gets exactly
+// the 404 a missing id gets, and every response
+// (found, missing, refused) is held until HIDE_USER_EXISTENCE_FLOOR_MS has
+// passed, so neither the body nor the timing says whether an id exists:
+//
+//   no key / bad key, any id     -> 404 "User not found"
+//   valid key, missing id        -> 404 "User not found"
+//   valid key, existing id       -> 200 with the user
+//
+// The tradeoff: every lookup costs at least the floor, including cache hits,
+// and the floor only hides timing while it is above the slowest D1 read.
+// Clients can no longer tell "no access" from "no such user" either.
+fn user_lookup_allowed(hide_existence: bool, caller: &ApiCaller, admin: bool) -> bool {
+    !hide_existence || admin || matches!(caller, ApiCaller::Key(_))
+}
+
+// How much longer to hold a response that took `elapsed_ms`
+fn existence_padding_ms(elapsed_ms: u64, floor_ms: u64) -> u64 {
+    floor_ms.saturating_sub(elapsed_ms)
+}
+
+async fn handle_get_user(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !ctx.data.config.hide_user_existence {
+        let scope = match tenant_scope(&req, &ctx).await? {
+            Ok(scope) => scope,
+            Err(denied) => return Ok(denied),
+        };
+        return get_user(req, ctx, scope).await;
+    }
+    let started = Date::now().as_millis();
+    let floor_ms = ctx.data.config.hide_user_existence_floor_ms;
+    let admin = is_admin(&req, &ctx)?;
+    // Resolved once, for both the existence gate and the tenant scope
+    let caller = lookup_api_key(&req, &ctx).await?;
+    let scope = caller_tenant_scope(ctx.data.config.tenant_isolation, &caller);
+    let response = match scope {
+        Some(scope) if user_lookup_allowed(true, &caller, admin) => get_user(req, ctx, scope).await,
+        // Tenant denials are a missing id too
+        _ => error_response(&ctx, ErrorCode::UserNotFound, "User not found"),
+    };
+    let pad = existence_padding_ms(Date::now().as_millis().saturating_sub(started), floor_ms);
+    if pad > 0 {
+        Delay::from(std::time::Duration::from_millis(pad)).await;
+    }
+    response
+}
+
+// Read-through: serve from KV when present, otherwise load from D1 and cache in
+// the background. Writes invalidate the entry; KV reads elsewhere may still
+// see the old value for up to 60s (KV's eventual consistency window), so a
+// request carrying a D1 bookmark skips the cache and reads from its session.
+async fn get_user(req: Request, ctx: RouteContext<ReqCtx>, scope: TenantScope) -> Result<Response> {
+    let id = ctx.param("id").unwrap();
+    let kv = ctx.kv("CACHE")?;
+    let cache_key = user_cache_key(user_cache_version(&kv).await?, id);
+    let has_bookmark = req.headers().get(D1_BOOKMARK_HEADER)?.is_some();
+
+    let mut lock_key = None;
+    let mut stale = None;
+    let cached = if has_bookmark {
+        None
+    } else {
+        let cached = async { Ok(kv.get(&cache_key).json::<CachedUser>().await.ok().flatten()) };
+        match within_deadline(&ctx, cached).await? {
+            Some(entry) if entry.is_fresh(Date::now().as_millis()) => Some(entry.user),
+            entry => {
+                stale = entry.map(|entry| entry.user);
+                let key = CacheKey::new(KeySpace::Lock).part("user").part(id).build();
+                let now = Date::now().as_millis() as f64;
+                if lock_is_live(kv.get(&key).text().await?.as_deref(), now) {
+                    // None once the wait is over: go to D1 like any other miss
+                    wait_for_fill(
+                        || async {
+                            kv.get(&cache_key)
+                                .json::<CachedUser>()
+                                .await
+                                .ok()
+                                .flatten()
+                                .filter(|entry| entry.is_fresh(Date::now().as_millis()))
+                                .map(|entry| entry.user)
+                        },
+                        |ms| Delay::from(std::time::Duration::from_millis(ms)),
+                        ctx.data.config.user_cache_lock_wait_ms,
+                    )
+                    .await
+                } else {
+                    let until = now + ctx.data.config.user_cache_lock_ttl_ms as f64;
+                    if take_cache_lock(&kv, &key, until).await {
+                        lock_key = Some(key);
+                    }
+                    None
+                }
+            }
+        }
+    };
+    if let Some(user) = cached {
+        // Cached entries are shared by all tenants; D1 would 404 the same way
+        if !scope.allows(&user) {
+            return error_response(&ctx, ErrorCode::UserNotFound, "User not found");
+        }
+        return respond(
+            &ctx,
+            &ApiResponse {
+                success: true,
+                data: Some(user),
+                error: None,
+                code: None,
+            },
+        );
+    }
+
+    let db = d1_session(&req, &ctx, false)?;
+
+    let statement = db
+        .prepare(format!(
+            "SELECT * FROM users WHERE id = ? AND deleted_at IS NULL{}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(vec![id.into()], Vec::new()))?;
+    let cipher = email_cipher(&ctx.env, &ctx.data.config)?;
+    let read = within_deadline(&ctx, statement.first::<User>(None))
+        .await
+        .and_then(|mut user| open_users(cipher.as_ref(), &mut user).map(|()| user));
+    let user = match stale_on_error(read, stale.filter(|user| scope.allows(user)))? {
+        Ok(user) => user,
+        Err((stale, e)) => {
+            console_warn!("serving stale cache entry {}: {}", stale.id, e);
+            let mut response = respond(
+                &ctx,
+                &ApiResponse {
+                    success: true,
+                    data: Some(stale),
+                    error: None,
+                    code: None,
+                },
+            )?;
+            response.headers_mut().set("Warning", STALE_WARNING)?;
+            return Ok(response);
+        }
+    };
+
+    let ttl = ctx.data.config.user_cache_ttl;
+    let body = user
+        .as_ref()
+        .map(|user| {
+            serde_json::to_string(&CachedUser {
+                user: user.clone(),
+                fresh_until_ms: Date::now().as_millis() + ttl * 1000,
+            })
+        })
+        .transpose()?;
+    if body.is_some() || lock_key.is_some() {
+        let ttl = ttl + ctx.data.config.user_cache_stale_ttl;
+        spawn_background(&ctx, "user cache write", async move {
+            if let Some(body) = body {
+                kv.put(&cache_key, body)?
+                    .expiration_ttl(ttl)
+                    .execute()
+                    .await?;
+            }
+            // Released only after the write, so waiters find the entry
+            if let Some(lock_key) = lock_key {
+                kv.delete(&lock_key).await?;
+            }
+            Ok(())
+        });
+    }
+
+    match user {
+        Some(user) => respond(
+            &ctx,
+            &ApiResponse {
+                success: true,
+                data: Some(user),
+                error: None,
+                code: None,
+            },
+        )
+        .and_then(|r| with_bookmark(&db, r)),
+        None => error_response(&ctx, ErrorCode::UserNotFound, "User not found"),
+    }
+}
+
+async fn handle_update_user(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let id = ctx.param("id").unwrap();
+    let db = d1_session(&req, &ctx, true)?;
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+
+    // Check if user exists
+    let existing = db
+        .prepare(format!(
+            "SELECT * FROM users WHERE id = ? AND deleted_at IS NULL{}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(vec![id.into()], Vec::new()))?
+        .first::<User>(None)
+        .await?;
+    let cipher = email_cipher(&ctx.env, &ctx.data.config)?;
+
+    let mut user = match existing {
+        Some(u) => u,
+        None => {
+            return error_response(&ctx, ErrorCode::UserNotFound, "User not found");
+        }
+    };
+    // Patches, comparisons and the response all work on the plaintext email
+    open_users(cipher.as_ref(), [&mut user])?;
+
+    // Parse update data. PATCH also takes a JSON Patch document, which is
+    // reduced to the same update; anything else is a merge of the given fields.
+    let json_patch = req.method() == Method::Patch
+        && req
+            .headers()
+            .get("Content-Type")?
+            .is_some_and(|ct| ct.trim().starts_with(JSON_PATCH_CONTENT_TYPE));
+    let parsed = if json_patch {
+        parse_json_body::<Vec<PatchOp>>(&req.text().await?)
+            .and_then(|ops| apply_json_patch(&user, &ops))
+    } else {
+        parse_json_body::<UpdateUserRequest>(&req.text().await?)
+    };
+    let input = match parsed {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+
+    // Apply updates
+    if let Err(message) = apply_user_update(&mut user, input) {
+        return error_response(&ctx, ErrorCode::ValidationFailed, message);
+    }
+    let stored = stored_email(
+        cipher.as_ref(),
+        &user.email,
+        &ctx.data.config.email_plus_normalize_domains,
+    );
+    let conflict = db
+        .prepare(email_conflict_sql(&scope, true))
+        .bind(&scope.bind(vec![stored.lookup.as_str().into(), id.into()], Vec::new()))?
+        .first::<serde_json::Value>(None)
+        .await?;
+    if conflict.is_some() {
+        return error_response(&ctx, ErrorCode::EmailConflict, "Email already exists");
+    }
+
+    // Update in database
+    // SET expressions see the old row, so this keeps verification only when
+    // the email is unchanged
+    let update = db
+        .prepare(format!(
+            "UPDATE users SET name = ?, email = ?, email_normalized = ?, email_hmac = ?, \
+             updated_at = ?, \
+             email_verified_at = CASE WHEN email = ? OR email_hmac = ? THEN email_verified_at END \
+             WHERE id = ?{}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(
+            vec![
+                user.name.clone().into(),
+                stored.email.into(),
+                stored.lookup.into(),
+                stored.hmac.as_deref().into(),
+                chrono::Utc::now().to_rfc3339().into(),
+                user.email.clone().into(),
+                stored.hmac.as_deref().into(),
+                id.into(),
+            ],
+            Vec::new(),
+        ))?;
+    d1_retry(&ctx, || update.run()).await?;
+
+    invalidate_user_cache(&ctx, id).await?;
+    dispatch_webhook(&ctx, "user.updated", id, serde_json::to_value(&user)?);
+    publish_user_event(&ctx, id, "updated", serde_json::to_value(&user)?);
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(user),
+            error: None,
+            code: None,
+        },
+    )
+    .and_then(|r| with_bookmark(&db, r))
+}
+
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+// One RFC 6902 operation. `from` (move/copy) is never accepted.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchOp {
+    op: String,
+    path: String,
+    #[serde(default)]
+    value: Option<serde_json::Value>,
+}
+
+// Applies the ops in order to `{ "name", "email" }` and returns the resulting
+// fields as an update, so validation and the write are shared with PUT. Any
+// failure, including a `test`, aborts the whole patch.
+fn apply_json_patch(
+    user: &User,
+    ops: &[PatchOp],
+) -> std::result::Result<UpdateUserRequest, (ErrorCode, String)> {
+    let mut doc = serde_json::Map::new();
+    doc.insert("name".into(), user.name.clone().into());
+    doc.insert("email".into(), user.email.clone().into());
+
+    for (i, patch) in ops.iter().enumerate() {
+        let field = match patch.path.as_str() {
+            "/name" => "name",
+            "/email" => "email",
+            path => {
+                return Err((
+                    ErrorCode::UnsupportedPatch,
+                    format!("op {}: path {} cannot be patched", i, path),
+                ));
+            }
+        };
+        let value = || {
+            patch.value.clone().ok_or_else(|| {
+                (
+                    ErrorCode::ValidationFailed,
+                    format!("op {}: {} needs a value", i, patch.op),
+                )
+            })
+        };
+        match patch.op.as_str() {
+            // For object members add and replace differ only on missing members
+            "add" => {
+                doc.insert(field.into(), value()?);
+            }
+            "replace" | "remove" if !doc.contains_key(field) => {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    format!("op {}: {} does not exist", i, patch.path),
+                ));
+            }
+            "replace" => {
+                doc.insert(field.into(), value()?);
+            }
+            "remove" => {
+                doc.remove(field);
+            }
+            "test" => {
+                if doc.get(field) != Some(&value()?) {
+                    return Err((
+                        ErrorCode::PatchTestFailed,
+                        format!("op {}: test failed for {}", i, patch.path),
+                    ));
+                }
+            }
+            op => {
+                return Err((
+                    ErrorCode::UnsupportedPatch,
+                    format!("op {}: unsupported op {}", i, op),
+                ));
+            }
+        }
+    }
+
+    let field = |name: &str| match doc.get(name) {
+        Some(serde_json::Value::String(value)) => Ok(value.clone()),
+        Some(_) => Err((
+            ErrorCode::ValidationFailed,
+            format!("{} must be a string", name),
+        )),
+        None => Err((ErrorCode::ValidationFailed, format!("{} is required", name))),
+    };
+    Ok(UpdateUserRequest {
+        name: Some(field("name")?),
+        email: Some(field("email")?),
+    })
+}
+
+// Soft delete: the row stays so an admin can restore it
+async fn handle_delete_user(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let id = ctx.param("id").unwrap();
+    let db = d1_session(&req, &ctx, true)?;
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+
+    let delete = db
+        .prepare(format!(
+            "UPDATE users SET deleted_at = ? WHERE id = ? AND deleted_at IS NULL{}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(
+            vec![chrono::Utc::now().to_rfc3339().into(), id.into()],
+            Vec::new(),
+        ))?;
+    let result = d1_retry(&ctx, || delete.run()).await?;
+
+    if result.meta().map(|m| m.changes).unwrap_or(0) == 0 {
+        return error_response(&ctx, ErrorCode::UserNotFound, "User not found");
+    }
+
+    adjust_user_count(&ctx, -1);
+    check_deleted_cap(&ctx);
+    invalidate_user_cache(&ctx, id).await?;
+    dispatch_webhook(&ctx, "user.deleted", id, serde_json::json!({ "id": id }));
+    publish_user_event(&ctx, id, "deleted", serde_json::json!({ "id": id }));
+
+    respond(
+        &ctx,
+        &ApiResponse::<()> {
+            success: true,
+            data: None,
+            error: None,
+            code: None,
+        },
+    )
+    .and_then(|r| with_bookmark(&db, r))
+}
+
+async fn handle_batch_get_users(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let input: BatchIdsRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+    let ids = match batch_ids(input.ids, D1_MAX_BOUND_PARAMS - scope.param_count()) {
+        Ok(ids) => ids,
+        Err(message) => return error_response(&ctx, ErrorCode::ValidationFailed, message),
+    };
+
+    let db = d1_session(&req, &ctx, false)?;
+    let mut users = match in_clause(&ids) {
+        None => Vec::new(),
+        Some(clause) => db
+            .prepare(format!(
+                "SELECT * FROM users WHERE deleted_at IS NULL AND id IN {}{}",
+                clause.sql,
+                scope.clause()
+            ))
+            .bind(&scope.bind(clause.bind_values(), Vec::new()))?
+            .all()
+            .await?
+            .results::<User>()?,
+    };
+    open_users(
+        email_cipher(&ctx.env, &ctx.data.config)?.as_ref(),
+        &mut users,
+    )?;
+    // Indices follow the de-duplicated ids
+    let results = ids
+        .iter()
+        .enumerate()
+        .map(|(index, id)| match users.iter().position(|u| &u.id == id) {
+            Some(found) => ItemOutcome::ok(index, users.swap_remove(found)),
+            None => ItemOutcome::err(index, ErrorCode::UserNotFound, "User not found"),
+        })
+        .collect();
+
+    batch_response(&ctx, BatchResult::new(results)).and_then(|r| with_bookmark(&db, r))
+}
+
+async fn handle_batch_delete_users(
+    mut req: Request,
+    ctx: RouteContext<ReqCtx>,
+) -> Result<Response> {
+    let input: BatchIdsRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+    // One parameter goes to the deleted_at timestamp
+    let ids = match batch_ids(input.ids, D1_MAX_BOUND_PARAMS - 1 - scope.param_count()) {
+        Ok(ids) => ids,
+        Err(message) => return error_response(&ctx, ErrorCode::ValidationFailed, message),
+    };
+
+    let db = d1_session(&req, &ctx, true)?;
+    let deleted: Vec<String> = match in_clause(&ids) {
+        None => Vec::new(),
+        Some(clause) => {
+            let mut params = vec![chrono::Utc::now().to_rfc3339().into()];
+            params.extend(clause.bind_values());
+            let delete = db
+                .prepare(format!(
+                    "UPDATE users SET deleted_at = ? WHERE deleted_at IS NULL AND id IN {}{} \
+                     RETURNING id",
+                    clause.sql,
+                    scope.clause()
+                ))
+                .bind(&scope.bind(params, Vec::new()))?;
+            d1_retry(&ctx, || delete.all())
+                .await?
+                .results::<serde_json::Value>()?
+                .into_iter()
+                .filter_map(|row| row["id"].as_str().map(str::to_string))
+                .collect()
+        }
+    };
+
+    if !deleted.is_empty() {
+        adjust_user_count(&ctx, -(deleted.len() as i64));
+        check_deleted_cap(&ctx);
+    }
+    for id in &deleted {
+        invalidate_user_cache(&ctx, id).await?;
+        dispatch_webhook(&ctx, "user.deleted", id, serde_json::json!({ "id": id }));
+        publish_user_event(&ctx, id, "deleted", serde_json::json!({ "id": id }));
+    }
+    let results = ids
+        .iter()
+        .enumerate()
+        .map(|(index, id)| {
+            if deleted.contains(id) {
+                ItemOutcome::ok(index, serde_json::json!({ "id": id }))
+            } else {
+                ItemOutcome::err(index, ErrorCode::UserNotFound, "User not found")
+            }
+        })
+        .collect();
+
+    batch_response(&ctx, BatchResult::new(results)).and_then(|r| with_bookmark(&db, r))
+}
+
+// Unlike the other lookups this must see soft-deleted rows
+fn check_restorable(user: Option<&User>) -> std::result::Result<(), ErrorCode> {
+    match user {
+        None => Err(ErrorCode::UserNotFound),
+        Some(u) if u.deleted_at.is_none() => Err(ErrorCode::UserNotDeleted),
+        Some(_) => Ok(()),
+    }
+}
+
+async fn handle_restore_user(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let id = ctx.param("id").unwrap();
+    let db = d1_session(&req, &ctx, true)?;
+
+    let mut user = db
+        .prepare("SELECT * FROM users WHERE id = ?")
+        .bind(&[id.into()])?
+        .first::<User>(None)
+        .await?;
+    open_users(
+        email_cipher(&ctx.env, &ctx.data.config)?.as_ref(),
+        &mut user,
+    )?;
+
+    match check_restorable(user.as_ref()) {
+        Err(ErrorCode::UserNotDeleted) => {
+            return error_response(&ctx, ErrorCode::UserNotDeleted, "User is not deleted");
+        }
+        Err(code) => return error_response(&ctx, code, "User not found"),
+        Ok(()) => {}
+    }
+
+    let restore = db
+        .prepare("UPDATE users SET deleted_at = NULL WHERE id = ?")
+        .bind(&[id.into()])?;
+    d1_retry(&ctx, || restore.run()).await?;
+
+    adjust_user_count(&ctx, 1);
+    let mut user = user.unwrap();
+    user.deleted_at = None;
+
+    invalidate_user_cache(&ctx, id).await?;
+    dispatch_webhook(&ctx, "user.restored", id, serde_json::to_value(&user)?);
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(user),
+            error: None,
+            code: None,
+        },
+    )
+    .and_then(|r| with_bookmark(&db, r))
+}
+
+// ============================================
+// EMAIL VERIFICATION
+// ============================================
+
+// Tokens are `<user id>.<expires unix secs>.<signature>`. The signature also
+// covers the email, so changing the address invalidates outstanding links
+// without storing anything.
+#[derive(Debug, PartialEq)]
+enum EmailTokenError {
+    Invalid,
+    Expired,
+}
+
+fn email_token_payload(id: &str, email: &str, expires: i64) -> String {
+    format!("verify-email\n{}\n{}\n{}", id, email, expires)
+}
+
+fn issue_email_token(keys: &SigningKeys, id: &str, email: &str, expires: i64) -> String {
+    format!(
+        "{}.{}.{}",
+        id,
+        expires,
+        keys.sign(&email_token_payload(id, email, expires))
+    )
+}
+
+// The user id, so the handler can load the row whose email the token must match
+fn email_token_user(token: &str) -> Option<&str> {
+    token.split('.').next().filter(|id| !id.is_empty())
+}
+
+// Tampering is checked before expiry, so a forged token never reads as merely expired
+fn check_email_token(
+    keys: &SigningKeys,
+    token: &str,
+    email: &str,
+    now: i64,
+) -> std::result::Result<(), EmailTokenError> {
+    let mut parts = token.split('.');
+    let (Some(id), Some(expires), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(EmailTokenError::Invalid);
+    };
+    let expires: i64 = expires.parse().map_err(|_| EmailTokenError::Invalid)?;
+    if !keys.verify(&email_token_payload(id, email, expires), signature) {
+        return Err(EmailTokenError::Invalid);
+    }
+    if now > expires {
+        return Err(EmailTokenError::Expired);
+    }
+    Ok(())
+}
+
+// Issues a link and hands it to the `user.email_verification_requested`
+// webhook; the receiver is responsible for sending the actual email. Each
+// call triggers a webhook, so it takes the admin key or a valid X-API-Key.
+async fn handle_send_verification(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? && !matches!(lookup_api_key(&req, &ctx).await?, ApiCaller::Key(_)) {
+        return error_response(
+            &ctx,
+            ErrorCode::Unauthorized,
+            "Admin key or valid X-API-Key required",
+        );
+    }
+    let Some(keys) = SigningKeys::from_env(&ctx.env) else {
+        console_error!("email verification needs SIGNING_SECRET");
+        return error_response(
+            &ctx,
+            ErrorCode::NotConfigured,
+            "Email verification is not configured",
+        );
+    };
+    let id = ctx.param("id").unwrap();
+    let db = d1_session(&req, &ctx, false)?;
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+
+    let Some(mut user) = db
+        .prepare(format!(
+            "SELECT * FROM users WHERE id = ? AND deleted_at IS NULL{}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(vec![id.into()], Vec::new()))?
+        .first::<User>(None)
+        .await?
+    else {
+        return error_response(&ctx, ErrorCode::UserNotFound, "User not found");
+    };
+    open_users(
+        email_cipher(&ctx.env, &ctx.data.config)?.as_ref(),
+        [&mut user],
+    )?;
+    if user.email_verified_at.is_some() {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            "Email is already verified",
+        );
+    }
+
+    let expires = chrono::Utc::now().timestamp() + ctx.data.config.email_verify_ttl;
+    let token = issue_email_token(&keys, &user.id, &user.email, expires);
+    let mut verify_url = req.url()?;
+    verify_url.set_path("/api/users/verify");
+    verify_url.set_query(Some(&format!("token={}", token)));
+
+    dispatch_webhook(
+        &ctx,
+        "user.email_verification_requested",
+        &user.id,
+        serde_json::json!({ "id": user.id, "email": user.email, "verify_url": verify_url.as_str() }),
+    );
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(serde_json::json!({ "expires_at": expires })),
+            error: None,
+            code: None,
+        },
+    )
+    .map(|r| r.with_status(202))
+}
+
+async fn handle_verify_email(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let Some(keys) = SigningKeys::from_env(&ctx.env) else {
+        console_error!("email verification needs SIGNING_SECRET");
+        return error_response(
+            &ctx,
+            ErrorCode::NotConfigured,
+            "Email verification is not configured",
+        );
+    };
+    let url = req.url()?;
+    let token = url
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .map(|(_, value)| value.to_string())
+        .unwrap_or_default();
+    let Some(id) = email_token_user(&token) else {
+        return error_response(&ctx, ErrorCode::InvalidToken, "Invalid token");
+    };
+
+    let db = d1_session(&req, &ctx, true)?;
+    let cipher = email_cipher(&ctx.env, &ctx.data.config)?;
+    let mut user = db
+        .prepare("SELECT * FROM users WHERE id = ? AND deleted_at IS NULL")
+        .bind(&[id.into()])?
+        .first::<User>(None)
+        .await?;
+    open_users(cipher.as_ref(), &mut user)?;
+    // An unknown user is reported like a forged token
+    let Some(mut user) = user else {
+        return error_response(&ctx, ErrorCode::InvalidToken, "Invalid token");
+    };
+
+    match check_email_token(&keys, &token, &user.email, chrono::Utc::now().timestamp()) {
+        Err(EmailTokenError::Invalid) => {
+            return error_response(&ctx, ErrorCode::InvalidToken, "Invalid token");
+        }
+        Err(EmailTokenError::Expired) => {
+            return error_response(&ctx, ErrorCode::TokenExpired, "Token has expired");
+        }
+        Ok(()) => {}
+    }
+
+    // Re-verifying keeps the original timestamp
+    if user.email_verified_at.is_none() {
+        let now = chrono::Utc::now().to_rfc3339();
+        let hmac = cipher.as_ref().map(|cipher| cipher.index(&user.email));
+        let update = db
+            .prepare(
+                "UPDATE users SET email_verified_at = ? WHERE id = ? AND (email = ? OR email_hmac = ?)",
+            )
+            .bind(&[
+                now.as_str().into(),
+                id.into(),
+                user.email.as_str().into(),
+                hmac.into(),
+            ])?;
+        d1_retry(&ctx, || update.run()).await?;
+        user.email_verified_at = Some(now);
+        invalidate_user_cache(&ctx, id).await?;
+    }
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(user),
+            error: None,
+            code: None,
+        },
+    )
+    .and_then(|r| with_bookmark(&db, r))
+}
+
+// ============================================
+// KV KEYS
+// ============================================
+
+// Every feature shares the CACHE namespace, so every key is built here with a
+// prefix for its keyspace. A feature can only read, write, or flush keys in
+// its own space; freeform keys from /api/cached live under `cache:`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KeySpace {
+    ApiKey,
+    Custom,
+    File,
+    Lock,
+    Meta,
+    User,
+    Webhook,
+    DeadLetter,
+}
+
+impl KeySpace {
+    fn prefix(self) -> &'static str {
+        match self {
+            KeySpace::ApiKey => "apikey:",
+            KeySpace::Custom => "cache:",
+            KeySpace::File => "file:",
+            KeySpace::Lock => "lock:",
+            KeySpace::Meta => "meta:",
+            KeySpace::User => "user:",
+            KeySpace::Webhook => "webhook:",
+            KeySpace::DeadLetter => "dlq:",
+        }
+    }
+
+    // Only cache keyspaces can be named for a flush. Cached users go stale via
+    // the version bump instead, and everything else is state.
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "cache" => Some(KeySpace::Custom),
+            "file" => Some(KeySpace::File),
+            _ => None,
+        }
+    }
+}
+
+struct CacheKey {
+    space: KeySpace,
+    version: Option<u64>,
+    parts: Vec<String>,
+}
+
+impl CacheKey {
+    fn new(space: KeySpace) -> Self {
+        Self {
+            space,
+            version: None,
+            parts: Vec::new(),
+        }
+    }
+
+    // Prefixes the key with `v{n}:`, ahead of the keyspace
+    fn version(mut self, version: u64) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    fn part(mut self, part: impl Into<String>) -> Self {
+        self.parts.push(part.into());
+        self
+    }
+
+    fn build(&self) -> String {
+        let version = self.version.map(|v| format!("v{}:", v)).unwrap_or_default();
+        format!("{}{}{}", version, self.space.prefix(), self.parts.join(":"))
+    }
+}
+
+// User cache entries are keyed `v{n}:user:{id}`, with n kept in KV. Bumping n
+// (POST /admin/cache/version) orphans every cached user at once; the old
+// entries are never read again and age out on their TTL. KV reads are cached
+// at the edge for up to a minute, so other locations can take that long to
+// see a bump.
+fn user_cache_version_key() -> String {
+    CacheKey::new(KeySpace::Meta)
+        .part("user_cache_version")
+        .build()
+}
+
+async fn user_cache_version(kv: &kv::KvStore) -> Result<u64> {
+    let stored = kv.get(&user_cache_version_key()).text().await?;
+    Ok(stored.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+fn user_cache_key(version: u64, id: &str) -> String {
+    CacheKey::new(KeySpace::User)
+        .version(version)
+        .part(id)
+        .build()
+}
+
+async fn invalidate_user_cache(ctx: &RouteContext<ReqCtx>, id: &str) -> Result<()> {
+    let kv = ctx.kv("CACHE")?;
+    let key = user_cache_key(user_cache_version(&kv).await?, id);
+    kv.delete(&key).await?;
+    Ok(())
+}
+
+// Two bumps racing can both land on the same n + 1; either way every entry
+// cached before them is orphaned
+async fn handle_bump_user_cache_version(
+    req: Request,
+    ctx: RouteContext<ReqCtx>,
+) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let kv = ctx.kv("CACHE")?;
+    let version = user_cache_version(&kv).await? + 1;
+    kv.put(&user_cache_version_key(), version.to_string())?
+        .execute()
+        .await?;
+    console_log!("user cache version bumped to {}", version);
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(serde_json::json!({ "version": version })),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// KV CACHE HANDLERS
+// ============================================
+
+// Values up to this size are buffered and returned as text; larger ones are
+// streamed straight from KV. KV caps values at 25 MiB, so even the largest
+// entry fits in memory: streaming lowers peak memory and time to first byte
+// rather than making otherwise-impossible responses possible.
+const KV_STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+// Stored as KV metadata so reads know the size before touching the value
+#[derive(Serialize, Deserialize)]
+struct CacheEntryMeta {
+    size: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    // Header absent, malformed, or multi-range: serve the whole value
+    Full,
+    // Inclusive start and end offsets
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn parse_byte_range(header: Option<&str>, size: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // Suffix range: the last N bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (size.saturating_sub(n), size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if size == 0 || start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+// ============================================
+// CACHE SHARDING
+// ============================================
+
+// With CACHE_SHARDS > 1, /api/cached entries are spread over that many KV
+// namespaces, bound as CACHE, CACHE_1, CACHE_2, ... A key's shard comes from
+// FNV-1a (stable across isolates and builds, unlike std's randomly seeded
+// hasher) fed into jump consistent hashing, so raising CACHE_SHARDS from n to
+// n + 1 only moves about 1/(n + 1) of the keys, all onto the new shard.
+// Moved keys read as misses until rewritten. Internal keyspaces (users,
+// locks, webhooks) stay on CACHE.
+const CACHE_SHARD_BINDINGS: &[&str] = &[
+    "CACHE", "CACHE_1", "CACHE_2", "CACHE_3", "CACHE_4", "CACHE_5", "CACHE_6", "CACHE_7",
+];
+
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// Lamping & Veach, "A Fast, Minimal Memory, Consistent Hash Algorithm"
+fn shard_for(key: &str, shards: usize) -> usize {
+    let mut hash = fnv1a_64(key.as_bytes());
+    let (mut bucket, mut next) = (-1i64, 0i64);
+    while next < shards.max(1) as i64 {
+        bucket = next;
+        hash = hash.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((hash >> 33) + 1) as f64)) as i64;
+    }
+    bucket as usize
+}
+
+// Binding name for the shard holding `key`
+fn cache_binding(config: &Config, key: &str) -> &'static str {
+    CACHE_SHARD_BINDINGS[shard_for(key, config.cache_shards)]
+}
+
+// worker-kv has no stream accessor, so call the binding's getWithMetadata
+// with `type: "stream"` directly
+async fn kv_get_stream(
+    env: &Env,
+    binding: &str,
+    key: &str,
+) -> Result<Option<(ResponseBody, Option<CacheEntryMeta>)>> {
+    use wasm_bindgen::JsCast;
+
+    let kv = js_sys::Reflect::get(env, &binding.into())?;
+    let get_with_metadata: js_sys::Function =
+        js_sys::Reflect::get(&kv, &"getWithMetadata".into())?.dyn_into()?;
+
+    let options = js_sys::Object::new();
+    js_sys::Reflect::set(&options, &"type".into(), &"stream".into())?;
+    let promise: js_sys::Promise = get_with_metadata
+        .call2(&kv, &key.into(), &options)?
+        .dyn_into()?;
+    let pair = wasm_bindgen_futures::JsFuture::from(promise).await?;
+
+    let value = js_sys::Reflect::get(&pair, &"value".into())?;
+    if value.is_null() {
+        return Ok(None);
+    }
+
+    let metadata = js_sys::Reflect::get(&pair, &"metadata".into())?;
+    let meta = if metadata.is_null() {
+        None
+    } else {
+        js_sys::JSON::stringify(&metadata)?
+            .as_string()
+            .and_then(|json| serde_json::from_str(&json).ok())
+    };
+
+    Ok(Some((ResponseBody::Stream(value.unchecked_into()), meta)))
+}
+
+// The range is worked out from the metadata size, so it's checked against the
+// bytes actually read; a value and size that disagree give None
+fn slice_range(bytes: &[u8], start: u64, end: u64) -> Option<&[u8]> {
+    let start = usize::try_from(start).ok()?;
+    let end = usize::try_from(end).ok()?;
+    bytes.get(start..=end)
+}
+
+async fn handle_cache_get(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let key = ctx.param("key").unwrap();
+
+    let key = CacheKey::new(KeySpace::Custom).part(key).build();
+    let binding = cache_binding(&ctx.data.config, &key);
+    let Some((body, meta)) = kv_get_stream(&ctx.env, binding, &key).await? else {
+        return error_response(&ctx, ErrorCode::NotFound, "Not found");
+    };
+    let size = meta.map(|m| m.size);
+
+    // KV has no ranged reads, so a range is sliced from the buffered value.
+    // Entries written before sizes were recorded don't support ranges.
+    if let Some(size) = size {
+        match parse_byte_range(req.headers().get("Range")?.as_deref(), size) {
+            ByteRange::Full => {}
+            ByteRange::Partial(start, end) => {
+                let bytes = Response::from_body(body)?.bytes().await?;
+                let Some(slice) = slice_range(&bytes, start, end) else {
+                    console_warn!(
+                        "cache entry {} is {} bytes, metadata says {}",
+                        key,
+                        bytes.len(),
+                        size
+                    );
+                    let mut headers = Headers::new();
+                    headers.set("Content-Range", &format!("bytes */{}", bytes.len()))?;
+                    return Ok(Response::empty()?.with_status(416).with_headers(headers));
+                };
+                let slice = slice.to_vec();
+
+                let mut headers = Headers::new();
+                headers.set("Content-Type", "text/plain; charset=utf-8")?;
+                headers.set(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", start, end, size),
+                )?;
+                headers.set("Accept-Ranges", "bytes")?;
+                return Ok(Response::from_bytes(slice)?
+                    .with_status(206)
+                    .with_headers(headers));
+            }
+            ByteRange::Unsatisfiable => {
+                let mut headers = Headers::new();
+                headers.set("Content-Range", &format!("bytes */{}", size))?;
+                return Ok(Response::empty()?.with_status(416).with_headers(headers));
+            }
+        }
+    }
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/plain; charset=utf-8")?;
+    if size.is_some() {
+        headers.set("Accept-Ranges", "bytes")?;
+    }
+    match size {
+        Some(size) if size <= KV_STREAM_THRESHOLD => {
+            let bytes = Response::from_body(body)?.bytes().await?;
+            Ok(Response::from_bytes(bytes)?.with_headers(headers))
+        }
+        _ => {
+            if let Some(size) = size {
+                headers.set("Content-Length", &size.to_string())?;
+            }
+            Ok(Response::from_body(body)?.with_headers(headers))
+        }
+    }
+}
+
+async fn handle_cache_set(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let key = CacheKey::new(KeySpace::Custom)
+        .part(ctx.param("key").unwrap())
+        .build();
+    let kv = ctx.kv(cache_binding(&ctx.data.config, &key))?;
+
+    let body = req.text().await?;
+    let meta = CacheEntryMeta {
+        size: body.len() as u64,
+    };
+
+    // Set with 1 hour expiration
+    kv.put(&key, body)?
+        .metadata(meta)?
+        .expiration_ttl(3600)
+        .execute()
+        .await?;
+
+    Response::ok("Cached")
+}
+
+// KV has no touch, so each key costs a read and a re-put: two subrequests
+const CACHE_TOUCH_MAX: usize = 100;
+// KV rejects expirations under 60s
+const KV_MIN_TTL: u64 = 60;
+
+#[derive(Deserialize)]
+struct CacheTouchRequest {
+    keys: Vec<String>,
+    #[serde(deserialize_with = "exact_integer")]
+    ttl: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct CacheTouchResult {
+    refreshed: Vec<String>,
+    absent: Vec<String>,
+}
+
+impl CacheTouchResult {
+    // `found[i]` says whether `keys[i]` existed and was re-put
+    fn from_outcomes(keys: Vec<String>, found: Vec<bool>) -> Self {
+        let (refreshed, absent): (Vec<_>, Vec<_>) =
+            keys.into_iter().zip(found).partition(|(_, found)| *found);
+        CacheTouchResult {
+            refreshed: refreshed.into_iter().map(|(key, _)| key).collect(),
+            absent: absent.into_iter().map(|(key, _)| key).collect(),
+        }
+    }
+}
+
+// Re-puts each entry (value and metadata) with the new TTL. A key that
+// expires between the read and the write is recreated for the new TTL.
+async fn handle_cache_touch(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let input: CacheTouchRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    if input.ttl < KV_MIN_TTL {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            format!("ttl must be at least {} seconds", KV_MIN_TTL),
+        );
+    }
+    let mut keys = Vec::with_capacity(input.keys.len());
+    for key in input.keys {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    if keys.len() > CACHE_TOUCH_MAX {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            format!("At most {} keys per touch", CACHE_TOUCH_MAX),
+        );
+    }
+
+    let ttl = input.ttl;
+    let outcomes = map_bounded(&keys, ctx.data.config.batch_concurrency, |name| {
+        let ctx = &ctx;
+        async move {
+            let key = CacheKey::new(KeySpace::Custom).part(name).build();
+            let kv = ctx.kv(cache_binding(&ctx.data.config, &key))?;
+            let (value, meta) = kv.get(&key).bytes_with_metadata::<CacheEntryMeta>().await?;
+            let Some(value) = value else {
+                return Ok(false);
+            };
+            let mut put = kv.put_bytes(&key, &value)?;
+            if let Some(meta) = meta {
+                put = put.metadata(meta)?;
+            }
+            put.expiration_ttl(ttl).execute().await?;
+            Ok::<_, Error>(true)
+        }
+    })
+    .await;
+    let found = outcomes.into_iter().collect::<Result<Vec<_>>>()?;
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(CacheTouchResult::from_outcomes(keys, found)),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// Every list and delete is a subrequest, and a request can make at most 1000
+// (50 on the free plan). Flushes stop below this budget and return a cursor so
+// the client can continue in a follow-up request.
+const KV_FLUSH_SUBREQUEST_BUDGET: usize = 900;
+
+#[derive(Serialize)]
+struct FlushResult {
+    deleted: usize,
+    partial: bool,
+    cursor: Option<String>,
+}
+
+fn is_subrequest_limit_error(message: &str) -> bool {
+    message.contains("Too many subrequests")
+}
+
+async fn handle_cache_flush(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let url = req.url()?;
+    let query: std::collections::HashMap<_, _> = url.query_pairs().collect();
+
+    // Scoped to one keyspace (default `cache`), optionally narrowed by prefix
+    let space = match query.get("space") {
+        None => KeySpace::Custom,
+        Some(name) => match KeySpace::parse(name) {
+            Some(space) => space,
+            None => {
+                return error_response(
+                    &ctx,
+                    ErrorCode::ValidationFailed,
+                    format!("Unknown keyspace: {}", name),
+                );
+            }
+        },
+    };
+    let prefix = CacheKey::new(space)
+        .part(
+            query
+                .get("prefix")
+                .map(|p| p.to_string())
+                .unwrap_or_default(),
+        )
+        .build();
+    // A sharded keyspace is flushed one shard at a time (?shard=N, default 0)
+    let shard = match query.get("shard").map(|s| s.parse::<usize>()) {
+        None => 0,
+        Some(Ok(shard)) if space == KeySpace::Custom && shard < ctx.data.config.cache_shards => {
+            shard
+        }
+        Some(_) => return error_response(&ctx, ErrorCode::ValidationFailed, "Invalid shard"),
+    };
+    let binding = CACHE_SHARD_BINDINGS[shard];
+    // Cursors handed out are bound to this prefix (and shard) when a signing
+    // secret is set
+    let bound = match shard {
+        0 => prefix.clone(),
+        _ => format!("{}:{}", binding, prefix),
+    };
+    let keys = SigningKeys::from_env(&ctx.env);
+    let mut cursor = match query.get("cursor") {
+        None => None,
+        Some(token) => match open_cursor(keys.as_ref(), &bound, token) {
+            Some(cursor) => Some(cursor),
+            None => return error_response(&ctx, ErrorCode::ValidationFailed, "Invalid cursor"),
+        },
+    };
+    let seal = |cursor: Option<String>| cursor.map(|c| seal_cursor(keys.as_ref(), &bound, &c));
+
+    let kv = ctx.kv(binding)?;
+    let mut deleted = 0;
+    let mut subrequests = 0;
+
+    // Walk the listing page by page. Deleted keys drop out of later listings, so
+    // resuming from the cursor of an interrupted page only re-lists survivors.
+    loop {
+        if subrequests >= KV_FLUSH_SUBREQUEST_BUDGET {
+            return flush_response(&ctx, deleted, true, seal(cursor));
+        }
+
+        let mut list = kv.list().prefix(prefix.clone());
+        if let Some(c) = &cursor {
+            list = list.cursor(c.clone());
+        }
+        let page = list.execute().await?;
+        subrequests += 1;
+
+        let remaining = KV_FLUSH_SUBREQUEST_BUDGET - subrequests;
+        let batch = &page.keys[..page.keys.len().min(remaining)];
+        let truncated = batch.len() < page.keys.len();
+
+        let results = map_bounded(batch, ctx.data.config.batch_concurrency, |key| {
+            kv.delete(&key.name)
+        })
+        .await;
+        subrequests += batch.len();
+
+        let mut limited = false;
+        for result in results {
+            match result {
+                Ok(()) => deleted += 1,
+                Err(e) => {
+                    let e = Error::from(e);
+                    if !is_subrequest_limit_error(&e.to_string()) {
+                        return Err(e);
+                    }
+                    limited = true;
+                }
+            }
+        }
+        if truncated || limited {
+            return flush_response(&ctx, deleted, true, seal(cursor));
+        }
+
+        if page.list_complete {
+            return flush_response(&ctx, deleted, false, None);
+        }
+        cursor = page.cursor;
+    }
+}
+
+// `<kv cursor>.<signature>`; unsigned when no signing secret is configured
+fn seal_cursor(keys: Option<&SigningKeys>, prefix: &str, cursor: &str) -> String {
+    match keys {
+        Some(keys) => format!(
+            "{}.{}",
+            cursor,
+            keys.sign(&format!("{}\n{}", prefix, cursor))
+        ),
+        None => cursor.to_string(),
+    }
+}
+
+fn open_cursor(keys: Option<&SigningKeys>, prefix: &str, token: &str) -> Option<String> {
+    let Some(keys) = keys else {
+        return Some(token.to_string());
+    };
+    let (cursor, signature) = token.rsplit_once('.')?;
+    keys.verify(&format!("{}\n{}", prefix, cursor), signature)
+        .then(|| cursor.to_string())
+}
+
+fn flush_response(
+    ctx: &RouteContext<ReqCtx>,
+    deleted: usize,
+    partial: bool,
+    cursor: Option<String>,
+) -> Result<Response> {
+    respond(
+        ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(FlushResult {
+                deleted,
+                partial,
+                cursor,
+            }),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// R2 STORAGE HANDLERS
+// ============================================
+
+// RFC 7231 IMF-fixdate, as used by Last-Modified
+fn http_date(millis: u64) -> String {
+    chrono::DateTime::from_timestamp_millis(millis as i64)
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+// `?download=true` forces a save dialog, named after the last path segment of the key
+fn content_disposition(key: &str, download: bool) -> Option<String> {
+    if !download {
+        return None;
+    }
+    let filename = key.rsplit('/').next().unwrap_or(key);
+    let escaped = filename.replace('\\', "\\\\").replace('"', "\\\"");
+    Some(format!("attachment; filename=\"{}\"", escaped))
+}
+
+// Splits an If-Match / If-None-Match value into (weak, opaque-tag) pairs.
+// Tags are quoted and may themselves contain commas, so this walks the quotes
+// rather than splitting on ','. Malformed entries are skipped.
+fn parse_etag_list(value: &str) -> Vec<(bool, &str)> {
+    let mut tags = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        if rest.is_empty() {
+            return tags;
+        }
+        let weak = rest.starts_with("W/");
+        let unprefixed = if weak { &rest[2..] } else { rest };
+        let Some(quoted) = unprefixed.strip_prefix('"') else {
+            // Skip to the next entry
+            rest = rest.split_once(',').map_or("", |(_, tail)| tail);
+            continue;
+        };
+        let Some(end) = quoted.find('"') else {
+            return tags;
+        };
+        tags.push((weak, &quoted[..end]));
+        rest = &quoted[end + 1..];
+    }
+}
+
+// HTTP ETag comparison (RFC 9110 §8.8.3.2). `etag` is the current entity tag
+// as sent in the ETag header. Strong comparison (If-Match) only matches when
+// neither side is weak; weak comparison (If-None-Match) ignores the W/ prefix.
+// `*` matches any current representation.
+fn compare_etag(header_value: &str, etag: &str, strong: bool) -> bool {
+    if header_value.trim() == "*" {
+        return true;
+    }
+    let Some(&(current_weak, current)) = parse_etag_list(etag).first() else {
+        return false;
+    };
+    parse_etag_list(header_value)
+        .into_iter()
+        .any(|(weak, tag)| tag == current && (!strong || (!weak && !current_weak)))
+}
+
+// Common types by key extension, for objects stored without a useful type.
+// HTML, SVG and scripts are left out on purpose: guessing those would let an
+// upload without a declared type run script on this origin.
+fn mime_from_extension(key: &str) -> Option<&'static str> {
+    let (_, ext) = key.rsplit_once('.')?;
+    let mime = match ext.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "xml" => "application/xml",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+// A specific stored type always wins. Missing or generic ones are only
+// guessed from the extension when sniffing is on.
+fn download_content_type(stored: Option<String>, key: &str, sniff: bool) -> String {
+    let generic = stored.as_deref().is_none_or(|t| {
+        let t = t.trim().to_ascii_lowercase();
+        t.is_empty() || t == "application/octet-stream" || t == "binary/octet-stream"
+    });
+    if generic && sniff {
+        if let Some(mime) = mime_from_extension(key) {
+            return mime.to_string();
+        }
+    }
+    stored
+        .filter(|_| !generic)
+        .unwrap_or_else(|| "application/octet-stream".to_string())
+}
+
+async fn handle_file_get(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let key = ctx.param("key").unwrap();
+    let bucket = ctx.bucket("STORAGE")?;
+
+    let url = req.url()?;
+    let download = url
+        .query_pairs()
+        .any(|(name, value)| name == "download" && value == "true");
+
+    let object = within_deadline(&ctx, bucket.get(key).execute()).await?;
+
+    match object {
+        Some(obj) => {
+            let etag = obj.http_etag();
+            if let Some(if_none_match) = req.headers().get("If-None-Match")? {
+                if compare_etag(&if_none_match, &etag, false) {
+                    let mut headers = Headers::new();
+                    headers.set("ETag", &etag)?;
+                    return Ok(Response::empty()?.with_status(304).with_headers(headers));
+                }
+            }
+
+            let body = obj.body().unwrap();
+            let bytes = body.bytes().await?;
+
+            let metadata = obj.http_metadata();
+            let content_type = download_content_type(
+                metadata.content_type,
+                key,
+                ctx.data.config.sniff_content_type,
+            );
+            let cache_control = metadata
+                .cache_control
+                .unwrap_or_else(|| ctx.data.config.file_cache_control.clone());
+
+            let mut headers = Headers::new();
+            headers.set("Content-Type", &content_type)?;
+            headers.set("Cache-Control", &cache_control)?;
+            headers.set("Last-Modified", &http_date(obj.uploaded().as_millis()))?;
+            headers.set("ETag", &etag)?;
+            if let Some(disposition) = content_disposition(key, download) {
+                headers.set("Content-Disposition", &disposition)?;
+            }
+
+            Ok(Response::from_bytes(bytes)?.with_headers(headers))
+        }
+        None => error_response(&ctx, ErrorCode::NotFound, "Not found"),
+    }
+}
+
+// KV index entry describing the object stored under an R2 key
+#[derive(Serialize, Deserialize)]
+struct FileRecord {
+    size: u32,
+    content_type: String,
+    etag: String,
+    uploaded_at: String,
+}
+
+struct TextUpload {
+    content_type: String,
+    charset: Option<String>,
+    bytes: Vec<u8>,
+}
+
+fn charset_param(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_ascii_lowercase())
+    })
+}
+
+// For text/* without a declared charset, detect a BOM (stripping a UTF-8 one)
+// or fall back to the default, and record the charset in the stored content
+// type so downloads advertise it
+fn normalize_text_upload(
+    content_type: &str,
+    mut bytes: Vec<u8>,
+    default_charset: &str,
+) -> TextUpload {
+    let is_text = content_type
+        .trim_start()
+        .get(..5)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("text/"));
+    if !is_text {
+        return TextUpload {
+            content_type: content_type.to_string(),
+            charset: None,
+            bytes,
+        };
+    }
+    if let Some(charset) = charset_param(content_type) {
+        return TextUpload {
+            content_type: content_type.to_string(),
+            charset: Some(charset),
+            bytes,
+        };
+    }
+
+    let charset = if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        bytes.drain(..3);
+        "utf-8".to_string()
+    } else if bytes.starts_with(&[0xFF, 0xFE]) {
+        "utf-16le".to_string()
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        "utf-16be".to_string()
+    } else {
+        default_charset.to_ascii_lowercase()
+    };
+
+    TextUpload {
+        content_type: format!("{}; charset={}", content_type.trim(), charset),
+        charset: Some(charset),
+        bytes,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum UploadLength {
+    Declared(u64),
+    // Chunked, or undeclared while lengths aren't required: the limit is
+    // enforced while reading instead
+    Streamed,
+}
+
+fn check_upload_length(
+    content_length: Option<&str>,
+    chunked: bool,
+    max: u64,
+    require_declared: bool,
+) -> std::result::Result<UploadLength, (ErrorCode, String)> {
+    match content_length {
+        Some(value) => {
+            let Ok(length) = value.trim().parse::<u64>() else {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Invalid Content-Length".to_string(),
+                ));
+            };
+            if length > max {
+                return Err((
+                    ErrorCode::PayloadTooLarge,
+                    format!("Upload exceeds {} bytes", max),
+                ));
+            }
+            Ok(UploadLength::Declared(length))
+        }
+        None if chunked || !require_declared => Ok(UploadLength::Streamed),
+        None => Err((
+            ErrorCode::LengthRequired,
+            "Content-Length is required".to_string(),
+        )),
+    }
+}
+
+// `type/subtype` without parameters, lowercased
+fn media_essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// Patterns are exact types, `type/*`, or `*/*`
+fn content_type_allowed(allowed: &[String], content_type: &str) -> bool {
+    let essence = media_essence(content_type);
+    allowed
+        .iter()
+        .any(|pattern| match pattern.strip_suffix("/*") {
+            Some("*") => true,
+            Some(major) => essence.split_once('/').is_some_and(|(m, _)| m == major),
+            None => *pattern == essence,
+        })
+}
+
+// Recognises a few formats by their magic bytes, executables included, so a
+// renamed binary can't pass as an image
+fn sniff_mime(bytes: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\0asm", "application/wasm"),
+        (b"MZ", "application/x-msdownload"),
+        (b"\x7fELF", "application/x-executable"),
+    ];
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .map(|&(_, mime)| mime)
+}
+
+// The 415 message for a disallowed upload, if any. `bytes` is only checked
+// when sniffing is on; unrecognised bytes fall back to the declared type.
+fn upload_type_error(config: &Config, declared: &str, bytes: Option<&[u8]>) -> Option<String> {
+    let allowed = config.allowed_upload_types.as_deref()?;
+    if !content_type_allowed(allowed, declared) {
+        return Some(format!(
+            "Content type {} is not allowed",
+            media_essence(declared)
+        ));
+    }
+    let sniffed = bytes
+        .filter(|_| config.sniff_upload_types)
+        .and_then(sniff_mime)?;
+    (!content_type_allowed(allowed, sniffed))
+        .then(|| format!("File content looks like {}, which is not allowed", sniffed))
+}
+
+// Reads the body chunk by chunk, giving up as soon as it passes `max`
+async fn read_body_limited(req: &mut Request, max: u64) -> Result<Option<Vec<u8>>> {
+    use futures::StreamExt;
+
+    let mut stream = req.stream()?;
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() as u64 > max {
+            return Ok(None);
+        }
+    }
+    Ok(Some(body))
+}
+
+// How PUT /api/files/:key reads its body, picked from the Content-Type
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UploadEncoding {
+    // The body is the file
+    Raw,
+    // `{ "data_base64": "...", "content_type": "..." }` (BASE64_JSON_UPLOADS)
+    Base64Json,
+    // Forms belong to POST /api/files/batch
+    Multipart,
+}
+
+fn upload_encoding(content_type: &str, base64_json: bool) -> UploadEncoding {
+    match media_essence(content_type).as_str() {
+        "application/json" if base64_json => UploadEncoding::Base64Json,
+        "multipart/form-data" => UploadEncoding::Multipart,
+        _ => UploadEncoding::Raw,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Base64Upload {
+    data_base64: String,
+    // The stored file's type; the envelope's own is always JSON
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+// base64 is 4/3 the size of the data, plus room for the JSON around it
+fn base64_body_limit(max: u64) -> u64 {
+    max.saturating_mul(4) / 3 + 4096
+}
+
+// The file's content type and decoded bytes. MAX_UPLOAD_BYTES applies to the
+// decoded size, the same limit a raw upload of the file would get.
+fn decode_base64_upload(
+    body: &[u8],
+    max: u64,
+) -> std::result::Result<(String, Vec<u8>), (ErrorCode, String)> {
+    use base64::Engine;
+
+    let text = std::str::from_utf8(body).map_err(|_| {
+        (
+            ErrorCode::InvalidJson,
+            "Invalid JSON: body is not UTF-8".to_string(),
+        )
+    })?;
+    let upload: Base64Upload = parse_json_body(text)?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(upload.data_base64.trim())
+        .map_err(|_| {
+            (
+                ErrorCode::ValidationFailed,
+                "data_base64 is not valid base64".to_string(),
+            )
+        })?;
+    if bytes.len() as u64 > max {
+        return Err((
+            ErrorCode::PayloadTooLarge,
+            format!("Upload exceeds {} bytes", max),
+        ));
+    }
+    let content_type = upload
+        .content_type
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    Ok((content_type, bytes))
+}
+
+async fn handle_file_upload(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let key = ctx.param("key").unwrap();
+    let bucket = ctx.bucket("STORAGE")?;
+
+    let declared = req
+        .headers()
+        .get("Content-Type")?
+        .unwrap_or("application/octet-stream".to_string());
+    let encoding = upload_encoding(&declared, ctx.data.config.base64_json_uploads);
+    if encoding == UploadEncoding::Multipart {
+        return error_response(
+            &ctx,
+            ErrorCode::UnsupportedMediaType,
+            "Multipart uploads go to POST /api/files/batch",
+        );
+    }
+
+    let max = ctx.data.config.max_upload_bytes;
+    let body_max = match encoding {
+        UploadEncoding::Base64Json => base64_body_limit(max),
+        _ => max,
+    };
+    let chunked = req
+        .headers()
+        .get("Transfer-Encoding")?
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    if let Err((code, message)) = check_upload_length(
+        req.headers().get("Content-Length")?.as_deref(),
+        chunked,
+        body_max,
+        ctx.data.config.require_content_length,
+    ) {
+        return error_response(&ctx, code, message);
+    }
+
+    // Optimistic concurrency: only overwrite the version the client last saw
+    if let Some(if_match) = req.headers().get("If-Match")? {
+        let current = bucket.head(key).await?.map(|obj| obj.http_etag());
+        if !current.is_some_and(|etag| compare_etag(&if_match, &etag, true)) {
+            return error_response(
+                &ctx,
+                ErrorCode::PreconditionFailed,
+                "File has changed since it was read",
+            );
+        }
+    }
+
+    // A raw upload's declared type is checked before reading the body; a
+    // base64 one only declares its type inside the body
+    if encoding == UploadEncoding::Raw {
+        if let Some(message) = upload_type_error(&ctx.data.config, &declared, None) {
+            return error_response(&ctx, ErrorCode::UnsupportedMediaType, message);
+        }
+    }
+
+    // Declared lengths can be wrong, so the limit is enforced on the bytes too
+    let Some(body) = read_body_limited(&mut req, body_max).await? else {
+        return error_response(
+            &ctx,
+            ErrorCode::PayloadTooLarge,
+            format!("Upload exceeds {} bytes", max),
+        );
+    };
+    let (content_type, body) = match encoding {
+        UploadEncoding::Base64Json => match decode_base64_upload(&body, max) {
+            Ok(upload) => upload,
+            Err((code, message)) => return error_response(&ctx, code, message),
+        },
+        _ => (declared, body),
+    };
+    if let Some(message) = upload_type_error(&ctx.data.config, &content_type, Some(&body)) {
+        return error_response(&ctx, ErrorCode::UnsupportedMediaType, message);
+    }
+    let upload =
+        normalize_text_upload(&content_type, body, &ctx.data.config.upload_default_charset);
+    let content_type = upload.content_type;
+    let bytes = upload.bytes;
+
+    let is_utf8 = matches!(upload.charset.as_deref(), Some("utf-8") | Some("utf8"));
+    if ctx.data.config.validate_text_uploads && is_utf8 && std::str::from_utf8(&bytes).is_err() {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            "Text upload is not valid UTF-8",
+        );
+    }
+
+    // Ordering: R2 is the source of truth and is written first. The index is
+    // only touched once the put has succeeded, so a failed put leaves both the
+    // object and its index entry as they were. If the index write fails after
+    // a successful put, the stale entry is removed in the background: the
+    // index may miss an object, but never describes bytes R2 doesn't hold.
+    let object = match bucket
+        .put(key, bytes)
+        .http_metadata(worker::HttpMetadata {
+            content_type: Some(content_type.clone()),
+            ..Default::default()
+        })
+        .execute()
+        .await
+    {
+        Ok(object) => object,
+        Err(e) => {
+            console_error!("R2 put failed for {}: {}", key, e);
+            return error_response(&ctx, ErrorCode::StorageUnavailable, "Storage write failed");
+        }
+    };
+
+    index_file(&ctx, key, &object, content_type).await?;
+
+    Response::ok("Uploaded")
+}
+
+// Call only after the R2 put has succeeded (see the ordering note above)
+async fn index_file(
+    ctx: &RouteContext<ReqCtx>,
+    key: &str,
+    object: &Object,
+    content_type: String,
+) -> Result<()> {
+    let record = FileRecord {
+        size: object.size(),
+        content_type,
+        etag: object.http_etag(),
+        uploaded_at: http_date(object.uploaded().as_millis()),
+    };
+    let index_key = CacheKey::new(KeySpace::File).part(key).build();
+    let kv = ctx.kv("CACHE")?;
+    let indexed = match kv.put(&index_key, serde_json::to_string(&record)?) {
+        Ok(put) => put.execute().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = indexed {
+        console_warn!("file index write failed for {}: {}", key, Error::from(e));
+        spawn_background(ctx, "file index cleanup", async move {
+            kv.delete(&index_key).await?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+// ============================================
+// BATCH UPLOADS (MULTIPART)
+// ============================================
+
+#[derive(Serialize)]
+struct BatchUploadItem {
+    field: String,
+    key: String,
+    size: usize,
+    etag: String,
+}
+
+// Keys must fit the single-segment `/api/files/:key` route, so only the last
+// path component of a filename is kept. Parts sent without a filename (or
+// with one that's nothing but separators/dots) fall back to the field name.
+fn batch_part_key(field: &str, filename: &str) -> Option<String> {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or("").trim();
+    let key = if base.is_empty() || base.chars().all(|c| c == '.') {
+        field.trim()
+    } else {
+        base
+    };
+    (!key.is_empty() && !key.contains('/')).then(|| key.to_string())
+}
+
+// The form's field names, in order and without repeats. worker::FormData has
+// no iterator, so the names come from the underlying JS FormData.
+fn form_field_names(form: &wasm_bindgen::JsValue) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let Some(entries) = js_sys::try_iter(form)? else {
+        return Ok(names);
+    };
+    for entry in entries {
+        let entry: js_sys::Array = wasm_bindgen::JsCast::unchecked_into(entry?);
+        if let Some(name) = entry.get(0).as_string() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+// Every file part is stored independently; each outcome's index is the part's
+// position in the form. The total size is checked up front so an oversized
+// batch writes nothing.
+async fn handle_file_batch_upload(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let max = ctx.data.config.max_upload_bytes;
+    // form_data() buffers the whole body, so refuse oversized bodies before reading
+    if let Err((code, message)) = check_upload_length(
+        req.headers().get("Content-Length")?.as_deref(),
+        false,
+        max,
+        ctx.data.config.require_content_length,
+    ) {
+        return error_response(&ctx, code, message);
+    }
+
+    let raw = match req.inner().form_data() {
+        Ok(promise) => wasm_bindgen_futures::JsFuture::from(promise).await,
+        Err(e) => Err(e),
+    };
+    let Ok(raw) = raw else {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            "Expected a multipart form body",
+        );
+    };
+    let names = form_field_names(&raw)?;
+    let form = FormData::from(raw);
+
+    let mut parts = Vec::new();
+    for name in names {
+        for entry in form.get_all(&name).unwrap_or_default() {
+            parts.push((name.clone(), entry));
+        }
+    }
+    let total: usize = parts
+        .iter()
+        .map(|(_, entry)| match entry {
+            FormEntry::File(file) => file.size(),
+            FormEntry::Field(_) => 0,
+        })
+        .sum();
+    if total as u64 > max {
+        return error_response(
+            &ctx,
+            ErrorCode::PayloadTooLarge,
+            format!("Batch exceeds {} bytes", max),
+        );
+    }
+
+    let bucket = ctx.bucket("STORAGE")?;
+    let mut seen: Vec<String> = Vec::new();
+    let jobs: Vec<_> = parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, (field, entry))| {
+            let file = match entry {
+                FormEntry::File(file) => file,
+                FormEntry::Field(_) => {
+                    return Err(ItemOutcome::err(
+                        index,
+                        ErrorCode::ValidationFailed,
+                        format!("{}: not a file part", field),
+                    ));
+                }
+            };
+            let key = batch_part_key(&field, &file.name()).filter(|key| !seen.contains(key));
+            let Some(key) = key else {
+                return Err(ItemOutcome::err(
+                    index,
+                    ErrorCode::ValidationFailed,
+                    format!(
+                        "{}: no usable filename, or a duplicate key in this batch",
+                        field
+                    ),
+                ));
+            };
+            seen.push(key.clone());
+            Ok((index, field, key, file))
+        })
+        .collect();
+
+    // Err(index) for parts skipped because the deadline was close
+    let outcomes = map_bounded(jobs, ctx.data.config.batch_concurrency, |job| {
+        let bucket = &bucket;
+        let ctx = &ctx;
+        async move {
+            let (index, field, key, file) = match job {
+                Ok(job) => job,
+                Err(item) => return Ok(item),
+            };
+            if ctx.data.deadline.nearly_expired(Date::now().as_millis()) {
+                return Err(index);
+            }
+            let size = file.size();
+            let content_type = Some(file.type_())
+                .filter(|t| !t.is_empty())
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            let bytes = match file.bytes().await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    console_error!("batch upload part {} unreadable: {}", field, e);
+                    return Ok(ItemOutcome::err(
+                        index,
+                        ErrorCode::ValidationFailed,
+                        format!("{}: part could not be read", field),
+                    ));
+                }
+            };
+            let type_error = upload_type_error(&ctx.data.config, &content_type, Some(&bytes));
+            if let Some(message) = type_error {
+                return Ok(ItemOutcome::err(
+                    index,
+                    ErrorCode::UnsupportedMediaType,
+                    format!("{}: {}", field, message),
+                ));
+            }
+            let stored = async {
+                let object = bucket
+                    .put(&key, bytes)
+                    .http_metadata(worker::HttpMetadata {
+                        content_type: Some(content_type.clone()),
+                        ..Default::default()
+                    })
+                    .execute()
+                    .await?;
+                index_file(ctx, &key, &object, content_type).await?;
+                Ok::<_, Error>(object.http_etag())
+            }
+            .await;
+            Ok(match stored {
+                Ok(etag) => ItemOutcome::ok(
+                    index,
+                    BatchUploadItem {
+                        field,
+                        key,
+                        size,
+                        etag,
+                    },
+                ),
+                Err(e) => {
+                    console_error!("batch upload of {} failed: {}", key, e);
+                    ItemOutcome::err(
+                        index,
+                        ErrorCode::StorageUnavailable,
+                        format!("{}: storage write failed", field),
+                    )
+                }
+            })
+        }
+    })
+    .await;
+    let mut results = Vec::new();
+    let mut unprocessed = Vec::new();
+    for outcome in outcomes {
+        match outcome {
+            Ok(item) => results.push(item),
+            Err(index) => unprocessed.push(index),
+        }
+    }
+
+    batch_response(&ctx, BatchResult::cut_short(results, unprocessed))
+}
+
+// ============================================
+// CONTENT-ADDRESSED BLOBS
+// ============================================
+
+// Dedup wants the hash before choosing a key, but hashing first would mean
+// holding the whole body. Instead the body is streamed once to a temporary
+// key, hashed chunk by chunk on the way through, and only then moved to
+// `sha256-<hex>`. R2 has no rename, so the move is a second streamed put from
+// the temp object (R2 checks it against the digest) followed by a delete; on a
+// dedup hit the temp object is simply deleted. Neither pass buffers the file.
+//
+// Temp objects orphaned by a crash mid-upload are left behind, so add an R2
+// lifecycle rule expiring the `tmp-blob-` prefix after a day.
+const BLOB_TEMP_PREFIX: &str = "tmp-blob-";
+const BLOB_KEY_PREFIX: &str = "sha256-";
+
+// Running digest and byte count over a body as it streams past
+#[derive(Clone, Default)]
+struct UploadDigest {
+    hasher: std::rc::Rc<std::cell::RefCell<sha2::Sha256>>,
+    bytes: std::rc::Rc<std::cell::Cell<u64>>,
+    // Set when the stream was failed for passing `max`
+    overflowed: std::rc::Rc<std::cell::Cell<bool>>,
+}
+
+impl UploadDigest {
+    // Passes chunks through unchanged, failing the stream once it passes `max`
+    fn tap(
+        &self,
+        stream: impl futures::Stream<Item = Result<Vec<u8>>> + 'static,
+        max: u64,
+    ) -> impl futures::Stream<Item = Result<Vec<u8>>> + 'static {
+        use futures::StreamExt;
+        use sha2::Digest;
+
+        let digest = self.clone();
+        stream.map(move |chunk| {
+            let chunk = chunk?;
+            let seen = digest.bytes.get() + chunk.len() as u64;
+            if seen > max {
+                digest.overflowed.set(true);
+                return Err(Error::RustError(format!("upload exceeds {} bytes", max)));
+            }
+            digest.bytes.set(seen);
+            digest.hasher.borrow_mut().update(&chunk);
+            Ok(chunk)
+        })
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes.get()
+    }
+
+    fn overflowed(&self) -> bool {
+        self.overflowed.get()
+    }
+
+    fn finish(self) -> Vec<u8> {
+        use sha2::Digest;
+        self.hasher.take().finalize().to_vec()
+    }
+}
+
+#[derive(Serialize)]
+struct BlobUpload {
+    key: String,
+    sha256: String,
+    size: u64,
+    // True when identical content was already stored
+    deduplicated: bool,
+}
+
+async fn handle_blob_upload(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let bucket = ctx.bucket("STORAGE")?;
+    let max = ctx.data.config.max_upload_bytes;
+
+    // R2 only accepts streams of known length, so Content-Length is required
+    let length = match check_upload_length(
+        req.headers().get("Content-Length")?.as_deref(),
+        false,
+        max,
+        true,
+    ) {
+        Ok(UploadLength::Declared(length)) => length,
+        Ok(UploadLength::Streamed) => unreachable!("length is required"),
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    let content_type = req
+        .headers()
+        .get("Content-Type")?
+        .unwrap_or("application/octet-stream".to_string());
+    // Only the declared type: the body is streamed, never held to sniff
+    if let Some(message) = upload_type_error(&ctx.data.config, &content_type, None) {
+        return error_response(&ctx, ErrorCode::UnsupportedMediaType, message);
+    }
+    let metadata = || worker::HttpMetadata {
+        content_type: Some(content_type.clone()),
+        ..Default::default()
+    };
+
+    let temp_key = format!("{}{}", BLOB_TEMP_PREFIX, uuid::Uuid::new_v4());
+    let digest = UploadDigest::default();
+    let body = FixedLengthStream::wrap(digest.tap(req.stream()?, max), length);
+    if let Err(e) = bucket
+        .put(&temp_key, body)
+        .http_metadata(metadata())
+        .execute()
+        .await
+    {
+        if digest.overflowed() {
+            return error_response(
+                &ctx,
+                ErrorCode::PayloadTooLarge,
+                format!("Upload exceeds {} bytes", max),
+            );
+        }
+        if digest.bytes() != length {
+            return error_response(
+                &ctx,
+                ErrorCode::ValidationFailed,
+                "Body length does not match Content-Length",
+            );
+        }
+        console_error!("R2 put failed for {}: {}", temp_key, e);
+        return error_response(&ctx, ErrorCode::StorageUnavailable, "Storage write failed");
+    }
+
+    let sha256 = digest.finish();
+    let sha256_hex = hex(&sha256);
+    let key = format!("{}{}", BLOB_KEY_PREFIX, sha256_hex);
+
+    let deduplicated = bucket.head(&key).await?.is_some();
+    if !deduplicated {
+        let copied = async {
+            let temp = bucket
+                .get(&temp_key)
+                .execute()
+                .await?
+                .ok_or_else(|| Error::RustError("temp object disappeared".into()))?;
+            let size = temp.size() as u64;
+            let stream = temp
+                .body()
+                .ok_or_else(|| Error::RustError("temp object has no body".into()))?
+                .stream()?;
+            bucket
+                .put(&key, FixedLengthStream::wrap(stream, size))
+                .http_metadata(metadata())
+                .sha256(sha256)
+                .execute()
+                .await
+        }
+        .await;
+        if let Err(e) = copied {
+            console_error!("R2 copy {} -> {} failed: {}", temp_key, key, e);
+            spawn_background(&ctx, "blob temp cleanup", async move {
+                bucket.delete(temp_key).await
+            });
+            return error_response(&ctx, ErrorCode::StorageUnavailable, "Storage write failed");
+        }
+    }
+    spawn_background(&ctx, "blob temp cleanup", async move {
+        bucket.delete(temp_key).await
+    });
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(BlobUpload {
+                key,
+                sha256: sha256_hex,
+                size: length,
+                deduplicated,
+            }),
+            error: None,
+            code: None,
+        },
+    )
+    .map(|r| r.with_status(if deduplicated { 200 } else { 201 }))
+}
+
+// ============================================
+// ADMIN AUTH
+// ============================================
+
+// Compares every byte so response timing doesn't leak how much of a key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn bearer_matches(authorization: Option<&str>, secret: &str) -> bool {
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|key| constant_time_eq(key.as_bytes(), secret.as_bytes()))
+}
+
+// Admin routes expect `Authorization: Bearer <ADMIN_KEY>`. Without the
+// secret configured they are closed to everyone.
+fn is_admin(req: &Request, ctx: &RouteContext<ReqCtx>) -> Result<bool> {
+    let Ok(admin_key) = ctx.secret("ADMIN_KEY") else {
+        return Ok(false);
+    };
+    Ok(bearer_matches(
+        req.headers().get("Authorization")?.as_deref(),
+        &admin_key.to_string(),
+    ))
+}
+
+// ============================================
+// API KEYS
+// ============================================
+
+// Keys are stored in KV under the SHA-256 of the key, so a KV read never
+// exposes a usable key. The plaintext is only returned once, at creation.
+#[derive(Serialize, Deserialize)]
+struct ApiKeyRecord {
+    name: String,
+    // Compute operations this key may run; None means all of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_ops: Option<Vec<String>>,
+    // The tenant whose users this key sees under TENANT_ISOLATION
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant_id: Option<String>,
+}
+
+impl ApiKeyRecord {
+    fn allows(&self, operation: &str) -> bool {
+        self.allowed_ops
+            .as_ref()
+            .is_none_or(|ops| ops.iter().any(|op| op == operation))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateApiKeyRequest {
+    name: String,
+    #[serde(default)]
+    allowed_ops: Option<Vec<String>>,
+    #[serde(default)]
+    tenant_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CreatedApiKey {
+    key: String,
+    #[serde(flatten)]
+    record: ApiKeyRecord,
+}
+
+enum ApiCaller {
+    Anonymous,
+    Key(ApiKeyRecord),
+    Invalid,
+}
+
+fn api_key_storage_key(key: &str) -> String {
+    CacheKey::new(KeySpace::ApiKey)
+        .part(sha256_hex(key))
+        .build()
+}
+
+async fn lookup_api_key(req: &Request, ctx: &RouteContext<ReqCtx>) -> Result<ApiCaller> {
+    let Some(key) = req.headers().get("X-API-Key")? else {
+        return Ok(ApiCaller::Anonymous);
+    };
+    let record = ctx
+        .kv("CACHE")?
+        .get(&api_key_storage_key(&key))
+        .json::<ApiKeyRecord>()
+        .await?;
+    Ok(record.map_or(ApiCaller::Invalid, ApiCaller::Key))
+}
+
+// Some(response) when the caller may not run every one of `operations`
+async fn authorize_compute(
+    req: &Request,
+    ctx: &RouteContext<ReqCtx>,
+    operations: &[&str],
+) -> Result<Option<Response>> {
+    let record = match lookup_api_key(req, ctx).await? {
+        ApiCaller::Key(record) => record,
+        ApiCaller::Anonymous if !ctx.data.config.compute_require_api_key => return Ok(None),
+        ApiCaller::Anonymous | ApiCaller::Invalid => {
+            return error_response(ctx, ErrorCode::Unauthorized, "Valid X-API-Key required")
+                .map(Some);
+        }
+    };
+    match operations.iter().find(|op| !record.allows(op)) {
+        Some(op) => error_response(
+            ctx,
+            ErrorCode::OperationNotAllowed,
+            format!("Operation not allowed for this key: {}", op),
+        )
+        .map(Some),
+        None => Ok(None),
+    }
+}
+
+async fn handle_create_api_key(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+    let input: CreateApiKeyRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    if input.name.trim().is_empty() {
+        return error_response(&ctx, ErrorCode::ValidationFailed, "Name is required");
+    }
+    if let Some(op) = input
+        .allowed_ops
+        .iter()
+        .flatten()
+        .find(|op| !COMPUTE_OPERATIONS.contains(&op.as_str()))
+    {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            format!("Unknown operation: {}", op),
+        );
+    }
+
+    let key = format!("sk_{}", uuid::Uuid::new_v4().simple());
+    let record = ApiKeyRecord {
+        name: input.name.trim().to_string(),
+        allowed_ops: input.allowed_ops,
+        tenant_id: input
+            .tenant_id
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty()),
+    };
+    ctx.kv("CACHE")?
+        .put(&api_key_storage_key(&key), serde_json::to_string(&record)?)?
+        .execute()
+        .await?;
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(CreatedApiKey { key, record }),
+            error: None,
+            code: None,
+        },
+    )
+    .map(|r| r.with_status(201))
+}
+
+// ============================================
+// SIGNING
+// ============================================
+
+// HMAC-SHA256 with a rotation window. Everything is signed with
+// SIGNING_SECRET and verified against it and SIGNING_SECRET_PREVIOUS, so
+// signatures issued before a rotation stay valid until the previous secret
+// is removed. Rotate by moving the current secret to _PREVIOUS, setting a new
+// current one, and deleting _PREVIOUS once old signatures have expired.
+struct SigningKeys {
+    current: Vec<u8>,
+    previous: Option<Vec<u8>>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], payload: &str) -> String {
+    use hmac::{Hmac, Mac};
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+impl SigningKeys {
+    fn new(current: &str, previous: Option<&str>) -> Self {
+        Self {
+            current: current.as_bytes().to_vec(),
+            previous: previous
+                .filter(|p| !p.is_empty())
+                .map(|p| p.as_bytes().to_vec()),
+        }
+    }
+
+    // None when SIGNING_SECRET isn't set
+    fn from_env(env: &Env) -> Option<Self> {
+        let current = env.secret("SIGNING_SECRET").ok()?.to_string();
+        if current.is_empty() {
+            return None;
+        }
+        let previous = env
+            .secret("SIGNING_SECRET_PREVIOUS")
+            .ok()
+            .map(|s| s.to_string());
+        Some(Self::new(&current, previous.as_deref()))
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        hmac_sha256(&self.current, payload)
+    }
+
+    fn verify(&self, payload: &str, signature: &str) -> bool {
+        std::iter::once(&self.current)
+            .chain(self.previous.as_ref())
+            .any(|key| constant_time_eq(hmac_sha256(key, payload).as_bytes(), signature.as_bytes()))
+    }
+}
+
+// ============================================
+// BACKGROUND TASKS
+// ============================================
+
+// Work handed to wait_until is cut off without notice once the runtime's
+// post-response window closes. Every background task goes through
+// `spawn_background`, which gives it its own shorter budget so it either
+// finishes, fails with its error logged, or is dropped with a clear timeout
+// log naming the task.
+#[derive(Debug, PartialEq)]
+enum BackgroundOutcome {
+    Done,
+    Failed(String),
+    TimedOut,
+}
+
+async fn run_with_budget(
+    task: impl std::future::Future<Output = Result<()>>,
+    timeout: impl std::future::Future<Output = ()>,
+) -> BackgroundOutcome {
+    let task = std::pin::pin!(task);
+    let timeout = std::pin::pin!(timeout);
+    match futures::future::select(task, timeout).await {
+        futures::future::Either::Left((Ok(()), _)) => BackgroundOutcome::Done,
+        futures::future::Either::Left((Err(e), _)) => BackgroundOutcome::Failed(e.to_string()),
+        futures::future::Either::Right(_) => BackgroundOutcome::TimedOut,
+    }
+}
+
+fn background_log_message(
+    label: &str,
+    budget_ms: u64,
+    outcome: &BackgroundOutcome,
+) -> Option<String> {
+    match outcome {
+        BackgroundOutcome::Done => None,
+        BackgroundOutcome::Failed(e) => Some(format!("{} failed: {}", label, e)),
+        BackgroundOutcome::TimedOut => Some(format!(
+            "{} timed out after {}ms and was abandoned",
+            label, budget_ms
+        )),
+    }
+}
+
+fn spawn_background(
+    ctx: &RouteContext<ReqCtx>,
+    label: &'static str,
+    task: impl std::future::Future<Output = Result<()>> + 'static,
+) {
+    let budget_ms = ctx.data.config.background_task_budget_ms;
+    ctx.data.worker_ctx.wait_until(async move {
+        let timeout = Delay::from(std::time::Duration::from_millis(budget_ms));
+        let outcome = run_with_budget(task, timeout).await;
+        if let Some(message) = background_log_message(label, budget_ms, &outcome) {
+            console_error!("{}", message);
+        }
+    });
+}
+
+// ============================================
+// REQUEST DEADLINE
+// ============================================
+
+// One budget for the whole request, fixed when it arrives. Each D1/KV/R2/fetch
+// call goes through `within_deadline`, which gives it only the time that's
+// left, so several slow calls together still finish by the budget; `fetch`
+// also races the router against it. Running out is a 504.
+//
+// Times are u64 milliseconds from Date::now. The remaining time is
+// `at_ms.saturating_sub(now)`: once the clock passes the deadline it's 0, not
+// a negative or wrapped-around duration, and a 0 budget fails the operation
+// without starting it.
+const DEADLINE_EXCEEDED: &str = "request deadline exceeded";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Deadline {
+    at_ms: u64,
+}
+
+impl Deadline {
+    fn after(now_ms: u64, budget_ms: u64) -> Self {
+        Deadline {
+            at_ms: now_ms.saturating_add(budget_ms),
+        }
+    }
+
+    fn remaining_ms(&self, now_ms: u64) -> u64 {
+        self.at_ms.saturating_sub(now_ms)
+    }
+
+    // For batches: too little left to start another item
+    fn nearly_expired(&self, now_ms: u64) -> bool {
+        self.remaining_ms(now_ms) <= BATCH_DEADLINE_RESERVE_MS
+    }
+}
+
+// Batch handlers check the deadline between items and stop starting new ones
+// with this much left, so what's done can still be sent. A batch cut short
+// answers 207 with `timed_out` and `unprocessed` set on its BatchResult,
+// rather than losing the finished items to a 504.
+const BATCH_DEADLINE_RESERVE_MS: u64 = 250;
+
+fn is_deadline_error(e: &Error) -> bool {
+    e.to_string().contains(DEADLINE_EXCEEDED)
+}
+
+// Runs `op` for at most `remaining_ms`
+async fn race_deadline<T, S, SFut>(
+    op: impl std::future::Future<Output = Result<T>>,
+    remaining_ms: u64,
+    sleep: S,
+) -> Result<T>
+where
+    S: FnOnce(u64) -> SFut,
+    SFut: std::future::Future<Output = ()>,
+{
+    if remaining_ms == 0 {
+        return Err(Error::RustError(DEADLINE_EXCEEDED.to_string()));
+    }
+    let op = std::pin::pin!(op);
+    let timeout = std::pin::pin!(sleep(remaining_ms));
+    match futures::future::select(op, timeout).await {
+        futures::future::Either::Left((outcome, _)) => outcome,
+        futures::future::Either::Right(_) => Err(Error::RustError(DEADLINE_EXCEEDED.to_string())),
+    }
+}
+
+// The timeout helper for handlers: an error here propagates with `?` and
+// `fetch` turns it into a 504
+async fn within_deadline<T>(
+    ctx: &RouteContext<ReqCtx>,
+    op: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let remaining = ctx.data.deadline.remaining_ms(Date::now().as_millis());
+    race_deadline(op, remaining, |ms| {
+        Delay::from(std::time::Duration::from_millis(ms))
+    })
+    .await
+}
+
+// ============================================
+// BOUNDED CONCURRENCY
+// ============================================
+
+// Runs `f` over `items` with at most `limit` futures in flight, returning
+// results in input order. A Worker can only have 6 subrequests open at once;
+// anything wider just queues inside the runtime while still holding memory.
+async fn map_bounded<T, R, F, Fut>(items: impl IntoIterator<Item = T>, limit: usize, f: F) -> Vec<R>
+where
+    F: FnMut(T) -> Fut,
+    Fut: std::future::Future<Output = R>,
+{
+    use futures::StreamExt;
+
+    futures::stream::iter(items)
+        .map(f)
+        .buffered(limit.max(1))
+        .collect()
+        .await
+}
+
+// ============================================
+// WEBHOOKS
+// ============================================
+
+// Deliveries are at-least-once, so every payload carries a stable `event_id`
+// consumers can dedupe on. The id is persisted in KV per logical event (event
+// type, resource and the request that raised it), so a retry in this
+// invocation, or a client retrying the request with the same X-Request-Id,
+// reuses it with a higher `delivery_attempt`.
+//
+// The request that raised the event is named in the payload (`request_id`,
+// plus `trace_id` under a traceparent), in an X-Request-Id header, in every
+// delivery log line and in the dead letter, so a receiver's logs and ours
+// join up with the original access log line.
+//
+// Each attempt gets WEBHOOK_TIMEOUT_MS. An event that fails every attempt is
+// written to the `dlq:` keyspace as a DeadLetter, payload included, and kept
+// for a week; GET /admin/dlq lists them. Nothing replays them automatically.
+const WEBHOOK_MAX_ATTEMPTS: u32 = 3;
+const WEBHOOK_RECORD_TTL: u64 = 86400;
+const WEBHOOK_DLQ_TTL: u64 = 7 * 86400;
+const DLQ_PAGE_MAX: u32 = 50;
+
+fn webhook_backoff_ms(attempt: u32) -> u64 {
+    500 * 2u64.pow(attempt - 1)
+}
+
+// Every attempt timing out, plus the backoff between them
+fn webhook_worst_case_ms(timeout_ms: u64) -> u64 {
+    let backoff: u64 = (1..WEBHOOK_MAX_ATTEMPTS).map(webhook_backoff_ms).sum();
+    timeout_ms * WEBHOOK_MAX_ATTEMPTS as u64 + backoff
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct DeadLetter {
+    event_id: String,
+    event_type: String,
+    occurred_at: String,
+    failed_at: String,
+    attempts: u32,
+    last_error: String,
+    data: serde_json::Value,
+    // Absent from letters written before correlation ids were recorded
+    #[serde(flatten)]
+    correlation: Correlation,
+}
+
+impl DeadLetter {
+    // Ordered by failure time, so listings come back oldest first
+    fn key(&self) -> String {
+        CacheKey::new(KeySpace::DeadLetter)
+            .part(self.failed_at.as_str())
+            .part(self.event_id.as_str())
+            .build()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct DeliveryRecord {
+    event_id: String,
+    attempts: u32,
+}
+
+#[derive(Serialize)]
+struct WebhookEvent {
+    event_id: String,
+    delivery_attempt: u32,
+    event_type: String,
+    occurred_at: String,
+    data: serde_json::Value,
+    #[serde(flatten)]
+    correlation: Correlation,
+}
+
+fn webhook_record_key(event_type: &str, resource_id: &str, request_id: &str) -> String {
+    CacheKey::new(KeySpace::Webhook)
+        .part(event_type)
+        .part(resource_id)
+        .part(request_id)
+        .build()
+}
+
+fn next_delivery(previous: Option<DeliveryRecord>) -> DeliveryRecord {
+    match previous {
+        Some(record) => DeliveryRecord {
+            event_id: record.event_id,
+            attempts: record.attempts + 1,
+        },
+        None => DeliveryRecord {
+            event_id: uuid::Uuid::new_v4().to_string(),
+            attempts: 1,
+        },
+    }
+}
+
+fn dispatch_webhook(
+    ctx: &RouteContext<ReqCtx>,
+    event_type: &str,
+    resource_id: &str,
+    data: serde_json::Value,
+) {
+    let Some(url) = ctx.data.config.webhook_url.clone() else {
+        return;
+    };
+    let kv = match ctx.kv("CACHE") {
+        Ok(kv) => kv,
+        Err(e) => {
+            console_error!("webhook {} skipped: {}", event_type, e);
+            return;
+        }
+    };
+
+    let occurred_at = chrono::Utc::now().to_rfc3339();
+    let correlation = ctx.data.correlation.clone();
+    let record_key = webhook_record_key(event_type, resource_id, &correlation.request_id);
+    let event_type = event_type.to_string();
+    let target = WebhookTarget {
+        url,
+        keys: SigningKeys::from_env(&ctx.env),
+        timeout_ms: ctx.data.config.webhook_timeout_ms,
+    };
+
+    spawn_background(ctx, "webhook delivery", async move {
+        deliver_webhook(
+            &kv,
+            &target,
+            &record_key,
+            event_type,
+            occurred_at,
+            data,
+            correlation,
+        )
+        .await
+    });
+}
+
+struct WebhookTarget {
+    url: String,
+    keys: Option<SigningKeys>,
+    timeout_ms: u64,
+}
+
+async fn deliver_webhook(
+    kv: &kv::KvStore,
+    target: &WebhookTarget,
+    record_key: &str,
+    event_type: String,
+    occurred_at: String,
+    data: serde_json::Value,
+    correlation: Correlation,
+) -> Result<()> {
+    // Read once; each attempt is then persisted before it goes out
+    let mut record = kv.get(record_key).json::<DeliveryRecord>().await?;
+    let mut last_error = String::new();
+    for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+        let next = next_delivery(record.take());
+        kv.put(record_key, serde_json::to_string(&next)?)?
+            .expiration_ttl(WEBHOOK_RECORD_TTL)
+            .execute()
+            .await?;
+
+        let event = WebhookEvent {
+            event_id: next.event_id.clone(),
+            delivery_attempt: next.attempts,
+            event_type: event_type.clone(),
+            occurred_at: occurred_at.clone(),
+            data: data.clone(),
+            correlation: correlation.clone(),
+        };
+
+        let body = serde_json::to_string(&event)?;
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        headers.set(REQUEST_ID_HEADER, &correlation.request_id)?;
+        // Receivers verify by recomputing the HMAC of the raw body
+        if let Some(keys) = &target.keys {
+            headers.set(
+                "X-Webhook-Signature",
+                &format!("sha256={}", keys.sign(&body)),
+            )?;
+        }
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(body.into()));
+
+        let fetch = Fetch::Request(Request::new_with_init(&target.url, &init)?);
+        let send = fetch.send();
+        let timeout = Delay::from(std::time::Duration::from_millis(target.timeout_ms));
+        last_error = match futures::future::select(std::pin::pin!(send), timeout).await {
+            futures::future::Either::Left((Ok(response), _))
+                if (200..300).contains(&response.status_code()) =>
+            {
+                return Ok(());
+            }
+            futures::future::Either::Left((Ok(response), _)) => {
+                format!("got {}", response.status_code())
+            }
+            futures::future::Either::Left((Err(e), _)) => format!("failed: {}", e),
+            futures::future::Either::Right(_) => {
+                format!("timed out after {}ms", target.timeout_ms)
+            }
+        };
+        console_warn!(
+            "webhook {} attempt {} {} {}",
+            event.event_id,
+            attempt,
+            last_error,
+            correlation.log_fields()
+        );
+
+        record = Some(next);
+        if attempt < WEBHOOK_MAX_ATTEMPTS {
+            Delay::from(std::time::Duration::from_millis(webhook_backoff_ms(
+                attempt,
+            )))
+            .await;
+        }
+    }
+
+    let letter = DeadLetter {
+        event_id: record.map(|r| r.event_id).unwrap_or_default(),
+        event_type,
+        occurred_at,
+        failed_at: chrono::Utc::now().to_rfc3339(),
+        attempts: WEBHOOK_MAX_ATTEMPTS,
+        last_error,
+        data,
+        correlation,
+    };
+    let key = letter.key();
+    kv.put(&key, serde_json::to_string(&letter)?)?
+        .expiration_ttl(WEBHOOK_DLQ_TTL)
+        .execute()
+        .await?;
+    Err(Error::RustError(format!(
+        "gave up after {} attempts; dead-lettered as {}",
+        WEBHOOK_MAX_ATTEMPTS, key
+    )))
+}
+
+#[derive(Serialize)]
+struct DeadLetterPage {
+    entries: Vec<DeadLetter>,
+    next_cursor: Option<String>,
+}
+
+// Oldest first, `limit` (at most DLQ_PAGE_MAX) per page
+async fn handle_dead_letters(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let url = req.url()?;
+    let limit = match positive_param(&url, "limit", DLQ_PAGE_MAX) {
+        Ok(limit) => limit.min(DLQ_PAGE_MAX),
+        Err(message) => return error_response(&ctx, ErrorCode::ValidationFailed, message),
+    };
+    let prefix = CacheKey::new(KeySpace::DeadLetter).build();
+    let keys = SigningKeys::from_env(&ctx.env);
+    let cursor = match url.query_pairs().find(|(key, _)| key == "cursor") {
+        None => None,
+        Some((_, token)) => match open_cursor(keys.as_ref(), &prefix, &token) {
+            Some(cursor) => Some(cursor),
+            None => return error_response(&ctx, ErrorCode::ValidationFailed, "Invalid cursor"),
+        },
+    };
+
+    let kv = ctx.kv("CACHE")?;
+    let mut list = kv.list().prefix(prefix.clone()).limit(limit as u64);
+    if let Some(cursor) = cursor {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+    let letters = map_bounded(&page.keys, ctx.data.config.batch_concurrency, |key| {
+        kv.get(&key.name).json::<DeadLetter>()
+    })
+    .await;
+    let mut entries = Vec::with_capacity(letters.len());
+    for letter in letters {
+        // Keys that expired between the listing and the read are skipped
+        entries.extend(letter?);
+    }
+    let next_cursor = page
+        .cursor
+        .filter(|_| !page.list_complete)
+        .map(|c| seal_cursor(keys.as_ref(), &prefix, &c));
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(DeadLetterPage {
+                entries,
+                next_cursor,
+            }),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// USER EVENTS (DURABLE OBJECT)
+// ============================================
+
+// One UserEvents object per user id (`id_from_name`), so each object only ever
+// sees its own user's events and the filtering happens by routing. Open SSE
+// streams are channels held in memory; an open stream keeps the object alive.
+#[derive(Serialize, Deserialize)]
+struct UserEvent {
+    event: String,
+    data: serde_json::Value,
+}
+
+// One SSE message; multi-line data becomes one `data:` line per line
+fn sse_frame(event: &str, data: &str) -> String {
+    let mut frame = format!("event: {}\n", event);
+    for line in data.lines() {
+        frame.push_str("data: ");
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push('\n');
+    frame
+}
+
+#[durable_object]
+pub struct UserEvents {
+    subscribers: Vec<futures::channel::mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+#[durable_object]
+impl DurableObject for UserEvents {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            subscribers: Vec::new(),
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        use futures::StreamExt;
+
+        match (req.method(), req.path().as_str()) {
+            (Method::Get, "/subscribe") => {
+                let (tx, rx) = futures::channel::mpsc::unbounded();
+                // A comment line, so clients see the stream open before the first event
+                let _ = tx.unbounded_send(b": connected\n\n".to_vec());
+                self.subscribers.push(tx);
+
+                let mut headers = Headers::new();
+                headers.set("Content-Type", "text/event-stream")?;
+                headers.set("Cache-Control", "no-cache")?;
+                Ok(Response::from_stream(rx.map(Ok::<Vec<u8>, Error>))?.with_headers(headers))
+            }
+            (Method::Post, "/publish") => {
+                let event: UserEvent = req.json().await?;
+                let frame = sse_frame(&event.event, &event.data.to_string()).into_bytes();
+                // Senders whose client disconnected fail here and are dropped
+                self.subscribers
+                    .retain(|tx| tx.unbounded_send(frame.clone()).is_ok());
+                // Deletion is final: dropping the senders ends every stream cleanly
+                if event.event == "deleted" {
+                    self.subscribers.clear();
+                }
+                Ok(Response::empty()?.with_status(204))
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+fn user_events_stub(ctx: &RouteContext<ReqCtx>, id: &str) -> Result<Stub> {
+    ctx.durable_object("USER_EVENTS")?
+        .id_from_name(id)?
+        .get_stub()
+}
+
+async fn handle_user_events(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let id = ctx.param("id").unwrap();
+    let db = d1_session(&req, &ctx, false)?;
+    let scope = match tenant_scope(&req, &ctx).await? {
+        Ok(scope) => scope,
+        Err(denied) => return Ok(denied),
+    };
+
+    // 404 up front rather than an open stream that never emits
+    let exists = db
+        .prepare(format!(
+            "SELECT id FROM users WHERE id = ? AND deleted_at IS NULL{}",
+            scope.clause()
+        ))
+        .bind(&scope.bind(vec![id.into()], Vec::new()))?
+        .first::<serde_json::Value>(None)
+        .await?
+        .is_some();
+    if !exists {
+        return error_response(&ctx, ErrorCode::UserNotFound, "User not found");
+    }
+
+    user_events_stub(&ctx, id)?
+        .fetch_with_str("https://user-events/subscribe")
+        .await
+}
+
+// Fire-and-forget, like webhooks: a publish failure never fails the write
+fn publish_user_event(ctx: &RouteContext<ReqCtx>, id: &str, event: &str, data: serde_json::Value) {
+    let stub = match user_events_stub(ctx, id) {
+        Ok(stub) => stub,
+        Err(e) => {
+            console_warn!("user event {} for {} skipped: {}", event, id, e);
+            return;
+        }
+    };
+    let event = UserEvent {
+        event: event.to_string(),
+        data,
+    };
+
+    spawn_background(ctx, "user event publish", async move {
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(&event)?.into()));
+        let req = Request::new_with_init("https://user-events/publish", &init)?;
+        stub.fetch_with_request(req).await?;
+        Ok(())
+    });
+}
+
+// ============================================
+// USER COUNTER (DURABLE OBJECT)
+// ============================================
+
+// A single UserCounter object (`id_from_name("users")`) holds the number of
+// non-deleted users. It handles one request at a time, so the read-modify-write
+// in /add needs no further locking.
+//
+// Every write that changes the active set adjusts it after the D1 write
+// commits, in the background. The count can still drift: an adjustment can be
+// lost (object unreachable, background budget exceeded), rows can be changed
+// outside this Worker, and a write that lands between a COUNT(*) and the /set
+// that stores it is missed. POST /api/users/count/reconcile and the daily
+// cron detect this by comparing the stored count with COUNT(*) on the
+// primary, report the difference as `drift`, and overwrite the stored value.
+//
+// Until the first COUNT(*) is stored the object is uninitialized: /add is a
+// no-op and the first list request bootstraps it from D1.
+const USER_COUNTER_KEY: &str = "count";
+
+#[derive(Serialize, Deserialize)]
+struct CounterState {
+    count: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CounterDelta {
+    delta: i64,
+}
+
+// Removes past zero are clamped: the next reconcile corrects the real value
+fn apply_count_delta(current: u64, delta: i64) -> u64 {
+    current.saturating_add_signed(delta)
+}
+
+// Positive when the stored count is too high; None when nothing was stored
+fn count_drift(stored: Option<u64>, actual: u64) -> Option<i64> {
+    stored.map(|stored| stored as i64 - actual as i64)
+}
+
+// Storage::get reports an unset key as an error rather than None
+fn is_missing_storage_value(message: &str) -> bool {
+    message.contains("No such value in storage")
+}
+
+// `#[durable_object]` defines a helper item per module, so a second object
+// needs a module of its own
+mod user_counter {
+    use super::*;
+
+    #[durable_object]
+    pub struct UserCounter {
+        state: State,
+    }
+
+    #[durable_object]
+    impl DurableObject for UserCounter {
+        fn new(state: State, _env: Env) -> Self {
+            Self { state }
+        }
+
+        async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+            let mut storage = self.state.storage();
+            // Unset means uninitialized, so the next list request re-bootstraps
+            // from D1; any other read failure is an error, not a reason to drop
+            // the delta
+            let current = match storage.get::<u64>(USER_COUNTER_KEY).await {
+                Ok(count) => Some(count),
+                Err(e) if is_missing_storage_value(&e.to_string()) => None,
+                Err(e) => return Err(e),
+            };
+
+            match (req.method(), req.path().as_str()) {
+                (Method::Get, "/count") => Response::from_json(&CounterState { count: current }),
+                (Method::Post, "/add") => {
+                    let CounterDelta { delta } = req.json().await?;
+                    let Some(current) = current else {
+                        return Response::from_json(&CounterState { count: None });
+                    };
+                    let count = apply_count_delta(current, delta);
+                    storage.put(USER_COUNTER_KEY, count).await?;
+                    Response::from_json(&CounterState { count: Some(count) })
+                }
+                // Returns the previous value so callers can report drift
+                (Method::Put, "/count") => {
+                    let CounterState { count } = req.json().await?;
+                    let Some(count) = count else {
+                        return Response::error("count is required", 400);
+                    };
+                    storage.put(USER_COUNTER_KEY, count).await?;
+                    Response::from_json(&CounterState { count: current })
+                }
+                _ => Response::error("Not found", 404),
+            }
+        }
+    }
+}
+pub use user_counter::UserCounter;
+
+fn user_counter_stub(env: &Env) -> Result<Stub> {
+    env.durable_object("USER_COUNTER")?
+        .id_from_name("users")?
+        .get_stub()
+}
+
+async fn counter_request(
+    env: &Env,
+    method: Method,
+    path: &str,
+    body: Option<String>,
+) -> Result<Option<u64>> {
+    let mut init = RequestInit::new();
+    init.with_method(method).with_body(body.map(Into::into));
+    let req = Request::new_with_init(&format!("https://user-counter{}", path), &init)?;
+    let state: CounterState = user_counter_stub(env)?
+        .fetch_with_request(req)
+        .await?
+        .json()
+        .await?;
+    Ok(state.count)
+}
+
+// Stores `count` and returns what was there before
+async fn store_user_count(env: &Env, count: u64) -> Result<Option<u64>> {
+    let body = serde_json::to_string(&CounterState { count: Some(count) })?;
+    counter_request(env, Method::Put, "/count", Some(body)).await
+}
+
+// The stored total, bootstrapping it from D1 on first use. If the object
+// can't be reached the list still works, from COUNT(*).
+async fn user_count(env: &Env, db: &D1Database) -> Result<u64> {
+    match counter_request(env, Method::Get, "/count", None).await {
+        Ok(Some(count)) => Ok(count),
+        Ok(None) => {
+            let count = count_active_users(db, &TenantScope::All).await?;
+            if let Err(e) = store_user_count(env, count).await {
+                console_warn!("user counter bootstrap failed: {}", e);
+            }
+            Ok(count)
+        }
+        Err(e) => {
+            console_warn!("user counter unavailable, using COUNT(*): {}", e);
+            count_active_users(db, &TenantScope::All).await
+        }
+    }
+}
+
+// Fire-and-forget after the D1 write has committed; a lost adjustment is
+// drift for the next reconcile to correct
+fn adjust_user_count(ctx: &RouteContext<ReqCtx>, delta: i64) {
+    if !ctx.data.config.user_counter {
+        return;
+    }
+    let env = ctx.env.clone();
+    spawn_background(ctx, "user count adjust", async move {
+        let body = serde_json::to_string(&CounterDelta { delta })?;
+        counter_request(&env, Method::Post, "/add", Some(body)).await?;
+        Ok(())
+    });
+}
+
+#[derive(Serialize)]
+struct CountReconcile {
+    previous: Option<u64>,
+    actual: u64,
+    drift: Option<i64>,
+}
+
+// Counts on the primary (not a replica session) so the stored value isn't
+// replaced with a stale one
+async fn reconcile_user_count(env: &Env) -> Result<CountReconcile> {
+    let actual = count_active_users(&env.d1("DB")?, &TenantScope::All).await?;
+    let previous = store_user_count(env, actual).await?;
+    Ok(CountReconcile {
+        previous,
+        actual,
+        drift: count_drift(previous, actual),
+    })
+}
+
+async fn handle_reconcile_user_count(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+    if !ctx.data.config.user_counter {
+        return error_response(&ctx, ErrorCode::NotFound, "User counter is not enabled");
+    }
+
+    let report = reconcile_user_count(&ctx.env).await?;
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(report),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// SCHEMA STATUS
+// ============================================
+
+// Every migration this code expects, oldest first. Keep in step with the
+// D1 SCHEMA blocks at the top of the file when adding one.
+const SCHEMA_MIGRATIONS: &[&str] = &[
+    "0001_create_users.sql",
+    "0002_user_retention.sql",
+    "0003_users_list_order.sql",
+    "0004_email_verification.sql",
+    "0005_email_normalized.sql",
+    "0006_user_tenant.sql",
+    "0007_email_hmac.sql",
+    "0008_tenant_email_unique.sql",
+];
+
+// Where `wrangler d1 migrations apply` records what it ran (its default
+// `migrations_table`)
+const MIGRATIONS_TABLE: &str = "d1_migrations";
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SchemaStatus {
+    // Latest migration the code knows about
+    expected: String,
+    // Latest migration applied to the database; None before any have run
+    applied: Option<String>,
+    // Known to the code but not applied (code ahead of the DB)
+    pending: Vec<String>,
+    // Applied but unknown to the code (DB ahead, e.g. an older deploy)
+    unknown: Vec<String>,
+    drift: bool,
+}
+
+fn schema_status(known: &[&str], applied: &[String]) -> SchemaStatus {
+    let pending: Vec<String> = known
+        .iter()
+        .filter(|name| !applied.iter().any(|a| a == *name))
+        .map(|name| name.to_string())
+        .collect();
+    let unknown: Vec<String> = applied
+        .iter()
+        .filter(|name| !known.contains(&name.as_str()))
+        .cloned()
+        .collect();
+    SchemaStatus {
+        expected: known
+            .last()
+            .map(|name| name.to_string())
+            .unwrap_or_default(),
+        applied: applied.last().cloned(),
+        drift: !pending.is_empty() || !unknown.is_empty(),
+        pending,
+        unknown,
+    }
+}
+
+async fn applied_migrations(db: &D1Database) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Row {
+        name: String,
+    }
+    let rows = db
+        .prepare(format!("SELECT name FROM {} ORDER BY id", MIGRATIONS_TABLE))
+        .all()
+        .await;
+    match rows {
+        Ok(rows) => Ok(rows.results::<Row>()?.into_iter().map(|r| r.name).collect()),
+        // No migrations have ever been applied, so wrangler hasn't created it
+        Err(e) if e.to_string().contains("no such table") => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn handle_schema_status(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let db = ctx.env.d1("DB")?;
+    let applied = within_deadline(&ctx, applied_migrations(&db)).await?;
+    let status = schema_status(SCHEMA_MIGRATIONS, &applied);
+    if status.drift {
+        console_warn!(
+            "schema drift: pending {:?}, unknown {:?}",
+            status.pending,
+            status.unknown
+        );
+    }
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(status),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// INDEX CHECK
+// ============================================
+
+// A migration that failed halfway, or a table recreated by hand, can leave
+// the users table without an index the queries were written against. Nothing
+// errors; lists and lookups just turn into full scans. GET /admin/indexes
+// (and INDEX_CHECK_ON_START) compares `PRAGMA index_list` with this list.
+struct ExpectedIndex {
+    name: &'static str,
+    columns: &'static str,
+    // What scans the table without it
+    used_by: &'static str,
+}
+
+const EXPECTED_INDEXES: &[ExpectedIndex] = &[
+    ExpectedIndex {
+        name: "idx_users_created_at_id",
+        columns: "created_at, id",
+        used_by: "user list ordering and cursors",
+    },
+    ExpectedIndex {
+        name: "idx_users_deleted_at",
+        columns: "deleted_at",
+        used_by: "soft-delete filtering and retention purges",
+    },
+    ExpectedIndex {
+        name: "idx_users_email_normalized",
+        columns: "email_normalized",
+        used_by: "email conflict checks",
+    },
+    ExpectedIndex {
+        name: "idx_users_tenant",
+        columns: "tenant_id, created_at, id",
+        used_by: "tenant-scoped user lists",
+    },
+    ExpectedIndex {
+        name: "idx_users_email_hmac",
+        columns: "email_hmac",
+        used_by: "encrypted email lookups",
+    },
+    ExpectedIndex {
+        name: "idx_users_tenant_email",
+        columns: "COALESCE(tenant_id, ''), email_normalized",
+        used_by: "per-tenant email uniqueness",
+    },
+];
+
+// Only one first request per isolate starts the INDEX_CHECK_ON_START check
+static INDEX_CHECK_STARTED: std::sync::OnceLock<()> = std::sync::OnceLock::new();
+
+#[derive(Serialize, Debug, PartialEq)]
+struct MissingIndex {
+    name: &'static str,
+    columns: &'static str,
+    used_by: &'static str,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct IndexReport {
+    present: Vec<&'static str>,
+    missing: Vec<MissingIndex>,
+}
+
+fn index_report(expected: &[ExpectedIndex], actual: &[String]) -> IndexReport {
+    let mut report = IndexReport {
+        present: Vec::new(),
+        missing: Vec::new(),
+    };
+    for index in expected {
+        if actual.iter().any(|name| name == index.name) {
+            report.present.push(index.name);
+        } else {
+            report.missing.push(MissingIndex {
+                name: index.name,
+                columns: index.columns,
+                used_by: index.used_by,
+            });
+        }
+    }
+    report
+}
+
+fn index_warnings(report: &IndexReport) -> Vec<String> {
+    report
+        .missing
+        .iter()
+        .map(|index| {
+            format!(
+                "missing index {} on users ({}): {} will scan the table",
+                index.name, index.columns, index.used_by
+            )
+        })
+        .collect()
+}
+
+async fn index_names(db: &D1Database) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Row {
+        name: String,
+    }
+    let rows = db.prepare("PRAGMA index_list(users)").all().await?;
+    Ok(rows.results::<Row>()?.into_iter().map(|r| r.name).collect())
+}
+
+async fn handle_index_check(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let db = ctx.env.d1("DB")?;
+    let names = within_deadline(&ctx, index_names(&db)).await?;
+    let report = index_report(EXPECTED_INDEXES, &names);
+    for warning in index_warnings(&report) {
+        console_warn!("{}", warning);
+    }
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(report),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// USERS BACKUP
+// ============================================
+
+// GET /admin/backup streams every users row, soft-deleted and anonymized ones
+// included, as gzip-compressed NDJSON. Rows are written exactly as stored
+// (encrypted emails stay encrypted) so the file can be loaded straight back.
+// Pages are read by keyset on `id` and compressed as they arrive, so memory
+// holds one page plus the compressor's window however big the table is.
+const BACKUP_PAGE_SIZE: u32 = 500;
+
+struct BackupEncoder(flate2::write::GzEncoder<Vec<u8>>);
+
+impl BackupEncoder {
+    fn new() -> Self {
+        Self(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ))
+    }
+
+    // One line per row. Returns the compressed bytes produced so far; the
+    // compressor may hold some back until later rows or `finish`.
+    fn write_rows(&mut self, rows: &[serde_json::Value]) -> Result<Vec<u8>> {
+        use std::io::Write;
+
+        for row in rows {
+            serde_json::to_writer(&mut self.0, row)?;
+            self.0
+                .write_all(b"\n")
+                .map_err(|e| Error::RustError(e.to_string()))?;
+        }
+        Ok(std::mem::take(self.0.get_mut()))
+    }
+
+    // The remaining bytes and the gzip trailer
+    fn finish(self) -> Result<Vec<u8>> {
+        self.0.finish().map_err(|e| Error::RustError(e.to_string()))
+    }
+}
+
+async fn backup_page(db: &D1Database, after: Option<&str>) -> Result<Vec<serde_json::Value>> {
+    let statement = match after {
+        None => db
+            .prepare("SELECT * FROM users ORDER BY id LIMIT ?")
+            .bind(&[BACKUP_PAGE_SIZE.into()])?,
+        Some(id) => db
+            .prepare("SELECT * FROM users WHERE id > ? ORDER BY id LIMIT ?")
+            .bind(&[id.into(), BACKUP_PAGE_SIZE.into()])?,
+    };
+    statement.all().await?.results()
+}
+
+struct BackupState {
+    db: D1Database,
+    after: Option<String>,
+    // None once the trailer has been sent
+    encoder: Option<BackupEncoder>,
+}
+
+// The status line is already sent by the time a later page fails, so a D1
+// error is logged and then errors the body stream. The client sees a broken
+// transfer (and a gzip file with no trailer), never a short backup that
+// looks complete.
+async fn handle_backup(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    use futures::TryStreamExt;
+
+    if !is_admin(&req, &ctx)? {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Admin key required");
+    }
+
+    let state = BackupState {
+        db: ctx.env.d1("DB")?,
+        after: None,
+        encoder: Some(BackupEncoder::new()),
+    };
+    let body = futures::stream::try_unfold(state, |mut state| async move {
+        let Some(mut encoder) = state.encoder.take() else {
+            return Ok(None);
+        };
+        let rows = backup_page(&state.db, state.after.as_deref())
+            .await
+            .inspect_err(|e| {
+                console_error!(
+                    "backup aborted after id {:?}: {}",
+                    state.after.as_deref().unwrap_or(""),
+                    e
+                )
+            })?;
+        let mut chunk = encoder.write_rows(&rows)?;
+        if rows.len() < BACKUP_PAGE_SIZE as usize {
+            chunk.extend(encoder.finish()?);
+        } else {
+            state.after = rows
+                .last()
+                .and_then(|row| row["id"].as_str())
+                .map(str::to_string);
+            state.encoder = Some(encoder);
+        }
+        Ok::<_, Error>(Some((chunk, state)))
+    })
+    .into_stream();
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/gzip")?;
+    headers.set(
+        "Content-Disposition",
+        &format!(
+            "attachment; filename=\"users-{}.ndjson.gz\"",
+            chrono::Utc::now().format("%Y-%m-%d")
+        ),
+    )?;
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::from_stream(body)?.with_headers(headers))
+}
+
+// ============================================
+// UPSTREAM PROXY
+// ============================================
+
+// Upstream response headers passed back to the client. Rate-limit headers
+// (both the IETF draft names and the common X- variants) and Retry-After are
+// kept so clients can back off on the upstream's terms.
+const PROXY_RESPONSE_HEADERS: &[&str] = &[
+    "content-type",
+    "cache-control",
+    "etag",
+    "last-modified",
+    "retry-after",
+    "ratelimit",
+    "ratelimit-policy",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+];
+// Longest Retry-After the proxy will wait out itself before its one retry
+const PROXY_MAX_RETRY_WAIT_SECS: u64 = 2;
+
+fn proxied_headers(upstream: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String)> {
+    upstream
+        .into_iter()
+        .filter(|(name, _)| PROXY_RESPONSE_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+        .collect()
+}
+
+// Whether to retry once after a 429/503, and how long to wait first. Only
+// idempotent methods, and only a short delta-seconds Retry-After; anything
+// else is passed straight through for the client to handle.
+fn proxy_retry_delay(method: &Method, status: u16, retry_after: Option<&str>) -> Option<u64> {
+    let idempotent = matches!(
+        method,
+        Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options
+    );
+    if !idempotent || !matches!(status, 429 | 503) {
+        return None;
+    }
+    retry_after?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|&secs| secs <= PROXY_MAX_RETRY_WAIT_SECS)
+}
+
+async fn handle_proxy(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let Some(upstream) = ctx.data.config.proxy_upstream.clone() else {
+        return error_response(&ctx, ErrorCode::NotFound, "Proxy is not configured");
+    };
+    let url = req.url()?;
+    let mut target = format!("{}/{}", upstream, ctx.param("path").unwrap());
+    if let Some(query) = url.query() {
+        target = format!("{}?{}", target, query);
+    }
+
+    let method = req.method();
+    let body = match method {
+        Method::Get | Method::Head => None,
+        _ => Some(req.bytes().await?),
+    };
+    let forward = |method: Method| -> Result<Request> {
+        let mut headers = Headers::new();
+        for name in ["Content-Type", "Accept"] {
+            if let Some(value) = req.headers().get(name)? {
+                headers.set(name, &value)?;
+            }
+        }
+        let mut init = RequestInit::new();
+        init.with_method(method).with_headers(headers);
+        if let Some(body) = &body {
+            init.with_body(Some(js_sys::Uint8Array::from(body.as_slice()).into()));
+        }
+        Request::new_with_init(&target, &init)
+    };
+
+    let mut response = match within_deadline(&ctx, Fetch::Request(forward(method.clone())?).send())
+        .await
+    {
+        Ok(response) => response,
+        Err(e) if is_deadline_error(&e) => return Err(e),
+        Err(e) => {
+            console_error!("proxy request to {} failed: {}", target, e);
+            return error_response(&ctx, ErrorCode::UpstreamUnavailable, "Upstream unavailable");
+        }
+    };
+    let retry_after = response.headers().get("Retry-After")?;
+    let remaining = ctx.data.deadline.remaining_ms(Date::now().as_millis());
+    let delay = proxy_retry_delay(&method, response.status_code(), retry_after.as_deref())
+        // No point waiting for a retry the deadline won't leave time for
+        .filter(|&secs| secs.saturating_mul(1000) < remaining);
+    if let Some(secs) = delay {
+        Delay::from(std::time::Duration::from_secs(secs)).await;
+        // A failed retry still returns the original upstream response
+        if let Ok(retried) = within_deadline(&ctx, Fetch::Request(forward(method)?).send()).await {
+            response = retried;
+        }
+    }
+
+    let mut headers = Headers::new();
+    for (name, value) in proxied_headers(response.headers().entries()) {
+        headers.set(&name, &value)?;
+    }
+    let status = response.status_code();
+    Ok(Response::from_bytes(response.bytes().await?)?
+        .with_status(status)
+        .with_headers(headers))
+}
+
+// ============================================
+// DEBUG ECHO
+// ============================================
+
+// Values are replaced, names kept, so clients can still see the header arrived
+const ECHO_REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+    "cf-access-jwt-assertion",
+];
+
+#[derive(Serialize)]
+struct EchoResponse {
+    method: String,
+    path: String,
+    headers: BTreeMap<String, String>,
+    query: BTreeMap<String, String>,
+    // Null when the body is empty or isn't JSON
+    body: Option<serde_json::Value>,
+    body_bytes: usize,
+}
+
+// Same 404 as an unknown route, so a disabled endpoint isn't discoverable
+fn debug_echo_denied(config: &Config) -> Option<ErrorCode> {
+    (!config.debug_echo).then_some(ErrorCode::NotFound)
+}
+
+fn redacted_headers(headers: impl Iterator<Item = (String, String)>) -> BTreeMap<String, String> {
+    headers
+        .map(|(name, value)| {
+            let name = name.to_ascii_lowercase();
+            let value = if ECHO_REDACTED_HEADERS.contains(&name.as_str()) {
+                "[redacted]".to_string()
+            } else {
+                value
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+async fn handle_debug_echo(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if let Some(code) = debug_echo_denied(&ctx.data.config) {
+        return error_response(&ctx, code, "Not found");
+    }
+    let url = req.url()?;
+    let body = req.bytes().await?;
+    let echo = EchoResponse {
+        method: req.method().to_string(),
+        path: url.path().to_string(),
+        headers: redacted_headers(req.headers().entries()),
+        query: url.query_pairs().into_owned().collect(),
+        body: serde_json::from_slice(&body).ok(),
+        body_bytes: body.len(),
+    };
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(echo),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// DATA RETENTION
+// ============================================
+
+// Anonymized emails use a reserved TLD so they can never receive mail and
+// can't collide with a real address
+const ANONYMIZED_EMAIL_DOMAIN: &str = "@anonymized.invalid";
+// Rows anonymized per run, to stay within the scheduled CPU budget
+const ANONYMIZE_BATCH_SIZE: u32 = 100;
+
+// Rows hard-deleted per cap enforcement, so one run stays a single quick query
+const DELETED_CAP_BATCH_SIZE: u32 = 500;
+
+struct RetentionReport {
+    deleted: usize,
+    // Hard-deleted early because RETENTION_MAX_DELETED was exceeded
+    capped: usize,
+    anonymized: usize,
+}
+
+fn sha256_hex(value: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex(&Sha256::digest(value.as_bytes()))
+}
+
+// Replaces name and email with SHA-256 digests. Already-anonymized identities
+// are returned unchanged, so re-running on the same row is a no-op.
+fn anonymize_identity(name: &str, email: &str) -> (String, String) {
+    if email.ends_with(ANONYMIZED_EMAIL_DOMAIN) {
+        return (name.to_string(), email.to_string());
+    }
+    (
+        format!("anon-{}", &sha256_hex(name)[..16]),
+        format!(
+            "{}{}",
+            sha256_hex(&email.to_lowercase()),
+            ANONYMIZED_EMAIL_DOMAIN
+        ),
+    )
+}
+
+// How many of the oldest soft-deleted rows to hard-delete now. Only the
+// excess over the cap goes, at most a batch at a time; a later delete or the
+// cron picks up any remainder.
+fn deleted_cap_overflow(soft_deleted: u64, max: u32) -> u32 {
+    soft_deleted
+        .saturating_sub(max as u64)
+        .min(DELETED_CAP_BATCH_SIZE as u64) as u32
+}
+
+// Hard-deletes the oldest soft-deleted users beyond RETENTION_MAX_DELETED.
+// Rows still inside the retention window are kept unless
+// RETENTION_CAP_IGNORES_WINDOW is set. Unscoped, like the rest of retention.
+async fn enforce_deleted_cap(db: &D1Database, max: u32, window_days: Option<u32>) -> Result<usize> {
+    let row = db
+        .prepare("SELECT COUNT(*) as count FROM users WHERE deleted_at IS NOT NULL")
+        .first::<serde_json::Value>(None)
+        .await?;
+    let soft_deleted = parse_count(row.as_ref())?;
+    let overflow = deleted_cap_overflow(soft_deleted, max);
+    if overflow == 0 {
+        return Ok(0);
+    }
+    let cutoff = window_days.map(days_ago);
+    let window = if cutoff.is_some() {
+        " AND deleted_at < ?"
+    } else {
+        ""
+    };
+    let mut params: Vec<wasm_bindgen::JsValue> = cutoff.into_iter().map(Into::into).collect();
+    params.push(overflow.into());
+    let purged = db
+        .prepare(format!(
+            "DELETE FROM users WHERE id IN (SELECT id FROM users WHERE deleted_at IS NOT NULL{} \
+             ORDER BY deleted_at LIMIT ?) RETURNING id",
+            window
+        ))
+        .bind(&params)?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?
+        .len();
+    console_warn!(
+        "{} soft-deleted users over the cap of {}; hard-deleted the oldest {}",
+        soft_deleted,
+        max,
+        purged
+    );
+    Ok(purged)
+}
+
+// Runs the cap check after a delete, off the response path
+fn check_deleted_cap(ctx: &RouteContext<ReqCtx>) {
+    let Some(max) = ctx.data.config.retention_max_deleted else {
+        return;
+    };
+    let env = ctx.env.clone();
+    let window_days = deleted_cap_window(&ctx.data.config);
+    spawn_background(ctx, "soft-delete cap", async move {
+        enforce_deleted_cap(&env.d1("DB")?, max, window_days).await?;
+        Ok(())
+    });
+}
+// Age in days a soft-deleted row must reach before the cap may purge it;
+// None when RETENTION_CAP_IGNORES_WINDOW lets the cap take any row
+fn deleted_cap_window(config: &Config) -> Option<u32> {
+    (!config.retention_cap_ignores_window).then_some(config.retention_deleted_days)
+}
+
+fn days_ago(days: u32) -> String {
+    (chrono::Utc::now() - chrono::Duration::days(days as i64)).to_rfc3339()
+}
+
+async fn enforce_retention(env: &Env, config: &Config) -> Result<RetentionReport> {
+    let db = env.d1("DB")?;
+
+    // Soft-deleted rows are already gone from the user cache
+    let deleted = db
+        .prepare("DELETE FROM users WHERE deleted_at IS NOT NULL AND deleted_at < ? RETURNING id")
+        .bind(&[days_ago(config.retention_deleted_days).into()])?
+        .all()
+        .await?
+        .results::<serde_json::Value>()?
+        .len();
+    let capped = match config.retention_max_deleted {
+        Some(max) => enforce_deleted_cap(&db, max, deleted_cap_window(config)).await?,
+        None => 0,
+    };
+
+    let Some(inactive_days) = config.retention_inactive_days else {
+        return Ok(RetentionReport {
+            deleted,
+            capped,
+            anonymized: 0,
+        });
+    };
+
+    let mut users = db
+        .prepare(
+            "SELECT * FROM users WHERE deleted_at IS NULL AND anonymized_at IS NULL \
+             AND COALESCE(updated_at, created_at) < ? LIMIT ?",
+        )
+        .bind(&[days_ago(inactive_days).into(), ANONYMIZE_BATCH_SIZE.into()])?
+        .all()
+        .await?
+        .results::<User>()?;
+    open_users(email_cipher(env, config)?.as_ref(), &mut users)?;
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let mut statements = Vec::with_capacity(users.len());
+    for user in &users {
+        let (name, email) = anonymize_identity(&user.name, &user.email);
+        statements.push(
+            db.prepare(
+                // Anonymized values aren't personal data, so they're stored in the clear
+                "UPDATE users SET name = ?, email = ?, email_normalized = ?, email_hmac = NULL, \
+                 anonymized_at = ? WHERE id = ?",
+            )
+            .bind(&[
+                name.into(),
+                email.as_str().into(),
+                email.as_str().into(),
+                now.as_str().into(),
+                user.id.as_str().into(),
+            ])?,
+        );
+    }
+    if !statements.is_empty() {
+        db.batch(statements).await?;
+    }
+
+    let kv = env.kv("CACHE")?;
+    let version = user_cache_version(&kv).await?;
+    for user in &users {
+        kv.delete(&user_cache_key(version, &user.id)).await?;
+    }
+
+    Ok(RetentionReport {
+        deleted,
+        capped,
+        anonymized: users.len(),
+    })
+}
+
+// ============================================
+// COMPUTE LOAD SHEDDING (DURABLE OBJECT)
+// ============================================
+
+// Lifecycle of a compute slot:
+//   1. The handler asks the single ComputeSlots object for a lease. At the
+//      limit it gets none and the request is shed with 503 + Retry-After.
+//   2. The lease is wrapped in a ComputeSlot guard held for the rest of the
+//      handler.
+//   3. However the handler exits (success, an error response, or `?` on a
+//      failure) the guard is dropped and its Drop queues the release.
+// A release lost on the way (background budget, object restart) can't pin a
+// slot forever: leases older than COMPUTE_SLOT_TTL_MS are expired on the next
+// acquire. If the object can't be reached at all, requests are let through.
+const COMPUTE_SLOT_TTL_MS: f64 = 60_000.0;
+const COMPUTE_SHED_RETRY_AFTER: &str = "1";
+
+#[derive(Default)]
+struct SlotTable {
+    next_id: u64,
+    // (lease id, acquired at in ms)
+    leases: Vec<(u64, f64)>,
+}
+
+impl SlotTable {
+    fn acquire(&mut self, now: f64, max: u32) -> Option<u64> {
+        self.leases
+            .retain(|&(_, acquired)| now - acquired < COMPUTE_SLOT_TTL_MS);
+        if self.leases.len() >= max as usize {
+            return None;
+        }
+        self.next_id += 1;
+        self.leases.push((self.next_id, now));
+        Some(self.next_id)
+    }
+
+    // Unknown ids (already expired, or from before a restart) are ignored
+    fn release(&mut self, id: u64) {
+        self.leases.retain(|&(lease, _)| lease != id);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SlotRequest {
+    max: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SlotLease {
+    lease: Option<u64>,
+}
+
+mod compute_slots {
+    use super::*;
+
+    // Slots only need to outlive the requests holding them, so the table
+    // lives in memory; a restarted object starts empty.
+    #[durable_object]
+    pub struct ComputeSlots {
+        slots: SlotTable,
+    }
+
+    #[durable_object]
+    impl DurableObject for ComputeSlots {
+        fn new(state: State, _env: Env) -> Self {
+            Self {
+                slots: SlotTable::default(),
+            }
+        }
+
+        async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+            let now = Date::now().as_millis() as f64;
+            match (req.method(), req.path().as_str()) {
+                (Method::Post, "/acquire") => {
+                    let SlotRequest { max } = req.json().await?;
+                    Response::from_json(&SlotLease {
+                        lease: self.slots.acquire(now, max),
+                    })
+                }
+                (Method::Post, "/release") => {
+                    if let SlotLease { lease: Some(id) } = req.json().await? {
+                        self.slots.release(id);
+                    }
+                    Ok(Response::empty()?.with_status(204))
+                }
+                _ => Response::error("Not found", 404),
+            }
+        }
+    }
+}
+pub use compute_slots::ComputeSlots;
+
+// Runs `release` exactly once, when dropped
+struct SlotGuard<F: FnOnce()> {
+    release: Option<F>,
+}
+
+impl<F: FnOnce()> SlotGuard<F> {
+    fn new(release: F) -> Self {
+        Self {
+            release: Some(release),
+        }
+    }
+
+    // Nothing to release (no limit configured, or the object was unreachable)
+    fn unlimited() -> Self {
+        Self { release: None }
+    }
+}
+
+impl<F: FnOnce()> Drop for SlotGuard<F> {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+type ComputeSlot<'a> = SlotGuard<Box<dyn FnOnce() + 'a>>;
+
+async fn slot_request(env: &Env, path: &str, body: String) -> Result<Response> {
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post).with_body(Some(body.into()));
+    let req = Request::new_with_init(&format!("https://compute-slots{}", path), &init)?;
+    env.durable_object("COMPUTE_SLOTS")?
+        .id_from_name("compute")?
+        .get_stub()?
+        .fetch_with_request(req)
+        .await
+}
+
+// None means the request should be shed
+async fn acquire_compute_slot(ctx: &RouteContext<ReqCtx>) -> Option<ComputeSlot<'_>> {
+    let Some(max) = ctx.data.config.compute_max_concurrency else {
+        return Some(SlotGuard::unlimited());
+    };
+    let acquired = async {
+        let body = serde_json::to_string(&SlotRequest { max })?;
+        slot_request(&ctx.env, "/acquire", body)
+            .await?
+            .json::<SlotLease>()
+            .await
+    }
+    .await;
+    let lease = match acquired {
+        Ok(SlotLease { lease: Some(lease) }) => lease,
+        Ok(SlotLease { lease: None }) => return None,
+        Err(e) => {
+            console_warn!("compute slots unavailable, not shedding: {}", e);
+            return Some(SlotGuard::unlimited());
+        }
+    };
+
+    Some(SlotGuard::new(Box::new(move || {
+        let env = ctx.env.clone();
+        spawn_background(ctx, "compute slot release", async move {
+            let body = serde_json::to_string(&SlotLease { lease: Some(lease) })?;
+            slot_request(&env, "/release", body).await?;
+            Ok(())
+        });
+    })))
+}
+
+fn overloaded_response(ctx: &RouteContext<ReqCtx>) -> Result<Response> {
+    let mut response = error_response(
+        ctx,
+        ErrorCode::Overloaded,
+        "Compute is at capacity, retry shortly",
+    )?;
+    response
+        .headers_mut()
+        .set("Retry-After", COMPUTE_SHED_RETRY_AFTER)?;
+    Ok(response)
+}
+
+// ============================================
+// COMPUTE USAGE (DURABLE OBJECT)
+// ============================================
+
+// A relative cost per operation, for billing and analytics rather than
+// precise CPU time: the data length scaled by how the operation grows.
+// Linear scans cost n, two-pass operations 2n, and anything that sorts
+// n * log2(n). Only successful operations are charged. Totals are kept
+// per API key in one ComputeUsage object per key (named by the key's hash),
+// so keys never contend with each other; anonymous calls aren't tracked.
+const COMPUTE_USAGE_KEY: &str = "usage";
+
+fn n_log_n(n: usize) -> u64 {
+    let n = n as u64;
+    n.saturating_mul((u64::BITS - n.leading_zeros()) as u64)
+}
+
+fn compute_cost(input: &ComputeRequest) -> u64 {
+    let n = input.data.len();
+    match input.operation.as_str() {
+        "sum" | "mean" | "max" | "min" | "cumsum" | "diff" | "trend" | "integrate" => n as u64,
+        "std" | "linear_regression" | "zscore" => 2 * n as u64,
+        "summary" | "outliers" | "rank" | "weighted_percentile" => n_log_n(n),
+        "describe" => input.series.values().map(|s| n_log_n(s.len())).sum(),
+        "fft_magnitude" => n_log_n(n.next_power_of_two()),
+        "group_agg" => n_log_n(input.pairs.len()),
+        // The inner operation reruns over every window
+        "rolling" => (n as u64).saturating_mul(input.window.max(1) as u64),
+        _ => 0,
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+struct UsageTotals {
+    cost: u64,
+    operations: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, other: &UsageTotals) {
+        self.cost = self.cost.saturating_add(other.cost);
+        self.operations = self.operations.saturating_add(other.operations);
+    }
+}
+
+mod compute_usage {
+    use super::*;
+
+    #[durable_object]
+    pub struct ComputeUsage {
+        state: State,
+    }
+
+    #[durable_object]
+    impl DurableObject for ComputeUsage {
+        fn new(state: State, _env: Env) -> Self {
+            Self { state }
+        }
+
+        async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+            let mut storage = self.state.storage();
+            // Missing on first use; any other read failure is an error, not zero
+            let mut totals = match storage.get::<UsageTotals>(COMPUTE_USAGE_KEY).await {
+                Ok(totals) => totals,
+                Err(e) if is_missing_storage_value(&e.to_string()) => UsageTotals::default(),
+                Err(e) => return Err(e),
+            };
+            match (req.method(), req.path().as_str()) {
+                (Method::Get, "/usage") => Response::from_json(&totals),
+                (Method::Post, "/add") => {
+                    let charge: UsageTotals = req.json().await?;
+                    totals.add(&charge);
+                    storage.put(COMPUTE_USAGE_KEY, &totals).await?;
+                    Response::from_json(&totals)
+                }
+                _ => Response::error("Not found", 404),
+            }
+        }
+    }
+}
+pub use compute_usage::ComputeUsage;
+
+async fn usage_request(
+    env: &Env,
+    key_id: &str,
+    method: Method,
+    path: &str,
+    body: Option<String>,
+) -> Result<UsageTotals> {
+    let mut init = RequestInit::new();
+    init.with_method(method).with_body(body.map(Into::into));
+    let req = Request::new_with_init(&format!("https://compute-usage{}", path), &init)?;
+    env.durable_object("COMPUTE_USAGE")?
+        .id_from_name(key_id)?
+        .get_stub()?
+        .fetch_with_request(req)
+        .await?
+        .json()
+        .await
+}
+
+// Charges the caller's key after the response; a lost charge only
+// under-counts, it never fails the request
+fn record_compute_usage(
+    ctx: &RouteContext<ReqCtx>,
+    req: &Request,
+    charge: UsageTotals,
+) -> Result<()> {
+    if !ctx.data.config.compute_usage || charge.operations == 0 {
+        return Ok(());
+    }
+    // authorize_compute has already rejected unknown keys
+    let Some(key) = req.headers().get("X-API-Key")? else {
+        return Ok(());
+    };
+    let env = ctx.env.clone();
+    spawn_background(ctx, "compute usage", async move {
+        let body = serde_json::to_string(&charge)?;
+        usage_request(&env, &sha256_hex(&key), Method::Post, "/add", Some(body)).await?;
+        Ok(())
+    });
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct KeyUsage {
+    name: String,
+    #[serde(flatten)]
+    totals: UsageTotals,
+}
+
+// Usage for the key in X-API-Key
+async fn handle_compute_usage(req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    if !ctx.data.config.compute_usage {
+        return error_response(&ctx, ErrorCode::NotFound, "Usage tracking is not enabled");
+    }
+    let ApiCaller::Key(record) = lookup_api_key(&req, &ctx).await? else {
+        return error_response(&ctx, ErrorCode::Unauthorized, "Valid X-API-Key required");
+    };
+    let key = req.headers().get("X-API-Key")?.unwrap_or_default();
+    let totals = within_deadline(
+        &ctx,
+        usage_request(&ctx.env, &sha256_hex(&key), Method::Get, "/usage", None),
+    )
+    .await?;
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(KeyUsage {
+                name: record.name,
+                totals,
+            }),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// CPU-INTENSIVE COMPUTATION
+// ============================================
+
+#[derive(Deserialize, Default)]
+struct ComputeRequest {
+    // Not sent for "describe" or "group_agg", which read `series` / `pairs` instead
+    #[serde(default)]
+    data: Vec<f64>,
+    // Missing or empty means COMPUTE_DEFAULT_OPERATION
+    #[serde(default)]
+    operation: String,
+    // Second series for paired operations (y values for linear_regression)
+    #[serde(default)]
+    data2: Vec<f64>,
+    // Decimal places to round every number in the result to
+    #[serde(default, deserialize_with = "optional_exact_integer")]
+    round: Option<u32>,
+    // For "rank": ties share a rank and the next rank follows on (1, 2, 2, 3)
+    // instead of skipping (1, 2, 2, 4)
+    #[serde(default)]
+    dense: bool,
+    // Named columns for "describe"; sorted by name so results come back in a
+    // stable order
+    #[serde(default)]
+    series: BTreeMap<String, Vec<f64>>,
+    // For "zscore": divide by the sample std (n - 1) instead of the population std
+    #[serde(default)]
+    sample: bool,
+    // For "group_agg": [key, value] pairs, aggregated per key by `agg`
+    #[serde(default)]
+    pairs: Vec<(String, f64)>,
+    #[serde(default)]
+    agg: String,
+    // For "weighted_percentile": one non-negative weight per `data` value,
+    // and the percentile wanted, 0 to 100
+    #[serde(default)]
+    weights: Vec<f64>,
+    #[serde(default)]
+    percentile: Option<f64>,
+    // For "rolling": `inner_operation` (a scalar operation) over each run of
+    // `window` consecutive values
+    #[serde(default, deserialize_with = "exact_integer")]
+    window: usize,
+    #[serde(default)]
+    inner_operation: String,
+    // For "integrate": `data` are y values, either `dx` apart (default 1) or
+    // at the matching `x` positions
+    #[serde(default)]
+    dx: Option<f64>,
+    #[serde(default)]
+    x: Option<Vec<f64>>,
+}
+
+impl ComputeRequest {
+    fn default_operation(&mut self, default: &str) {
+        if self.operation.is_empty() {
+            self.operation = default.to_string();
+        }
+    }
+
+    // Number of input values, across every series for "describe"
+    fn value_count(&self) -> usize {
+        match self.operation.as_str() {
+            "describe" => self.series.values().map(Vec::len).sum(),
+            "group_agg" => self.pairs.len(),
+            _ => self.data.len(),
+        }
+    }
+}
+
+// Beyond this an f64 has no more decimal digits to round
+const COMPUTE_MAX_ROUND: u32 = 15;
+
+#[derive(Serialize)]
+struct ComputeResult {
+    result: ComputeValue,
+    operation: String,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct ComputeBatchRequest {
+    operations: Vec<ComputeRequest>,
+}
+
+// Every operation `run_compute` understands, for validating key allowlists.
+// Both this table and the dispatch `match` are fixed at compile time, so
+// there is no per-request registry to build or cache in a static.
+const COMPUTE_OPERATIONS: &[&str] = &[
+    "sum",
+    "mean",
+    "max",
+    "min",
+    "std",
+    "summary",
+    "outliers",
+    "rank",
+    "linear_regression",
+    "describe",
+    "cumsum",
+    "diff",
+    "zscore",
+    "trend",
+    "fft_magnitude",
+    "group_agg",
+    "weighted_percentile",
+    "rolling",
+    "integrate",
+];
+
+// Operations "rolling" can apply per window: the ones reducing data to one number
+const ROLLING_OPERATIONS: &[&str] = &["sum", "mean", "max", "min", "std"];
+
+// Keeps one batch within a single request's CPU budget
+const COMPUTE_BATCH_MAX: usize = 50;
+
+#[derive(Serialize)]
+struct BatchComputeItem {
+    operation: String,
+    count: usize,
+    result: ComputeValue,
+    // Only with ?timings=true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed_ms: Option<f64>,
+}
+
+// Scalar results serialize as a bare number, exactly as before
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ComputeValue {
+    Scalar(f64),
+    Summary(SummaryStats),
+    Regression(Regression),
+    Outliers(Outliers),
+    Ranking(Ranking),
+    Described(BTreeMap<String, SummaryStats>),
+    // One aggregate per key for "group_agg"
+    Grouped(BTreeMap<String, f64>),
+    // Element-wise results (cumsum, diff) serialize as a plain array
+    Series(Vec<f64>),
+    Trend(Trend),
+    Spectrum(Spectrum),
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SummaryStats {
+    count: usize,
+    min: f64,
+    max: f64,
+    mean: f64,
+    median: f64,
+    std: f64,
+    q1: f64,
+    q3: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct Regression {
+    slope: f64,
+    intercept: f64,
+    r_squared: f64,
+}
+
+// Ordinary least squares fit of y = slope * x + intercept
+fn linear_regression(x: &[f64], y: &[f64]) -> std::result::Result<Regression, &'static str> {
+    if x.is_empty() || x.len() != y.len() {
+        return Err("data and data2 must be non-empty and the same length");
+    }
+
+    let n = x.len() as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+
+    let mut ss_xx = 0.0;
+    let mut ss_xy = 0.0;
+    let mut ss_yy = 0.0;
+    for (xi, yi) in x.iter().zip(y) {
+        ss_xx += (xi - mean_x).powi(2);
+        ss_xy += (xi - mean_x) * (yi - mean_y);
+        ss_yy += (yi - mean_y).powi(2);
+    }
+    if ss_xx == 0.0 {
+        return Err("data (x) must not be constant");
+    }
+
+    let slope = ss_xy / ss_xx;
+    // A constant y is fit exactly by the horizontal line
+    let r_squared = if ss_yy == 0.0 {
+        1.0
+    } else {
+        (ss_xy * ss_xy) / (ss_xx * ss_yy)
+    };
+
+    Ok(Regression {
+        slope,
+        intercept: mean_y - slope * mean_x,
+        r_squared,
+    })
+}
+
+#[derive(Serialize, Debug)]
+struct Outliers {
+    q1: f64,
+    q3: f64,
+    iqr: f64,
+    lower_bound: f64,
+    upper_bound: f64,
+    // Positions in the input array, ascending, with the matching values
+    indices: Vec<usize>,
+    values: Vec<f64>,
+}
+
+// Round half to even on the scaled value. Values too large to scale are
+// already integral at this precision and pass through unchanged.
+fn round_to(x: f64, places: u32) -> f64 {
+    let scale = 10f64.powi(places as i32);
+    let scaled = x * scale;
+    if !scaled.is_finite() {
+        return x;
+    }
+    scaled.round_ties_even() / scale
+}
+
+impl ComputeValue {
+    // JSON has no NaN or Infinity (serde_json writes them as null), so a
+    // result holding one is refused rather than sent with its numbers missing
+    fn is_finite(&self) -> bool {
+        let all = |values: &[f64]| values.iter().all(|x| x.is_finite());
+        match self {
+            ComputeValue::Scalar(x) => x.is_finite(),
+            ComputeValue::Summary(s) => s.is_finite(),
+            ComputeValue::Regression(reg) => all(&[reg.slope, reg.intercept, reg.r_squared]),
+            ComputeValue::Outliers(o) => {
+                all(&[o.q1, o.q3, o.iqr, o.lower_bound, o.upper_bound]) && all(&o.values)
+            }
+            ComputeValue::Ranking(ranking) => ranking.sorted.iter().all(|v| v.value.is_finite()),
+            ComputeValue::Described(series) => series.values().all(SummaryStats::is_finite),
+            ComputeValue::Grouped(groups) => groups.values().all(|x| x.is_finite()),
+            ComputeValue::Series(values) => all(values),
+            ComputeValue::Trend(_) => true,
+            ComputeValue::Spectrum(spectrum) => all(&spectrum.magnitudes),
+        }
+    }
+
+    fn rounded(self, places: u32) -> Self {
+        let r = |x: f64| round_to(x, places);
+        match self {
+            ComputeValue::Scalar(x) => ComputeValue::Scalar(r(x)),
+            ComputeValue::Summary(s) => ComputeValue::Summary(s.rounded(places)),
+            ComputeValue::Regression(reg) => ComputeValue::Regression(Regression {
+                slope: r(reg.slope),
+                intercept: r(reg.intercept),
+                r_squared: r(reg.r_squared),
+            }),
+            ComputeValue::Outliers(o) => ComputeValue::Outliers(Outliers {
+                q1: r(o.q1),
+                q3: r(o.q3),
+                iqr: r(o.iqr),
+                lower_bound: r(o.lower_bound),
+                upper_bound: r(o.upper_bound),
+                indices: o.indices,
+                values: o.values.into_iter().map(r).collect(),
+            }),
+            ComputeValue::Ranking(ranking) => ComputeValue::Ranking(Ranking {
+                ranks: ranking.ranks,
+                sorted: ranking
+                    .sorted
+                    .into_iter()
+                    .map(|v| RankedValue {
+                        value: r(v.value),
+                        ..v
+                    })
+                    .collect(),
+            }),
+            ComputeValue::Described(series) => ComputeValue::Described(
+                series
+                    .into_iter()
+                    .map(|(name, s)| (name, s.rounded(places)))
+                    .collect(),
+            ),
+            ComputeValue::Series(values) => {
+                ComputeValue::Series(values.into_iter().map(r).collect())
+            }
+            ComputeValue::Trend(trend) => ComputeValue::Trend(trend),
+            ComputeValue::Grouped(groups) => {
+                ComputeValue::Grouped(groups.into_iter().map(|(key, x)| (key, r(x))).collect())
+            }
+            ComputeValue::Spectrum(spectrum) => ComputeValue::Spectrum(Spectrum {
+                magnitudes: spectrum.magnitudes.into_iter().map(r).collect(),
+                ..spectrum
+            }),
+        }
+    }
+}
+
+impl SummaryStats {
+    fn is_finite(&self) -> bool {
+        [
+            self.min,
+            self.max,
+            self.mean,
+            self.median,
+            self.std,
+            self.q1,
+            self.q3,
+        ]
+        .iter()
+        .all(|x| x.is_finite())
+    }
+
+    fn rounded(self, places: u32) -> Self {
+        let r = |x: f64| round_to(x, places);
+        SummaryStats {
+            count: self.count,
+            min: r(self.min),
+            max: r(self.max),
+            mean: r(self.mean),
+            median: r(self.median),
+            std: r(self.std),
+            q1: r(self.q1),
+            q3: r(self.q3),
+        }
+    }
+}
+
+// Tukey's fences: anything outside [Q1 - 1.5*IQR, Q3 + 1.5*IQR]
+fn iqr_outliers(data: &[f64]) -> Outliers {
+    let mut sorted = data.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let q1 = percentile_sorted(&sorted, 0.25);
+    let q3 = percentile_sorted(&sorted, 0.75);
+    let iqr = q3 - q1;
+    let lower_bound = q1 - 1.5 * iqr;
+    let upper_bound = q3 + 1.5 * iqr;
+
+    let (indices, values) = data
+        .iter()
+        .enumerate()
+        .filter(|(_, &x)| x < lower_bound || x > upper_bound)
+        .unzip();
+
+    Outliers {
+        q1,
+        q3,
+        iqr,
+        lower_bound,
+        upper_bound,
+        indices,
+        values,
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct RankedValue {
+    value: f64,
+    // Position in the input array
+    index: usize,
+    rank: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Ranking {
+    // 1-based rank of each input value, in input order
+    ranks: Vec<usize>,
+    // Ascending by value; equal values keep their input order
+    sorted: Vec<RankedValue>,
+}
+
+// Ascending ranks. Standard ("1224") gives tied values the rank of the first
+// of them; dense ("1223") numbers the distinct values instead.
+fn rank_values(data: &[f64], dense: bool) -> Ranking {
+    let mut order: Vec<usize> = (0..data.len()).collect();
+    order.sort_by(|&a, &b| data[a].total_cmp(&data[b]));
+
+    let mut ranks = vec![0; data.len()];
+    let mut sorted = Vec::with_capacity(data.len());
+    let mut rank = 0;
+    for (position, &index) in order.iter().enumerate() {
+        let tied = position > 0 && data[order[position - 1]] == data[index];
+        if !tied {
+            rank = if dense { rank + 1 } else { position + 1 };
+        }
+        ranks[index] = rank;
+        sorted.push(RankedValue {
+            value: data[index],
+            index,
+            rank,
+        });
+    }
+    Ranking { ranks, sorted }
+}
+
+// Linear interpolation between closest ranks; `sorted` must be non-empty
+fn percentile_sorted(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+// One sort for the order statistics, one pass (Welford) for mean and std
+fn summary_stats(data: &[f64]) -> SummaryStats {
+    let mut sorted = data.to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    let mut mean = 0.0;
+    let mut m2 = 0.0;
+    for (i, &x) in sorted.iter().enumerate() {
+        let delta = x - mean;
+        mean += delta / (i + 1) as f64;
+        m2 += delta * (x - mean);
+    }
+
+    SummaryStats {
+        count: sorted.len(),
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        mean,
+        median: percentile_sorted(&sorted, 0.5),
+        std: (m2 / sorted.len() as f64).sqrt(),
+        q1: percentile_sorted(&sorted, 0.25),
+        q3: percentile_sorted(&sorted, 0.75),
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Trend {
+    monotonic_increasing: bool,
+    monotonic_decreasing: bool,
+    // Every step moves in the monotonic direction; false for flat data
+    strictly: bool,
+    // Sign of the least-squares slope against the index: -1, 0 or 1
+    slope_sign: i8,
+}
+
+// One pass: the monotonic flags from consecutive pairs, and the slope sign
+// from running sums (n * sum(xy) - sum(x) * sum(y) has the slope's sign)
+fn trend(data: &[f64]) -> Trend {
+    let (mut increasing, mut decreasing) = (true, true);
+    let (mut strictly_up, mut strictly_down) = (true, true);
+    let (mut sum_x, mut sum_y, mut sum_xy) = (0.0, 0.0, 0.0);
+    let mut previous: Option<f64> = None;
+    for (i, &y) in data.iter().enumerate() {
+        if let Some(prev) = previous {
+            increasing &= y >= prev;
+            decreasing &= y <= prev;
+            strictly_up &= y > prev;
+            strictly_down &= y < prev;
+        }
+        previous = Some(y);
+        let x = i as f64;
+        sum_x += x;
+        sum_y += y;
+        sum_xy += x * y;
+    }
+    let n = data.len() as f64;
+    let slope_sign = if increasing && decreasing {
+        // Flat: skip the sums, which may not cancel exactly
+        0
+    } else {
+        let numerator = n * sum_xy - sum_x * sum_y;
+        if numerator > 0.0 {
+            1
+        } else if numerator < 0.0 {
+            -1
+        } else {
+            0
+        }
+    };
+    let strictly = data.len() > 1 && ((increasing && strictly_up) || (decreasing && strictly_down));
+    Trend {
+        monotonic_increasing: increasing,
+        monotonic_decreasing: decreasing,
+        strictly,
+        slope_sign,
+    }
+}
+
+// Running totals: out[i] = x[0] + ... + x[i]
+fn cumulative_sum(data: &[f64]) -> Vec<f64> {
+    data.iter()
+        .scan(0.0, |total, &x| {
+            *total += x;
+            Some(*total)
+        })
+        .collect()
+}
+
+// First differences: out[i] = x[i + 1] - x[i], one shorter than the input
+fn first_differences(data: &[f64]) -> Vec<f64> {
+    data.windows(2).map(|w| w[1] - w[0]).collect()
+}
+
+// Standard scores: out[i] = (x[i] - mean) / std. Err for constant data, where
+// std is zero, or a sample std over fewer than 2 values.
+fn z_scores(data: &[f64], sample: bool) -> std::result::Result<Vec<f64>, String> {
+    let n = data.len() as f64;
+    if sample && data.len() < 2 {
+        return Err("zscore with sample std needs at least 2 values".to_string());
+    }
+    let mean = data.iter().sum::<f64>() / n;
+    let squares: f64 = data.iter().map(|x| (x - mean).powi(2)).sum();
+    let std = (squares / if sample { n - 1.0 } else { n }).sqrt();
+    if std == 0.0 {
+        return Err("zscore is undefined for constant data (std is zero)".to_string());
+    }
+    Ok(data.iter().map(|x| (x - mean) / std).collect())
+}
+
+#[derive(Serialize, Debug)]
+struct Spectrum {
+    // |X[k]| for every bin k of the padded length; bin k is k / fft_len
+    // cycles per sample
+    magnitudes: Vec<f64>,
+    input_len: usize,
+    fft_len: usize,
+    // Set when the input wasn't already a power of two long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+// Iterative radix-2 Cooley-Tukey FFT of real input, zero-padded up to the
+// next power of two. Padding adds no signal but does interpolate the
+// spectrum, so the response says when it happened.
+fn fft_magnitude(data: &[f64]) -> Spectrum {
+    let n = data.len().next_power_of_two();
+    let mut re = data.to_vec();
+    re.resize(n, 0.0);
+    let mut im = vec![0.0; n];
+
+    // Bit-reversal permutation puts the input in butterfly order
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i
+            .reverse_bits()
+            .checked_shr(usize::BITS - bits)
+            .unwrap_or(0);
+        if i < j {
+            re.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f64::consts::PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len *= 2;
+    }
+
+    Spectrum {
+        magnitudes: re.iter().zip(&im).map(|(r, i)| r.hypot(*i)).collect(),
+        input_len: data.len(),
+        fft_len: n,
+        warning: (n != data.len())
+            .then(|| format!("Input of {} values was zero-padded to {}", data.len(), n)),
+    }
+}
+
+const GROUP_AGGS: &[&str] = &["sum", "mean", "count", "max", "min"];
+
+// Group-by in one pass: running sum, count, min and max per key, with the
+// requested one picked at the end. Keys come back sorted.
+fn group_aggregate(
+    pairs: &[(String, f64)],
+    agg: &str,
+) -> std::result::Result<BTreeMap<String, f64>, String> {
+    if !GROUP_AGGS.contains(&agg) {
+        return Err(format!("agg must be one of: {}", GROUP_AGGS.join(", ")));
+    }
+    if pairs.is_empty() {
+        return Err("pairs must have at least one [key, value] pair".to_string());
+    }
+    if let Some((key, _)) = pairs.iter().find(|(_, x)| !x.is_finite()) {
+        return Err(format!("Value for key {} must be a finite number", key));
+    }
+
+    let mut groups: BTreeMap<&str, (f64, usize, f64, f64)> = BTreeMap::new();
+    for (key, x) in pairs {
+        let (sum, count, min, max) =
+            groups
+                .entry(key)
+                .or_insert((0.0, 0, f64::INFINITY, f64::NEG_INFINITY));
+        *sum += x;
+        *count += 1;
+        *min = min.min(*x);
+        *max = max.max(*x);
+    }
+    Ok(groups
+        .into_iter()
+        .map(|(key, (sum, count, min, max))| {
+            let value = match agg {
+                "sum" => sum,
+                "mean" => sum / count as f64,
+                "count" => count as f64,
+                "max" => max,
+                _ => min,
+            };
+            (key.to_string(), value)
+        })
+        .collect())
+}
+
+// Sorts by value carrying the weights along, then walks the cumulative weight
+// to where it crosses the target. Each value sits at the middle of its own
+// weight, shifted and scaled so the first is at 0% and the last at 100%, with
+// linear interpolation in between; with equal weights that's exactly
+// percentile_sorted. Zero weights carry no mass and are dropped first.
+fn weighted_percentile(
+    data: &[f64],
+    weights: &[f64],
+    percentile: Option<f64>,
+) -> std::result::Result<f64, String> {
+    let Some(percentile) = percentile else {
+        return Err("percentile is required".to_string());
+    };
+    if !(0.0..=100.0).contains(&percentile) {
+        return Err("percentile must be between 0 and 100".to_string());
+    }
+    if weights.len() != data.len() {
+        return Err(format!(
+            "weights has {} values but data has {}",
+            weights.len(),
+            data.len()
+        ));
+    }
+    if data.iter().any(|x| !x.is_finite()) {
+        return Err("Data must contain only finite numbers".to_string());
+    }
+    if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+        return Err("Weights must be finite and non-negative".to_string());
+    }
+
+    let mut weighted: Vec<(f64, f64)> = data
+        .iter()
+        .copied()
+        .zip(weights.iter().copied())
+        .filter(|&(_, w)| w > 0.0)
+        .collect();
+    if weighted.is_empty() {
+        return Err("Total weight must be positive".to_string());
+    }
+    weighted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let (first_weight, (last, last_weight)) = (weighted[0].1, weighted[weighted.len() - 1]);
+    let total: f64 = weighted.iter().map(|&(_, w)| w).sum();
+    let target = percentile / 100.0 * (total - (first_weight + last_weight) / 2.0);
+    let mut position = 0.0;
+    for pair in weighted.windows(2) {
+        let ((x, w), (next, next_w)) = (pair[0], pair[1]);
+        let step = (w + next_w) / 2.0;
+        if target <= position + step {
+            return Ok(x + (next - x) * ((target - position) / step));
+        }
+        position += step;
+    }
+    Ok(last)
+}
+
+// Trapezoidal rule. A single point spans nothing, so integrates to 0.
+fn integrate(y: &[f64], dx: Option<f64>, x: Option<&[f64]>) -> std::result::Result<f64, String> {
+    if y.iter().any(|v| !v.is_finite()) {
+        return Err("Data must contain only finite numbers".to_string());
+    }
+    let area = |(y0, y1): (f64, f64), width: f64| (y0 + y1) / 2.0 * width;
+    match (x, dx) {
+        (Some(_), Some(_)) => Err("Send either x or dx, not both".to_string()),
+        (Some(x), None) => {
+            if x.len() != y.len() {
+                return Err(format!("x has {} values but data has {}", x.len(), y.len()));
+            }
+            if x.iter().any(|v| !v.is_finite()) {
+                return Err("x must contain only finite numbers".to_string());
+            }
+            Ok(y.windows(2)
+                .zip(x.windows(2))
+                .map(|(y, x)| area((y[0], y[1]), x[1] - x[0]))
+                .sum())
+        }
+        (None, dx) => {
+            let dx = dx.unwrap_or(1.0);
+            if !(dx.is_finite() && dx > 0.0) {
+                return Err("dx must be a positive number".to_string());
+            }
+            Ok(y.windows(2).map(|y| area((y[0], y[1]), dx)).sum())
+        }
+    }
+}
+
+fn window_stat(operation: &str, window: &[f64]) -> f64 {
+    let n = window.len() as f64;
+    match operation {
+        "sum" => window.iter().sum(),
+        "mean" => window.iter().sum::<f64>() / n,
+        "max" => window.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        "min" => window.iter().cloned().fold(f64::INFINITY, f64::min),
+        // Population std, as the "std" operation
+        _ => {
+            let mean = window.iter().sum::<f64>() / n;
+            (window.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n).sqrt()
+        }
+    }
+}
+
+// One value per full window, so `data.len() - window + 1` of them; the first
+// covers data[0..window]
+fn rolling(data: &[f64], window: usize, operation: &str) -> std::result::Result<Vec<f64>, String> {
+    if !ROLLING_OPERATIONS.contains(&operation) {
+        return Err(format!(
+            "inner_operation must be one of: {}",
+            ROLLING_OPERATIONS.join(", ")
+        ));
+    }
+    if window == 0 {
+        return Err("window must be at least 1".to_string());
+    }
+    if window > data.len() {
+        return Err(format!(
+            "window of {} is longer than the data ({} values)",
+            window,
+            data.len()
+        ));
+    }
+    if data.iter().any(|x| !x.is_finite()) {
+        return Err("Data must contain only finite numbers".to_string());
+    }
+    Ok(data
+        .windows(window)
+        .map(|values| window_stat(operation, values))
+        .collect())
+}
+
+// Summary stats per named series. Every series has to be usable: one empty or
+// non-finite series fails the whole request, naming the series.
+fn describe_series(
+    series: &BTreeMap<String, Vec<f64>>,
+) -> std::result::Result<BTreeMap<String, SummaryStats>, String> {
+    if series.is_empty() {
+        return Err("series must have at least one named series".to_string());
+    }
+    series
+        .iter()
+        .map(|(name, values)| {
+            if values.is_empty() {
+                return Err(format!("Series {} is empty", name));
+            }
+            if values.iter().any(|x| !x.is_finite()) {
+                return Err(format!("Series {} must contain only finite numbers", name));
+            }
+            Ok((name.clone(), summary_stats(values)))
+        })
+        .collect()
+}
+
+// Large arrays are processed in chunks with a yield back to the runtime between
+// them, so one big request doesn't monopolize the isolate. Yielding bounds the CPU
+// spent per tick but not the total: the work still counts against the request's
+// CPU limit, and each yield adds a little wall-clock latency.
+const COMPUTE_CHUNK_SIZE: usize = 10_000;
+
+async fn yield_to_runtime() {
+    // Delay needs the JS timer API, so native test builds skip the yield
+    #[cfg(target_arch = "wasm32")]
+    Delay::from(std::time::Duration::from_millis(0)).await;
+}
+
+// Folds chunk by chunk in input order, so results match a single-pass fold exactly
+async fn chunked_fold<F>(data: &[f64], init: f64, mut f: F) -> f64
+where
+    F: FnMut(f64, f64) -> f64,
+{
+    let mut acc = init;
+    for (i, chunk) in data.chunks(COMPUTE_CHUNK_SIZE).enumerate() {
+        if i > 0 {
+            yield_to_runtime().await;
+        }
+        acc = chunk.iter().fold(acc, |acc, &x| f(acc, x));
+    }
+    acc
+}
+
+// Population variance
+async fn chunked_variance(data: &[f64]) -> f64 {
+    let n = data.len() as f64;
+    let mean = chunked_fold(data, 0.0, |acc, x| acc + x).await / n;
+    chunked_fold(data, 0.0, |acc, x| acc + (x - mean).powi(2)).await / n
+}
+
+async fn run_compute(
+    input: &ComputeRequest,
+) -> std::result::Result<ComputeValue, (ErrorCode, String)> {
+    // describe and group_agg read `series` / `pairs` and check those themselves
+    if input.data.is_empty() && !matches!(input.operation.as_str(), "describe" | "group_agg") {
+        return Err((
+            ErrorCode::ValidationFailed,
+            "Data array is empty".to_string(),
+        ));
+    }
+    if input.round.is_some_and(|places| places > COMPUTE_MAX_ROUND) {
+        return Err((
+            ErrorCode::ValidationFailed,
+            format!("round must be at most {}", COMPUTE_MAX_ROUND),
+        ));
+    }
+
+    let result = match input.operation.as_str() {
+        "sum" => ComputeValue::Scalar(input.data.iter().sum()),
+        "mean" => ComputeValue::Scalar(input.data.iter().sum::<f64>() / input.data.len() as f64),
+        "max" => ComputeValue::Scalar(input.data.iter().cloned().fold(f64::NEG_INFINITY, f64::max)),
+        "min" => ComputeValue::Scalar(input.data.iter().cloned().fold(f64::INFINITY, f64::min)),
+        // Two passes over the data, so chunk it to keep each tick short
+        "std" => ComputeValue::Scalar(chunked_variance(&input.data).await.sqrt()),
+        "summary" => {
+            if input.data.iter().any(|x| !x.is_finite()) {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Data must contain only finite numbers".to_string(),
+                ));
+            }
+            ComputeValue::Summary(summary_stats(&input.data))
+        }
+        "outliers" => {
+            if input.data.iter().any(|x| !x.is_finite()) {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Data must contain only finite numbers".to_string(),
+                ));
+            }
+            ComputeValue::Outliers(iqr_outliers(&input.data))
+        }
+        "rank" => {
+            if input.data.iter().any(|x| x.is_nan()) {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Data must not contain NaN".to_string(),
+                ));
+            }
+            ComputeValue::Ranking(rank_values(&input.data, input.dense))
+        }
+        "linear_regression" => match linear_regression(&input.data, &input.data2) {
+            Ok(regression) => ComputeValue::Regression(regression),
+            Err(message) => return Err((ErrorCode::ValidationFailed, message.to_string())),
+        },
+        "cumsum" | "diff" => {
+            if input.data.iter().any(|x| !x.is_finite()) {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Data must contain only finite numbers".to_string(),
+                ));
+            }
+            if input.operation == "cumsum" {
+                ComputeValue::Series(cumulative_sum(&input.data))
+            } else if input.data.len() < 2 {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "diff needs at least 2 values".to_string(),
+                ));
+            } else {
+                ComputeValue::Series(first_differences(&input.data))
+            }
+        }
+        "trend" => {
+            if input.data.iter().any(|x| !x.is_finite()) {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Data must contain only finite numbers".to_string(),
+                ));
+            }
+            ComputeValue::Trend(trend(&input.data))
+        }
+        "fft_magnitude" => {
+            if input.data.iter().any(|x| !x.is_finite()) {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Data must contain only finite numbers".to_string(),
+                ));
+            }
+            ComputeValue::Spectrum(fft_magnitude(&input.data))
+        }
+        "zscore" => {
+            if input.data.iter().any(|x| !x.is_finite()) {
+                return Err((
+                    ErrorCode::ValidationFailed,
+                    "Data must contain only finite numbers".to_string(),
+                ));
+            }
+            match z_scores(&input.data, input.sample) {
+                Ok(scores) => ComputeValue::Series(scores),
+                Err(message) => return Err((ErrorCode::ValidationFailed, message)),
+            }
+        }
+        "describe" => match describe_series(&input.series) {
+            Ok(described) => ComputeValue::Described(described),
+            Err(message) => return Err((ErrorCode::ValidationFailed, message)),
+        },
+        "group_agg" => match group_aggregate(&input.pairs, &input.agg) {
+            Ok(groups) => ComputeValue::Grouped(groups),
+            Err(message) => return Err((ErrorCode::ValidationFailed, message)),
+        },
+        "integrate" => match integrate(&input.data, input.dx, input.x.as_deref()) {
+            Ok(area) => ComputeValue::Scalar(area),
+            Err(message) => return Err((ErrorCode::ValidationFailed, message)),
+        },
+        "rolling" => match rolling(&input.data, input.window, &input.inner_operation) {
+            Ok(values) => ComputeValue::Series(values),
+            Err(message) => return Err((ErrorCode::ValidationFailed, message)),
+        },
+        "weighted_percentile" => {
+            match weighted_percentile(&input.data, &input.weights, input.percentile) {
+                Ok(value) => ComputeValue::Scalar(value),
+                Err(message) => return Err((ErrorCode::ValidationFailed, message)),
+            }
+        }
+        _ => {
+            return Err((
+                ErrorCode::UnknownOperation,
+                format!("Unknown operation: {}", input.operation),
+            ));
+        }
+    };
+    // Finite inputs can still overflow, e.g. the sum or mean of values near f64::MAX
+    if !result.is_finite() {
+        return Err((
+            ErrorCode::NonFiniteResult,
+            format!("{}: result is not finite", input.operation),
+        ));
+    }
+    Ok(match input.round {
+        Some(places) => result.rounded(places),
+        None => result,
+    })
+}
+
+// Each operation succeeds or fails on its own; one bad entry doesn't fail the
+// batch. Returns the finished items and the indices left once `out_of_time`
+// says the deadline is close. The clock only moves across I/O, so a run of
+// purely CPU-bound operations can still overshoot; the chunked ones yield and
+// let it advance.
+async fn run_compute_batch(
+    operations: &[ComputeRequest],
+    timings: bool,
+    now: impl Fn() -> f64,
+    out_of_time: impl Fn() -> bool,
+) -> BatchResult<BatchComputeItem> {
+    let mut results = Vec::with_capacity(operations.len());
+    for (index, input) in operations.iter().enumerate() {
+        if out_of_time() {
+            return BatchResult::cut_short(results, (index..operations.len()).collect());
+        }
+        let started = timings.then(&now);
+        let outcome = run_compute(input).await;
+        let elapsed_ms = started.map(|started| (now() - started).max(0.0));
+        results.push(match outcome {
+            Ok(result) => ItemOutcome::ok(
+                index,
+                BatchComputeItem {
+                    operation: input.operation.clone(),
+                    count: input.value_count(),
+                    result,
+                    elapsed_ms,
+                },
+            ),
+            Err((code, message)) => ItemOutcome::err(index, code, message),
+        });
+    }
+    BatchResult::new(results)
+}
+
+async fn handle_compute_batch(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let mut input: ComputeBatchRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    for op in &mut input.operations {
+        op.default_operation(&ctx.data.config.compute_default_operation);
+    }
+    if input.operations.len() > COMPUTE_BATCH_MAX {
+        return error_response(
+            &ctx,
+            ErrorCode::ValidationFailed,
+            format!("At most {} operations per batch", COMPUTE_BATCH_MAX),
+        );
+    }
+    let operations: Vec<&str> = input
+        .operations
+        .iter()
+        .map(|op| op.operation.as_str())
+        .collect();
+    if let Some(denied) = authorize_compute(&req, &ctx, &operations).await? {
+        return Ok(denied);
+    }
+    let Some(_slot) = acquire_compute_slot(&ctx).await else {
+        return overloaded_response(&ctx);
+    };
+
+    let timings = req
+        .url()?
+        .query_pairs()
+        .any(|(name, value)| name == "timings" && value == "true");
+    // Workers only advance Date.now() across I/O, so purely CPU-bound
+    // operations can report 0ms; the yields in chunked operations help.
+    let batch = run_compute_batch(
+        &input.operations,
+        timings,
+        || Date::now().as_millis() as f64,
+        || ctx.data.deadline.nearly_expired(Date::now().as_millis()),
+    )
+    .await;
+    let mut charge = UsageTotals::default();
+    for item in batch.results.iter().filter(|item| item.succeeded()) {
+        charge.add(&UsageTotals {
+            cost: compute_cost(&input.operations[item.index]),
+            operations: 1,
+        });
+    }
+    record_compute_usage(&ctx, &req, charge)?;
+
+    batch_response(&ctx, batch)
+}
+
+async fn handle_compute(mut req: Request, ctx: RouteContext<ReqCtx>) -> Result<Response> {
+    let mut input: ComputeRequest = match parse_json_body(&req.text().await?) {
+        Ok(data) => data,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    input.default_operation(&ctx.data.config.compute_default_operation);
+    if let Some(denied) = authorize_compute(&req, &ctx, &[input.operation.as_str()]).await? {
+        return Ok(denied);
+    }
+    let Some(_slot) = acquire_compute_slot(&ctx).await else {
+        return overloaded_response(&ctx);
+    };
+
+    let result = match run_compute(&input).await {
+        Ok(result) => result,
+        Err((code, message)) => return error_response(&ctx, code, message),
+    };
+    let charge = UsageTotals {
+        cost: compute_cost(&input),
+        operations: 1,
+    };
+    record_compute_usage(&ctx, &req, charge)?;
+
+    respond(
+        &ctx,
+        &ApiResponse {
+            success: true,
+            data: Some(ComputeResult {
+                result,
+                count: input.value_count(),
+                operation: input.operation,
+            }),
+            error: None,
+            code: None,
+        },
+    )
+}
+
+// ============================================
+// TESTS
+// ============================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A live, unverified, untenanted user; tests override what they need
+    fn test_user() -> User {
+        User {
+            id: "u1".to_string(),
+            name: "Ada".to_string(),
+            email: "ada@example.com".to_string(),
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+            deleted_at: None,
+            email_verified_at: None,
+            tenant_id: None,
+        }
+    }
+
+    #[test]
+    fn test_compute_sum() {
+        let data = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+        let sum: f64 = data.iter().sum();
+        assert_eq!(sum, 15.0);
+    }
+
+    #[test]
+    fn test_compute_mean() {
+        let data = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+        let mean = data.iter().sum::<f64>() / data.len() as f64;
+        assert_eq!(mean, 3.0);
+    }
+
+    #[test]
+    fn test_compute_round() {
+        let input = ComputeRequest {
+            data: vec![1.0, 2.0, 2.0],
+            operation: "mean".to_string(),
+            round: Some(2),
+            ..Default::default()
+        };
+        let result = futures::executor::block_on(run_compute(&input))
+            .ok()
+            .unwrap();
+        assert!(matches!(result, ComputeValue::Scalar(mean) if mean.to_string() == "1.67"));
+
+        // Ties go to the even neighbour
+        assert_eq!(round_to(0.125, 2), 0.12);
+        assert_eq!(round_to(0.375, 2), 0.38);
+        assert_eq!(round_to(2.5, 0), 2.0);
+        assert_eq!(round_to(1e300, 15), 1e300);
+    }
+
+    #[test]
+    fn test_compute_registry_matches_dispatch() {
+        let expected = [
+            "sum",
+            "mean",
+            "max",
+            "min",
+            "std",
+            "summary",
+            "outliers",
+            "rank",
+            "linear_regression",
+            "describe",
+            "cumsum",
+            "diff",
+            "zscore",
+            "trend",
+            "fft_magnitude",
+            "group_agg",
+            "weighted_percentile",
+            "rolling",
+            "integrate",
+        ];
+        assert_eq!(COMPUTE_OPERATIONS, expected);
+
+        // Every listed operation reaches a `run_compute` arm; only unlisted
+        // names fall through to UnknownOperation
+        let run = |operation: &str| {
+            futures::executor::block_on(run_compute(&ComputeRequest {
+                data: vec![1.0, 2.0, 3.0],
+                operation: operation.to_string(),
+                data2: vec![2.0, 4.0, 6.0],
+                series: BTreeMap::from([("a".to_string(), vec![1.0, 2.0])]),
+                ..Default::default()
+            }))
+        };
+        for op in COMPUTE_OPERATIONS {
+            assert!(
+                !matches!(run(op), Err((ErrorCode::UnknownOperation, _))),
+                "{} is listed but not dispatched",
+                op
+            );
+        }
+        assert!(matches!(
+            run("median"),
+            Err((ErrorCode::UnknownOperation, _))
+        ));
+    }
+
+    #[test]
+    fn test_zscore_standardizes() {
+        let data = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        let moments = |scores: &[f64], ddof: f64| {
+            let n = scores.len() as f64;
+            let mean = scores.iter().sum::<f64>() / n;
+            let var = scores.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - ddof);
+            (mean, var.sqrt())
+        };
+
+        let scores = z_scores(&data, false).unwrap();
+        let (mean, std) = moments(&scores, 0.0);
+        assert!(mean.abs() < 1e-12);
+        assert!((std - 1.0).abs() < 1e-12);
+        // Population std of this data is exactly 2
+        assert_eq!(scores[0], -1.5);
+
+        let scores = z_scores(&data, true).unwrap();
+        let (mean, std) = moments(&scores, 1.0);
+        assert!(mean.abs() < 1e-12);
+        assert!((std - 1.0).abs() < 1e-12);
+
+        assert!(z_scores(&[3.0, 3.0, 3.0], false).is_err());
+        assert!(z_scores(&[3.0], true).is_err());
+        let constant = futures::executor::block_on(run_compute(&ComputeRequest {
+            data: vec![1.0, 1.0],
+            operation: "zscore".to_string(),
+            ..Default::default()
+        }));
+        assert!(matches!(constant, Err((ErrorCode::ValidationFailed, _))));
+    }
+
+    #[test]
+    fn test_fft_magnitude_peaks_at_signal_bin() {
+        // Five full cycles over 64 samples lands in bin 5 (and its mirror, 59)
+        let data: Vec<f64> = (0..64)
+            .map(|i| (2.0 * std::f64::consts::PI * 5.0 * i as f64 / 64.0).sin())
+            .collect();
+        let spectrum = fft_magnitude(&data);
+        assert_eq!(spectrum.fft_len, 64);
+        assert!(spectrum.warning.is_none());
+        let peak = (0..32)
+            .max_by(|&a, &b| spectrum.magnitudes[a].total_cmp(&spectrum.magnitudes[b]))
+            .unwrap();
+        assert_eq!(peak, 5);
+        assert!((spectrum.magnitudes[5] - 32.0).abs() < 1e-9);
+        assert!((spectrum.magnitudes[59] - 32.0).abs() < 1e-9);
+        assert!(spectrum.magnitudes[3] < 1e-9);
+
+        let padded = fft_magnitude(&data[..60]);
+        assert_eq!((padded.input_len, padded.fft_len), (60, 64));
+        assert!(padded.warning.is_some());
+        // A single value is its own transform
+        assert_eq!(fft_magnitude(&[-2.0]).magnitudes, vec![2.0]);
+    }
+
+    #[test]
+    fn test_group_agg_sums_per_key() {
+        let pairs = vec![
+            ("eu".to_string(), 2.0),
+            ("us".to_string(), 5.0),
+            ("eu".to_string(), 3.5),
+        ];
+        let sums = group_aggregate(&pairs, "sum").unwrap();
+        assert_eq!(
+            sums,
+            BTreeMap::from([("eu".to_string(), 5.5), ("us".to_string(), 5.0)])
+        );
+        assert_eq!(group_aggregate(&pairs, "count").unwrap()["eu"], 2.0);
+        assert_eq!(group_aggregate(&pairs, "mean").unwrap()["eu"], 2.75);
+
+        assert!(group_aggregate(&pairs, "median").is_err());
+        assert!(group_aggregate(&[], "sum").is_err());
+        // Reached through run_compute with no `data` at all
+        let input: ComputeRequest = serde_json::from_str(
+            r#"{"operation": "group_agg", "agg": "max", "pairs": [["a", 1], ["a", 4], ["b", 2]]}"#,
+        )
+        .unwrap();
+        let result = futures::executor::block_on(run_compute(&input)).unwrap();
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            serde_json::json!({ "a": 4.0, "b": 2.0 })
+        );
+        assert_eq!(input.value_count(), 3);
+    }
+
+    #[test]
+    fn test_infinite_result_rejected() {
+        let run = |json: &str| {
+            let input: ComputeRequest = serde_json::from_str(json).unwrap();
+            futures::executor::block_on(run_compute(&input))
+        };
+        // Two finite values whose sum overflows
+        let Err((code, message)) = run(r#"{"operation": "sum", "data": [1e308, 1e308]}"#) else {
+            panic!("overflowing sum should fail");
+        };
+        assert_eq!(code, ErrorCode::NonFiniteResult);
+        assert_eq!(code.status(), 422);
+        assert_eq!(message, "sum: result is not finite");
+        assert!(matches!(
+            run(r#"{"operation": "mean", "data": [1e308, 1e308]}"#),
+            Err((ErrorCode::NonFiniteResult, _))
+        ));
+        assert!(matches!(
+            run(r#"{"operation": "cumsum", "data": [1e308, 1e308]}"#),
+            Err((ErrorCode::NonFiniteResult, _))
+        ));
+
+        assert!(run(r#"{"operation": "sum", "data": [1e308, -1e308]}"#).is_ok());
+    }
+
+    #[test]
+    fn test_integrate_trapezoidal() {
+        assert_eq!(integrate(&[0.0, 1.0, 2.0], Some(1.0), None), Ok(2.0));
+        // dx defaults to 1
+        assert_eq!(integrate(&[0.0, 1.0, 2.0], None, None), Ok(2.0));
+        assert_eq!(integrate(&[0.0, 1.0, 2.0], Some(0.5), None), Ok(1.0));
+        // Uneven spacing: y = x over [0, 1, 3] is 4.5
+        assert_eq!(
+            integrate(&[0.0, 1.0, 3.0], None, Some(&[0.0, 1.0, 3.0])),
+            Ok(4.5)
+        );
+        assert_eq!(integrate(&[5.0], None, None), Ok(0.0));
+
+        assert!(integrate(&[0.0, 1.0], None, Some(&[0.0])).is_err());
+        assert!(integrate(&[0.0, 1.0], Some(0.0), None).is_err());
+        assert!(integrate(&[0.0, 1.0], Some(-1.0), None).is_err());
+        assert!(integrate(&[0.0, 1.0], Some(1.0), Some(&[0.0, 1.0])).is_err());
+
+        let input: ComputeRequest =
+            serde_json::from_str(r#"{"operation": "integrate", "data": [0, 1, 2], "dx": 1}"#)
+                .unwrap();
+        let result = futures::executor::block_on(run_compute(&input)).unwrap();
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            serde_json::json!(2.0)
+        );
+    }
+
+    #[test]
+    fn test_rolling_mean() {
+        assert_eq!(rolling(&[2.0, 4.0, 6.0], 2, "mean"), Ok(vec![3.0, 5.0]));
+        assert_eq!(rolling(&[2.0, 4.0, 6.0], 3, "sum"), Ok(vec![12.0]));
+        assert_eq!(
+            rolling(&[1.0, 5.0, 2.0, 0.0], 2, "max"),
+            Ok(vec![5.0, 5.0, 2.0])
+        );
+
+        assert!(rolling(&[2.0, 4.0], 3, "mean").is_err());
+        assert!(rolling(&[2.0, 4.0], 0, "mean").is_err());
+        assert!(rolling(&[2.0, 4.0], 1, "rank").is_err());
+
+        let input: ComputeRequest = serde_json::from_str(
+            r#"{"operation": "rolling", "data": [2, 4, 6], "window": 2, "inner_operation": "mean"}"#,
+        )
+        .unwrap();
+        let result = futures::executor::block_on(run_compute(&input)).unwrap();
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            serde_json::json!([3.0, 5.0])
+        );
+    }
+
+    #[test]
+    fn test_weighted_percentile_uniform_weights() {
+        let data = vec![7.0, 1.0, 4.0, 10.0, 2.5];
+        let mut sorted = data.clone();
+        sorted.sort_by(f64::total_cmp);
+        let uniform = vec![2.0; data.len()];
+        for p in [0.0, 10.0, 25.0, 50.0, 90.0, 100.0] {
+            let weighted = weighted_percentile(&data, &uniform, Some(p)).unwrap();
+            assert!((weighted - percentile_sorted(&sorted, p / 100.0)).abs() < 1e-12);
+        }
+
+        // Weight on one value pulls the median towards it
+        let heavy = weighted_percentile(&[1.0, 2.0, 3.0], &[1.0, 1.0, 10.0], Some(50.0)).unwrap();
+        assert!(heavy > 2.0 && heavy < 3.0);
+        assert_eq!(weighted_percentile(&[4.0], &[0.5], Some(90.0)), Ok(4.0));
+        // Zero weights drop out
+        let skipped = weighted_percentile(&[1.0, 50.0, 3.0], &[1.0, 0.0, 1.0], Some(50.0));
+        assert_eq!(skipped, Ok(2.0));
+
+        assert!(weighted_percentile(&[1.0, 2.0], &[1.0], Some(50.0)).is_err());
+        assert!(weighted_percentile(&[1.0, 2.0], &[0.0, 0.0], Some(50.0)).is_err());
+        assert!(weighted_percentile(&[1.0, 2.0], &[1.0, -1.0], Some(50.0)).is_err());
+        assert!(weighted_percentile(&[1.0, 2.0], &[1.0, 1.0], Some(101.0)).is_err());
+        assert!(weighted_percentile(&[1.0, 2.0], &[1.0, 1.0], None).is_err());
+    }
+
+    #[test]
+    fn test_compute_default_operation() {
+        let config = Config::default();
+        let mut input: ComputeRequest = serde_json::from_str(r#"{"data": [1, 2, 3, 4]}"#).unwrap();
+        input.default_operation(&config.compute_default_operation);
+        assert_eq!(input.operation, "summary");
+        let Ok(ComputeValue::Summary(stats)) = futures::executor::block_on(run_compute(&input))
+        else {
+            panic!("expected summary stats");
+        };
+        assert_eq!(stats.count, 4);
+
+        // An explicit operation is left alone, unknown ones included
+        let mut input: ComputeRequest =
+            serde_json::from_str(r#"{"data": [1], "operation": "median"}"#).unwrap();
+        input.default_operation(&config.compute_default_operation);
+        assert!(matches!(
+            futures::executor::block_on(run_compute(&input)),
+            Err((ErrorCode::UnknownOperation, _))
+        ));
+
+        let config = Config {
+            compute_default_operation: "median".to_string(),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_trend_increasing_and_flat() {
+        assert_eq!(
+            trend(&[1.0, 2.0, 3.0]),
+            Trend {
+                monotonic_increasing: true,
+                monotonic_decreasing: false,
+                strictly: true,
+                slope_sign: 1,
+            }
+        );
+        // Constant data satisfies both non-strict flags
+        assert_eq!(
+            trend(&[3.0, 3.0, 3.0]),
+            Trend {
+                monotonic_increasing: true,
+                monotonic_decreasing: true,
+                strictly: false,
+                slope_sign: 0,
+            }
+        );
+
+        let non_strict = trend(&[5.0, 5.0, 4.0]);
+        assert!(non_strict.monotonic_decreasing && !non_strict.strictly);
+        assert_eq!(non_strict.slope_sign, -1);
+        // Not monotonic, but still trending up overall
+        let noisy = trend(&[1.0, 3.0, 2.0, 4.0]);
+        assert!(!noisy.monotonic_increasing && !noisy.monotonic_decreasing);
+        assert_eq!(noisy.slope_sign, 1);
+    }
+
+    #[test]
+    fn test_cumsum_and_diff() {
+        assert_eq!(cumulative_sum(&[1.0, 2.0, 3.0]), vec![1.0, 3.0, 6.0]);
+        assert_eq!(first_differences(&[1.0, 3.0, 6.0]), vec![2.0, 3.0]);
+
+        let run = |operation: &str, data: Vec<f64>| {
+            futures::executor::block_on(run_compute(&ComputeRequest {
+                data,
+                operation: operation.to_string(),
+                ..Default::default()
+            }))
+        };
+        let Ok(ComputeValue::Series(sums)) = run("cumsum", vec![1.0, 2.0, 3.0]) else {
+            panic!("expected a series");
+        };
+        assert_eq!(sums, vec![1.0, 3.0, 6.0]);
+        assert_eq!(
+            serde_json::to_string(&ComputeValue::Series(sums)).unwrap(),
+            "[1.0,3.0,6.0]"
+        );
+        assert!(matches!(
+            run("diff", vec![5.0]),
+            Err((ErrorCode::ValidationFailed, _))
+        ));
+        assert!(matches!(
+            run("cumsum", Vec::new()),
+            Err((ErrorCode::ValidationFailed, _))
+        ));
+    }
+
+    #[test]
+    fn test_describe_series() {
+        let input: ComputeRequest = serde_json::from_str(
+            r#"{"operation": "describe", "series": {"b": [10, 20], "a": [1, 2, 3, 4]}}"#,
+        )
+        .unwrap();
+        assert_eq!(input.value_count(), 6);
+        let result = futures::executor::block_on(run_compute(&input))
+            .ok()
+            .unwrap();
+        let ComputeValue::Described(described) = result else {
+            panic!("expected per-series stats");
+        };
+        assert_eq!(described.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(described["a"].mean, 2.5);
+        assert_eq!(described["a"].count, 4);
+        assert_eq!(described["b"].mean, 15.0);
+
+        let mut series = BTreeMap::new();
+        series.insert("ok".to_string(), vec![1.0]);
+        series.insert("empty".to_string(), Vec::new());
+        assert_eq!(
+            describe_series(&series).unwrap_err(),
+            "Series empty is empty"
+        );
+        series.insert("empty".to_string(), vec![f64::INFINITY]);
+        assert!(describe_series(&series).is_err());
+        assert!(describe_series(&BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_compute_cost_is_proportional() {
+        let request = |operation: &str, n: usize| ComputeRequest {
+            data: vec![1.0; n],
+            operation: operation.to_string(),
+            ..Default::default()
+        };
+        // Twice the data, twice the cost
+        assert_eq!(compute_cost(&request("sum", 1_000)), 1_000);
+        assert_eq!(compute_cost(&request("sum", 2_000)), 2_000);
+        assert_eq!(compute_cost(&request("std", 1_000)), 2_000);
+        // Sorting costs more per element as n grows
+        assert_eq!(compute_cost(&request("rank", 1_024)), 1_024 * 11);
+        assert!(compute_cost(&request("summary", 1_000)) > compute_cost(&request("mean", 1_000)));
+
+        let mut totals = UsageTotals::default();
+        for n in [1_000, 2_000] {
+            totals.add(&UsageTotals {
+                cost: compute_cost(&request("sum", n)),
+                operations: 1,
+            });
+        }
+        assert_eq!(
+            totals,
+            UsageTotals {
+                cost: 3_000,
+                operations: 2
+            }
+        );
+
+        // Only an unset key starts from zero
+        assert!(is_missing_storage_value("No such value in storage."));
+        assert!(!is_missing_storage_value(
+            "Durable Object storage operation exceeded timeout"
+        ));
+    }
+
+    #[test]
+    fn test_rank_values() {
+        let ranking = rank_values(&[30.0, 10.0, 20.0], false);
+        assert_eq!(ranking.ranks, vec![3, 1, 2]);
+        let sorted: Vec<(f64, usize)> = ranking.sorted.iter().map(|v| (v.value, v.index)).collect();
+        assert_eq!(sorted, vec![(10.0, 1), (20.0, 2), (30.0, 0)]);
+
+        // Ties: standard skips after the tie, dense doesn't
+        let data = [5.0, 1.0, 5.0, 9.0];
+        assert_eq!(rank_values(&data, false).ranks, vec![2, 1, 2, 4]);
+        assert_eq!(rank_values(&data, true).ranks, vec![2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_chunked_variance_matches_single_pass() {
+        let data: Vec<f64> = (0..COMPUTE_CHUNK_SIZE * 2 + 17)
+            .map(|i| (i as f64 * 0.37).sin() * 100.0)
+            .collect();
+
+        let mean = data.iter().sum::<f64>() / data.len() as f64;
+        let expected = data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / data.len() as f64;
+
+        let chunked = futures::executor::block_on(chunked_variance(&data));
+        assert_eq!(chunked, expected);
+    }
+
+    #[test]
+    fn test_index_head_has_headers_but_no_body() {
+        let (get_headers, get_body) = index_parts(false);
+        let (head_headers, head_body) = index_parts(true);
+
+        assert_eq!(get_body, INDEX_BODY.as_bytes());
+        assert!(head_body.is_empty());
+        assert_eq!(head_headers, get_headers);
+        let content_length = INDEX_BODY.len().to_string();
+        assert!(head_headers
+            .iter()
+            .any(|(name, value)| *name == "Content-Length" && *value == content_length));
+    }
+
+    #[test]
+    fn test_not_found_error_code() {
+        let body =
+            serde_json::to_value(error_body(ErrorCode::UserNotFound, "User not found")).unwrap();
+
+        assert_eq!(body["code"], "user_not_found");
+        assert_eq!(body["error"], "User not found");
+        assert_eq!(ErrorCode::UserNotFound.status(), 404);
+    }
+
+    #[test]
+    fn test_subrequest_limit_error_detection() {
+        assert!(is_subrequest_limit_error(
+            "Error: Too many subrequests by single Worker invocation."
+        ));
+        assert!(!is_subrequest_limit_error(
+            "KV GET failed: 500 Internal Error"
+        ));
+    }
+
+    #[test]
+    fn test_summary_stats() {
+        let stats = summary_stats(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+
+        assert_eq!(
+            stats,
+            SummaryStats {
+                count: 5,
+                min: 1.0,
+                max: 5.0,
+                mean: 3.0,
+                median: 3.0,
+                std: 2.0_f64.sqrt(),
+                q1: 2.0,
+                q3: 4.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_download_content_disposition() {
+        assert_eq!(content_disposition("reports/q1.pdf", false), None);
+        assert_eq!(
+            content_disposition("reports/q1.pdf", true).as_deref(),
+            Some("attachment; filename=\"q1.pdf\"")
+        );
+        assert_eq!(
+            content_disposition("odd\"name.txt", true).as_deref(),
+            Some("attachment; filename=\"odd\\\"name.txt\"")
+        );
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_webhook_retry_reuses_event_id() {
+        let first = next_delivery(None);
+        assert_eq!(first.attempts, 1);
+
+        // A retry loads the persisted record and keeps its id
+        let persisted: DeliveryRecord =
+            serde_json::from_str(&serde_json::to_string(&first).unwrap()).unwrap();
+        let retry = next_delivery(Some(persisted));
+
+        assert_eq!(retry.event_id, first.event_id);
+        assert_eq!(retry.attempts, 2);
+
+        // A client retry of the same request lands on the same record; another
+        // request for the same resource gets its own
+        assert_eq!(
+            webhook_record_key("user.updated", "u1", "req-1"),
+            "webhook:user.updated:u1:req-1"
+        );
+        assert_ne!(
+            webhook_record_key("user.updated", "u1", "req-1"),
+            webhook_record_key("user.updated", "u1", "req-2")
+        );
+    }
+
+    #[test]
+    fn test_request_id_flows_into_webhook() {
+        // POST /api/users arrived with these; the delivery runs after the response
+        let correlation = Correlation {
+            request_id: "req-7f3c".to_string(),
+            trace_id: trace_id_from(Some(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )),
+        };
+        let event = WebhookEvent {
+            event_id: "e1".to_string(),
+            delivery_attempt: 1,
+            event_type: "user.created".to_string(),
+            occurred_at: "2024-06-01T00:00:00+00:00".to_string(),
+            data: serde_json::json!({ "id": "u1" }),
+            correlation: correlation.clone(),
+        };
+        let payload = serde_json::to_value(&event).unwrap();
+        assert_eq!(payload["request_id"], "req-7f3c");
+        assert_eq!(payload["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(
+            correlation.log_fields(),
+            "req=req-7f3c trace=4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+
+        // Letters from before the ids were recorded still load
+        let old = r#"{"event_id":"e0","event_type":"user.deleted","occurred_at":"t0",
+            "failed_at":"t1","attempts":3,"last_error":"got 500","data":{}}"#;
+        let letter: DeadLetter = serde_json::from_str(old).unwrap();
+        assert_eq!(letter.correlation, Correlation::default());
+
+        assert_eq!(trace_id_from(None), None);
+        let zeros = format!("00-{}-00f067aa0ba902b7-01", "0".repeat(32));
+        assert_eq!(trace_id_from(Some(&zeros)), None);
+        assert_eq!(trace_id_from(Some("00-XYZ-00f067aa0ba902b7-01")), None);
+        let no_trace = Correlation {
+            request_id: "req-1".to_string(),
+            trace_id: None,
+        };
+        assert_eq!(no_trace.log_fields(), "req=req-1");
+        assert!(serde_json::to_value(&no_trace)
+            .unwrap()
+            .get("trace_id")
+            .is_none());
+    }
+
+    #[test]
+    fn test_exhausted_webhook_lands_in_dlq() {
+        // Every attempt times out: 3 x 5s plus 0.5s and 1s of backoff, which
+        // still leaves the default background budget room for the DLQ write
+        let config = Config {
+            webhook_url: Some("https://hooks.example.com".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(webhook_worst_case_ms(config.webhook_timeout_ms), 16_500);
+        assert!(config.validate().is_ok());
+        let slow = Config {
+            webhook_timeout_ms: 10_000,
+            ..config
+        };
+        assert!(slow
+            .validate()
+            .unwrap_err()
+            .starts_with("WEBHOOK_TIMEOUT_MS"));
+
+        // The last persisted record is what gets dead-lettered
+        let mut record = next_delivery(None);
+        while record.attempts < WEBHOOK_MAX_ATTEMPTS {
+            record = next_delivery(Some(record));
+        }
+        let letter = DeadLetter {
+            event_id: record.event_id.clone(),
+            event_type: "user.deleted".to_string(),
+            occurred_at: "2024-06-01T00:00:00+00:00".to_string(),
+            failed_at: "2024-06-01T00:00:17+00:00".to_string(),
+            attempts: record.attempts,
+            last_error: "timed out after 5000ms".to_string(),
+            data: serde_json::json!({ "id": "u1" }),
+            correlation: Correlation::default(),
+        };
+        assert_eq!(
+            letter.key(),
+            format!("dlq:2024-06-01T00:00:17+00:00:{}", record.event_id)
+        );
+        assert_eq!(letter.attempts, 3);
+        let stored: DeadLetter =
+            serde_json::from_str(&serde_json::to_string(&letter).unwrap()).unwrap();
+        assert_eq!(stored, letter);
+        assert_eq!(KeySpace::parse("dlq"), None);
+    }
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(parse_byte_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            parse_byte_range(Some("bytes=0-9"), 100),
+            ByteRange::Partial(0, 9)
+        );
+        assert_eq!(
+            parse_byte_range(Some("bytes=90-"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            parse_byte_range(Some("bytes=-10"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            parse_byte_range(Some("bytes=50-500"), 100),
+            ByteRange::Partial(50, 99)
+        );
+        assert_eq!(
+            parse_byte_range(Some("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(
+            parse_byte_range(Some("bytes=0-1,5-6"), 100),
+            ByteRange::Full
+        );
+
+        // Metadata claiming 100 bytes for a 10-byte value can't index past it
+        let bytes = [7u8; 10];
+        assert_eq!(slice_range(&bytes, 2, 4), Some(&bytes[2..5]));
+        assert_eq!(slice_range(&bytes, 0, 9).map(<[u8]>::len), Some(10));
+        assert_eq!(slice_range(&bytes, 90, 99), None);
+        assert_eq!(slice_range(&bytes, 5, 10), None);
+    }
+
+    #[test]
+    fn test_upload_length_checks() {
+        let max = 1024;
+        // Strict mode: a plain upload with no Content-Length gets 411
+        let err = check_upload_length(None, false, max, true).unwrap_err();
+        assert_eq!(err.0, ErrorCode::LengthRequired);
+        assert_eq!(err.0.status(), 411);
+
+        // Chunked uploads are still accepted and limited while streaming
+        assert_eq!(
+            check_upload_length(None, true, max, true),
+            Ok(UploadLength::Streamed)
+        );
+        assert_eq!(
+            check_upload_length(None, false, max, false),
+            Ok(UploadLength::Streamed)
+        );
+        assert_eq!(
+            check_upload_length(Some("10"), false, max, true),
+            Ok(UploadLength::Declared(10))
+        );
+        assert_eq!(
+            check_upload_length(Some("2048"), false, max, true)
+                .unwrap_err()
+                .0,
+            ErrorCode::PayloadTooLarge
+        );
+    }
+
+    #[test]
+    fn test_text_upload_strips_utf8_bom() {
+        let mut bytes = vec![0xEF, 0xBB, 0xBF];
+        bytes.extend_from_slice(b"hello");
+
+        let upload = normalize_text_upload("text/plain", bytes, "utf-8");
+
+        assert_eq!(upload.bytes, b"hello");
+        assert_eq!(upload.charset.as_deref(), Some("utf-8"));
+        assert_eq!(upload.content_type, "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn test_text_upload_keeps_declared_charset() {
+        let upload =
+            normalize_text_upload("text/csv; charset=ISO-8859-1", b"a,b".to_vec(), "utf-8");
+
+        assert_eq!(upload.content_type, "text/csv; charset=ISO-8859-1");
+        assert_eq!(upload.charset.as_deref(), Some("iso-8859-1"));
+    }
+
+    #[test]
+    fn test_restore_rejects_user_that_is_not_deleted() {
+        let user = test_user();
+
+        let outcome = check_restorable(Some(&user));
+        assert_eq!(outcome, Err(ErrorCode::UserNotDeleted));
+        assert_eq!(ErrorCode::UserNotDeleted.status(), 409);
+
+        assert_eq!(check_restorable(None), Err(ErrorCode::UserNotFound));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    // Returns Pending once so buffered futures genuinely overlap
+    struct YieldOnce(bool);
+
+    impl std::future::Future for YieldOnce {
+        type Output = ();
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<()> {
+            if self.0 {
+                std::task::Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_bounded_caps_in_flight() {
+        use std::cell::Cell;
+
+        // 50 items, limit 6: never more than 6 running, all 50 done, order kept
+        let in_flight = Cell::new(0);
+        let peak = Cell::new(0);
+
+        let results = futures::executor::block_on(map_bounded(0..50, 6, |i| {
+            let (in_flight, peak) = (&in_flight, &peak);
+            async move {
+                in_flight.set(in_flight.get() + 1);
+                peak.set(peak.get().max(in_flight.get()));
+                YieldOnce(false).await;
+                in_flight.set(in_flight.get() - 1);
+                i * 2
+            }
+        }));
+
+        assert_eq!(results, (0..50).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(peak.get(), 6);
+        assert_eq!(in_flight.get(), 0);
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        assert_eq!(negotiate_format(None), Format::Json);
+        assert_eq!(negotiate_format(Some("*/*")), Format::Json);
+        assert_eq!(
+            negotiate_format(Some("application/msgpack")),
+            Format::MessagePack
+        );
+        assert_eq!(
+            negotiate_format(Some("application/json;q=0.5, application/x-msgpack")),
+            Format::MessagePack
+        );
+        assert_eq!(negotiate_format(Some("text/csv")), Format::Json);
+    }
+
+    #[test]
+    fn test_json_format_matches_serde_json() {
+        let body = error_body(ErrorCode::NotFound, "Not found");
+        assert_eq!(
+            Format::Json.serialize(&body).unwrap(),
+            serde_json::to_vec(&body).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_list_query_rejects_invalid_numbers() {
+        let parse = |query: &str| {
+            parse_list_query(
+                &Url::parse(&format!("https://x/{}", query)).unwrap(),
+                10_000,
+            )
+        };
+
+        assert_eq!(parse(""), Ok(Paginator { page: 1, limit: 10 }));
+        assert_eq!(
+            parse("?page=3&limit=500"),
+            Ok(Paginator {
+                page: 3,
+                limit: 100
+            })
+        );
+
+        let err = parse("?limit=-5").unwrap_err();
+        assert!(err.contains("limit"));
+        let err = parse("?limit=abc").unwrap_err();
+        assert!(err.contains("limit"));
+        assert!(parse("?page=0").unwrap_err().contains("page"));
+    }
+
+    #[test]
+    fn test_strict_query_params() {
+        let names = |query: &str| -> Vec<String> {
+            Url::parse(&format!("https://x/api/users{}", query))
+                .unwrap()
+                .query_pairs()
+                .map(|(name, _)| name.into_owned())
+                .collect()
+        };
+        // The typo is named so the client can fix it
+        let typo = names("?limt=10");
+        assert_eq!(unknown_query_param("/api/users", &typo), Some("limt"));
+        assert_eq!(
+            unknown_query_param("/api/users", &names("?page=2&limit=10&pretty=true")),
+            None
+        );
+        // The cursor route takes `cursor`, the offset one doesn't
+        assert_eq!(
+            unknown_query_param("/api/users/cursor", &names("?cursor=x")),
+            None
+        );
+        assert_eq!(
+            unknown_query_param("/api/users", &names("?cursor=x")),
+            Some("cursor")
+        );
+        assert_eq!(
+            unknown_query_param("/api/users/verify", &names("?token=t")),
+            None
+        );
+        assert_eq!(
+            unknown_query_param("/api/users/u1", &names("?page=1")),
+            Some("page")
+        );
+        // Unlisted routes are left alone
+        assert_eq!(
+            unknown_query_param("/api/files/a.txt", &names("?download=true")),
+            None
+        );
+
+        assert!(!Config::default().strict_query_params);
+    }
+
+    #[test]
+    fn test_paginator() {
+        let meta = |page, limit, total| {
+            let r = Paginator::new(page, limit).build(Vec::<()>::new(), total);
+            (r.page, r.limit, r.total_pages, r.has_next, r.has_prev)
+        };
+
+        assert_eq!(meta(1, 10, 0), (1, 10, 0, false, false));
+        assert_eq!(meta(1, 10, 10), (1, 10, 1, false, false));
+        assert_eq!(meta(1, 10, 11), (1, 10, 2, true, false));
+        assert_eq!(meta(2, 10, 11), (2, 10, 2, false, true));
+        // Past the end: no next page, but the previous one exists
+        assert_eq!(meta(5, 10, 11), (5, 10, 2, false, true));
+        // Clamped inputs
+        assert_eq!(meta(0, 10, 25), (1, 10, 3, true, false));
+        assert_eq!(meta(1, 0, 3), (1, 1, 3, true, false));
+        assert_eq!(meta(1, 1000, 250), (1, 100, 3, true, false));
+
+        assert_eq!(Paginator::new(1, 10).offset(), 0);
+        assert_eq!(Paginator::new(3, 25).offset(), 50);
+        assert_eq!(Paginator::new(0, 25).offset(), 0);
+        assert_eq!(Paginator::new(u32::MAX, 100).offset(), u32::MAX);
+    }
+
+    #[test]
+    fn test_list_query_rejects_deep_page() {
+        let url = Url::parse("https://x/?page=100000&limit=100").unwrap();
+        let err = parse_list_query(&url, 10_000).unwrap_err();
+        assert!(err.starts_with("page too deep"));
+        assert!(err.contains(USER_CURSOR_ENDPOINT));
+
+        // The last page within the limit is still served
+        let url = Url::parse("https://x/?page=101&limit=100").unwrap();
+        assert!(parse_list_query(&url, 10_000).is_ok());
+
+        let cursor = encode_user_cursor("2024-01-01T00:00:00+00:00", "u1");
+        assert_eq!(
+            decode_user_cursor(&cursor),
+            Some(("2024-01-01T00:00:00+00:00".to_string(), "u1".to_string()))
+        );
+        assert_eq!(decode_user_cursor("zz"), None);
+    }
+
+    #[test]
+    fn test_access_log_json_fields() {
+        let entry = AccessLog {
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            request_id: "8a1b2c3d4e5f-AMS".to_string(),
+            method: "GET".to_string(),
+            path: "/api/users".to_string(),
+            status: 200,
+            duration_ms: 12,
+            country: Some("NL".to_string()),
+            subject: Some("admin".to_string()),
+        };
+
+        let line = entry.format(LogFormat::Json);
+        assert!(!line.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        for field in [
+            "timestamp",
+            "request_id",
+            "method",
+            "path",
+            "status",
+            "duration_ms",
+            "country",
+            "subject",
+        ] {
+            assert!(json.get(field).is_some(), "missing {}", field);
+        }
+        assert_eq!(json["status"], 200);
+
+        assert_eq!(
+            entry.format(LogFormat::Text),
+            "GET /api/users 200 12ms req=8a1b2c3d4e5f-AMS subject=admin"
+        );
+    }
+
+    #[test]
+    fn test_unsampled_request_logs_minimal_line() {
+        let mut entry = AccessLog {
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            request_id: String::new(),
+            method: "GET".to_string(),
+            path: "/api/users".to_string(),
+            status: 200,
+            duration_ms: 12,
+            country: Some("NL".to_string()),
+            subject: None,
+        };
+        // Some request id that falls outside a 1-in-100 sample
+        entry.request_id = (0..)
+            .map(|n| format!("req-{}", n))
+            .find(|id| !fnv1a_64(id.as_bytes()).is_multiple_of(100))
+            .unwrap();
+        assert!(!log_in_full(&entry, 100, 1000));
+        assert_eq!(entry.format_minimal(LogFormat::Text), "GET /api/users 200");
+        let json: serde_json::Value =
+            serde_json::from_str(&entry.format_minimal(LogFormat::Json)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "method": "GET", "path": "/api/users", "status": 200 })
+        );
+
+        // Rate 1, errors and slow requests are always logged in full
+        assert!(log_in_full(&entry, 1, 1000));
+        entry.duration_ms = 1000;
+        assert!(log_in_full(&entry, 100, 1000));
+        entry.duration_ms = 12;
+        entry.status = 500;
+        assert!(log_in_full(&entry, 100, 1000));
+    }
+
+    #[test]
+    fn test_cors_credentials_reject_wildcard_origin() {
+        let config = Config {
+            cors_allow_credentials: true,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            cors_allow_credentials: true,
+            cors_allowed_origins: vec!["https://app.example.com".to_string()],
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            allowed_origin(&config, Some("https://app.example.com")).as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(allowed_origin(&config, Some("https://evil.example")), None);
+        assert_eq!(ErrorCode::OriginNotAllowed.status(), 403);
+    }
+
+    #[test]
+    fn test_linear_regression_recovers_line() {
+        let x = [0.0, 1.0, 2.0, 3.0, 4.0];
+        let y: Vec<f64> = x.iter().map(|x| 2.0 * x + 1.0).collect();
+
+        let fit = linear_regression(&x, &y).unwrap();
+        assert!((fit.slope - 2.0).abs() < 1e-12);
+        assert!((fit.intercept - 1.0).abs() < 1e-12);
+        assert!((fit.r_squared - 1.0).abs() < 1e-12);
+
+        assert!(linear_regression(&[1.0, 1.0], &[2.0, 3.0]).is_err());
+        assert!(linear_regression(&[1.0, 2.0], &[2.0]).is_err());
+    }
+
+    #[test]
+    fn test_iqr_outliers_flags_single_outlier() {
+        let data = [10.0, 12.0, 11.0, 13.0, 12.0, 95.0, 11.0, 10.0, 12.0];
+        let result = iqr_outliers(&data);
+
+        assert_eq!(result.indices, vec![5]);
+        assert_eq!(result.values, vec![95.0]);
+        assert_eq!(result.iqr, result.q3 - result.q1);
+        assert!(data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 5)
+            .all(|(_, &x)| x >= result.lower_bound && x <= result.upper_bound));
+    }
+
+    #[test]
+    fn test_user_list_order_has_tiebreaker() {
+        // Two users inserted in the same millisecond share created_at. Sorting on
+        // created_at alone lets SQLite return them in either order, so one can
+        // show up on both page 1 and page 2 (and the other on neither). With id
+        // as the last key the pair always comes back in the same order.
+        let keys: Vec<&str> = USER_LIST_ORDER.split(", ").collect();
+        assert_eq!(keys.first(), Some(&"created_at DESC"));
+        assert_eq!(keys.last(), Some(&"id DESC"));
+    }
+
+    #[test]
+    fn test_email_verification_token() {
+        let keys = SigningKeys::new("secret", None);
+        let now = 1_700_000_000;
+        let token = issue_email_token(&keys, "u1", "ada@example.com", now + 3600);
+
+        // Happy path
+        assert_eq!(email_token_user(&token), Some("u1"));
+        assert_eq!(
+            check_email_token(&keys, &token, "ada@example.com", now),
+            Ok(())
+        );
+
+        assert_eq!(
+            check_email_token(&keys, &token, "ada@example.com", now + 3601),
+            Err(EmailTokenError::Expired)
+        );
+        // Token for a previous address, or with a bumped expiry
+        assert_eq!(
+            check_email_token(&keys, &token, "new@example.com", now),
+            Err(EmailTokenError::Invalid)
+        );
+        let tampered = token.replacen(&(now + 3600).to_string(), &(now + 99_999).to_string(), 1);
+        assert_eq!(
+            check_email_token(&keys, &tampered, "ada@example.com", now),
+            Err(EmailTokenError::Invalid)
+        );
+        // A missing SIGNING_SECRET is a server-side gap, not a client error
+        assert_eq!(ErrorCode::NotConfigured.status(), 500);
+    }
+
+    #[test]
+    fn test_debug_echo_gate_and_redaction() {
+        let mut config = Config::default();
+        assert_eq!(debug_echo_denied(&config).map(|c| c.status()), Some(404));
+        config.debug_echo = true;
+        assert_eq!(debug_echo_denied(&config), None);
+
+        let headers = redacted_headers(
+            [
+                ("Authorization", "Bearer secret"),
+                ("X-API-Key", "k"),
+                ("Content-Type", "application/json"),
+            ]
+            .into_iter()
+            .map(|(n, v)| (n.to_string(), v.to_string())),
+        );
+        assert_eq!(headers["authorization"], "[redacted]");
+        assert_eq!(headers["x-api-key"], "[redacted]");
+        assert_eq!(headers["content-type"], "application/json");
+    }
+
+    #[test]
+    fn test_user_counter_delta_and_drift() {
+        assert_eq!(apply_count_delta(10, 1), 11);
+        assert_eq!(apply_count_delta(10, -3), 7);
+        // A lost create followed by its delete can't go negative
+        assert_eq!(apply_count_delta(0, -1), 0);
+
+        assert_eq!(count_drift(Some(12), 10), Some(2));
+        assert_eq!(count_drift(Some(8), 10), Some(-2));
+        assert_eq!(count_drift(Some(10), 10), Some(0));
+        assert_eq!(count_drift(None, 10), None);
+    }
+
+    #[test]
+    fn test_route_cache_control() {
+        let config = Config::from_lookup(|name| {
+            (name == "ROUTE_CACHE_CONTROL").then(|| {
+                "/=public, max-age=3600; /api/users/:id=private, max-age=30; \
+                 /api/users/cursor=no-store; /api/files/*path=public, max-age=60"
+                    .to_string()
+            })
+        });
+        let rules = &config.route_cache_control;
+
+        assert_eq!(
+            cache_control_for(rules, "/", 200, None),
+            "public, max-age=3600"
+        );
+        assert_eq!(
+            cache_control_for(rules, "/api/users/abc", 200, None),
+            "private, max-age=30"
+        );
+        // The literal route beats the :id pattern
+        assert_eq!(
+            cache_control_for(rules, "/api/users/cursor", 200, None),
+            "no-store"
+        );
+        assert_eq!(
+            cache_control_for(rules, "/api/files/a/b.png", 200, Some("private")),
+            "public, max-age=60"
+        );
+        // Unconfigured: the handler's own header, else no-store
+        assert_eq!(cache_control_for(rules, "/health", 200, None), "no-store");
+        assert_eq!(
+            cache_control_for(rules, "/api/users/abc/events", 200, Some("no-cache")),
+            "no-cache"
+        );
+        assert_eq!(
+            cache_control_for(rules, "/api/users/missing", 404, None),
+            "no-store"
+        );
+    }
+
+    #[test]
+    fn test_upload_digest_streams_chunks() {
+        use futures::StreamExt;
+
+        let chunks = vec![b"hello ".to_vec(), b"wor".to_vec(), b"ld".to_vec()];
+        let digest = UploadDigest::default();
+        let stream = digest.tap(
+            futures::stream::iter(chunks.clone().into_iter().map(Ok)),
+            64,
+        );
+        let passed: Vec<Vec<u8>> = futures::executor::block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .map(|chunk| chunk.unwrap())
+            .collect();
+
+        // Chunks pass through untouched and the digest matches a one-shot hash
+        assert_eq!(passed, chunks);
+        assert_eq!(digest.bytes(), 11);
+        assert!(!digest.overflowed());
+        assert_eq!(hex(&digest.finish()), sha256_hex("hello world"));
+
+        let digest = UploadDigest::default();
+        let stream = digest.tap(futures::stream::iter(chunks.into_iter().map(Ok)), 8);
+        let results = futures::executor::block_on(stream.collect::<Vec<_>>());
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        // Reported as too large, not as a Content-Length mismatch
+        assert!(digest.overflowed());
+    }
+
+    #[test]
+    fn test_d1_locked_retry() {
+        use std::cell::{Cell, RefCell};
+
+        // Matched case-insensitively on "database is locked" or SQLITE_BUSY
+        let locked = "D1_ERROR: database is locked: SQLITE_BUSY";
+        assert_eq!(classify_d1_error(locked), D1ErrorKind::Locked);
+        assert_eq!(classify_d1_error("SQLITE_BUSY"), D1ErrorKind::Locked);
+        assert_eq!(
+            classify_d1_error("D1_ERROR: UNIQUE constraint failed: users.email"),
+            D1ErrorKind::Other
+        );
+
+        // Locked twice, then succeeds on the third attempt after two backoffs
+        let attempts = Cell::new(0);
+        let slept = RefCell::new(Vec::new());
+        let op = || {
+            attempts.set(attempts.get() + 1);
+            let n = attempts.get();
+            async move {
+                if n < 3 {
+                    Err(Error::RustError(locked.into()))
+                } else {
+                    Ok(n)
+                }
+            }
+        };
+        let sleep = |ms| {
+            slept.borrow_mut().push(ms);
+            async {}
+        };
+        let result = futures::executor::block_on(retry_locked(2, op, sleep));
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(*slept.borrow(), vec![50, 100]);
+
+        // Out of retries: the locked error surfaces (fetch maps it to a 503)
+        attempts.set(0);
+        let result = futures::executor::block_on(retry_locked(1, op, |_| async {}));
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 2);
+
+        // Anything else is never retried
+        attempts.set(0);
+        let failing = || {
+            attempts.set(attempts.get() + 1);
+            async { Err::<(), _>(Error::RustError("no such table: users".into())) }
+        };
+        assert!(futures::executor::block_on(retry_locked(2, failing, |_| async {})).is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_compute_slot_lifecycle() {
+        use std::cell::RefCell;
+
+        let table = RefCell::new(SlotTable::default());
+        let acquire = |now| {
+            let table = &table;
+            let lease = table.borrow_mut().acquire(now, 2)?;
+            Some(SlotGuard::new(move || table.borrow_mut().release(lease)))
+        };
+
+        // Up to the limit, then shed; dropping a guard frees its slot
+        let first = acquire(0.0).unwrap();
+        let second = acquire(0.0).unwrap();
+        assert!(acquire(0.0).is_none());
+        drop(first);
+        assert_eq!(table.borrow().leases.len(), 1);
+
+        // The error path releases too: `?` drops the guard on the way out
+        let failing = || -> std::result::Result<(), &str> {
+            let _slot = acquire(0.0).unwrap();
+            assert_eq!(table.borrow().leases.len(), 2);
+            Err("compute failed")?;
+            Ok(())
+        };
+        assert!(failing().is_err());
+        assert_eq!(table.borrow().leases.len(), 1);
+
+        // A lost release expires instead of holding the slot forever
+        std::mem::forget(second);
+        let _third = acquire(COMPUTE_SLOT_TTL_MS).unwrap();
+        let _fourth = acquire(COMPUTE_SLOT_TTL_MS).unwrap();
+        assert_eq!(table.borrow().leases.len(), 2);
+    }
+
+    #[test]
+    fn test_batch_part_key() {
+        assert_eq!(
+            batch_part_key("file", "report.pdf"),
+            Some("report.pdf".to_string())
+        );
+        // Client-side paths are reduced to the base name
+        assert_eq!(
+            batch_part_key("file", "C:\\Users\\ada\\notes.txt"),
+            Some("notes.txt".to_string())
+        );
+        assert_eq!(
+            batch_part_key("file", "../../etc/passwd"),
+            Some("passwd".to_string())
+        );
+        // No filename: the field name is the key
+        assert_eq!(batch_part_key("avatar", ""), Some("avatar".to_string()));
+        assert_eq!(batch_part_key("avatar", ".."), Some("avatar".to_string()));
+        assert_eq!(batch_part_key("", ""), None);
+    }
+
+    #[test]
+    fn test_download_content_type_sniffing() {
+        assert_eq!(mime_from_extension("logo.PNG"), Some("image/png"));
+        assert_eq!(download_content_type(None, "logo.png", true), "image/png");
+        assert_eq!(
+            download_content_type(Some("application/octet-stream".into()), "logo.png", true),
+            "image/png"
+        );
+        // Off by default, and never overrides a specific stored type
+        assert_eq!(
+            download_content_type(None, "logo.png", false),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            download_content_type(Some("image/webp".into()), "logo.png", true),
+            "image/webp"
+        );
+        assert_eq!(
+            download_content_type(None, "README", true),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn test_user_cache_coalescing() {
+        use std::cell::{Cell, RefCell};
+
+        let cache: RefCell<Option<&str>> = RefCell::new(None);
+        let lock: RefCell<Option<String>> = RefCell::new(None);
+        let d1_queries = Cell::new(0);
+        let now = 1_000.0;
+
+        let miss = || async {
+            if lock_is_live(lock.borrow().as_deref(), now) {
+                let waited = wait_for_fill(|| async { *cache.borrow() }, |_| YieldOnce(false), 500);
+                if let Some(user) = waited.await {
+                    return user;
+                }
+            } else {
+                *lock.borrow_mut() = Some((now + 5_000.0).to_string());
+            }
+            d1_queries.set(d1_queries.get() + 1);
+            // The D1 round trip
+            YieldOnce(false).await;
+            *cache.borrow_mut() = Some("ada");
+            *lock.borrow_mut() = None;
+            "ada"
+        };
+
+        // A fills, B waits on A's lock and is served from the cache
+        let (a, b) = futures::executor::block_on(futures::future::join(miss(), miss()));
+        assert_eq!((a, b), ("ada", "ada"));
+        assert_eq!(d1_queries.get(), 1);
+
+        // A lock nobody fills: the wait gives up at the deadline
+        let polls = Cell::new(0);
+        let waited = futures::executor::block_on(wait_for_fill(
+            || async {
+                polls.set(polls.get() + 1);
+                None::<()>
+            },
+            |_| async {},
+            120,
+        ));
+        assert_eq!(waited, None);
+        assert_eq!(polls.get(), 3);
+
+        assert!(lock_is_live(Some("2000"), now));
+        assert!(!lock_is_live(Some("999"), now));
+        assert!(!lock_is_live(None, now));
+    }
+
+    #[test]
+    fn test_base64_json_upload() {
+        assert_eq!(
+            upload_encoding("application/json; charset=utf-8", true),
+            UploadEncoding::Base64Json
+        );
+        // Off by default, so JSON files are still stored as sent
+        assert_eq!(
+            upload_encoding("application/json", Config::default().base64_json_uploads),
+            UploadEncoding::Raw
+        );
+        assert_eq!(
+            upload_encoding("multipart/form-data; boundary=x", true),
+            UploadEncoding::Multipart
+        );
+        assert_eq!(upload_encoding("image/png", true), UploadEncoding::Raw);
+
+        let body = br#"{"data_base64": "aGVsbG8gd29ybGQ=", "content_type": "text/plain"}"#;
+        assert_eq!(
+            decode_base64_upload(body, 1024),
+            Ok(("text/plain".to_string(), b"hello world".to_vec()))
+        );
+        let untyped = decode_base64_upload(br#"{"data_base64": "AAEC"}"#, 1024).unwrap();
+        assert_eq!(
+            untyped,
+            ("application/octet-stream".to_string(), vec![0, 1, 2])
+        );
+
+        let invalid = decode_base64_upload(br#"{"data_base64": "not base64!"}"#, 1024);
+        assert_eq!(invalid.unwrap_err().0, ErrorCode::ValidationFailed);
+        assert_eq!(ErrorCode::ValidationFailed.status(), 400);
+        // The limit is on the decoded bytes
+        let large = decode_base64_upload(br#"{"data_base64": "aGVsbG8gd29ybGQ="}"#, 5);
+        assert_eq!(large.unwrap_err().0, ErrorCode::PayloadTooLarge);
+        assert_eq!(
+            decode_base64_upload(br#"{"data": "AAEC"}"#, 1024)
+                .unwrap_err()
+                .0,
+            ErrorCode::UnknownField
+        );
+    }
+
+    #[test]
+    fn test_upload_type_allowlist() {
+        let mut config = Config::from_lookup(|name| {
+            (name == "ALLOWED_UPLOAD_TYPES").then(|| "image/*, application/pdf".to_string())
+        });
+
+        let exe = "application/x-msdownload";
+        let error = upload_type_error(&config, exe, None).unwrap();
+        assert!(error.contains(exe));
+        assert_eq!(ErrorCode::UnsupportedMediaType.status(), 415);
+        assert_eq!(upload_type_error(&config, "image/png", None), None);
+        assert_eq!(
+            upload_type_error(&config, "Application/PDF; x=1", None),
+            None
+        );
+
+        // Sniffing catches an executable declared as an image
+        let pe = b"MZ\x90\x00\x03";
+        assert_eq!(upload_type_error(&config, "image/png", Some(pe)), None);
+        config.sniff_upload_types = true;
+        assert!(upload_type_error(&config, "image/png", Some(pe)).is_some());
+        assert_eq!(
+            upload_type_error(&config, "image/png", Some(b"\x89PNG\r\n\x1a\n...")),
+            None
+        );
+
+        // Unset allows everything
+        assert_eq!(upload_type_error(&Config::default(), exe, Some(pe)), None);
+    }
+
+    #[test]
+    fn test_request_deadline() {
+        let deadline = Deadline::after(1_000, 500);
+        assert_eq!(deadline.remaining_ms(1_200), 300);
+        // Past the deadline is 0, never negative
+        assert_eq!(deadline.remaining_ms(9_000), 0);
+        assert_eq!(Deadline::after(u64::MAX - 1, 500).remaining_ms(0), u64::MAX);
+
+        let budgets = std::cell::RefCell::new(Vec::new());
+        let sleep = |ms| {
+            budgets.borrow_mut().push(ms);
+            futures::future::ready(())
+        };
+        // A slow operation is cut off with the remaining budget, not the full one
+        let slow = futures::executor::block_on(race_deadline(
+            futures::future::pending::<Result<()>>(),
+            deadline.remaining_ms(1_400),
+            sleep,
+        ));
+        assert!(is_deadline_error(&slow.unwrap_err()));
+        assert_eq!(*budgets.borrow(), vec![100]);
+
+        // Nothing left: fails without starting or sleeping
+        let spent = futures::executor::block_on(race_deadline(
+            async { Ok(1) },
+            deadline.remaining_ms(1_600),
+            |_| -> futures::future::Ready<()> { panic!("no sleep once expired") },
+        ));
+        assert!(is_deadline_error(&spent.unwrap_err()));
+        assert_eq!(ErrorCode::DeadlineExceeded.status(), 504);
+
+        let fast = futures::executor::block_on(race_deadline(async { Ok(1) }, 100, |_| {
+            futures::future::pending()
+        }));
+        assert_eq!(fast.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_csrf_double_submit() {
+        let cookies = Some("theme=dark; csrf_token=abc123");
+        assert!(!csrf_check(&Method::Post, cookies, Some("abc124"), false));
+        assert!(!csrf_check(&Method::Delete, cookies, None, false));
+        assert!(csrf_check(&Method::Post, cookies, Some("abc123"), false));
+
+        // Cookies without a token can't pass a state-changing request
+        assert!(!csrf_check(
+            &Method::Put,
+            Some("theme=dark"),
+            Some(""),
+            false
+        ));
+        // Safe methods, cookie-less requests and bearer/API-key auth skip the check
+        assert!(csrf_check(&Method::Get, cookies, None, false));
+        assert!(csrf_check(&Method::Post, None, None, false));
+        assert!(csrf_check(&Method::Patch, cookies, None, true));
+
+        assert_eq!(cookie_value("a=1; csrf_token=x", CSRF_COOKIE), Some("x"));
+        assert!(csrf_set_cookie().starts_with("csrf_token="));
+        assert_eq!(ErrorCode::CsrfFailed.status(), 403);
+    }
+
+    #[test]
+    fn test_response_size_limits() {
+        // A list page one byte over the hard limit
+        let page = Paginator::new(1, 100).build(vec!["x".repeat(64); 100], 1_000);
+        let len = JsonFormat.serialize(&page).unwrap().len();
+        let hard = len as u64 - 1;
+        assert_eq!(response_size(len, hard / 2, hard), ResponseSize::OverHard);
+        assert_eq!(
+            response_size(len, hard / 2, hard + 1),
+            ResponseSize::OverSoft
+        );
+        assert_eq!(response_size(len, hard + 1, hard + 1), ResponseSize::Normal);
+        assert_eq!(ErrorCode::ResponseTooLarge.status(), 413);
+
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+        config.response_soft_limit_bytes = config.response_hard_limit_bytes + 1;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_schema_status_drift() {
+        let known = &SCHEMA_MIGRATIONS[..4];
+        // Code ahead of the DB: 0004 shipped but was never applied
+        let applied: Vec<String> = known[..3].iter().map(|m| m.to_string()).collect();
+        let status = schema_status(known, &applied);
+        assert!(status.drift);
+        assert_eq!(status.expected, "0004_email_verification.sql");
+        assert_eq!(status.applied.as_deref(), Some("0003_users_list_order.sql"));
+        assert_eq!(status.pending, vec!["0004_email_verification.sql"]);
+        assert!(status.unknown.is_empty());
+
+        // DB ahead of the code
+        let mut applied: Vec<String> = known.iter().map(|m| m.to_string()).collect();
+        applied.push("0099_future.sql".to_string());
+        let status = schema_status(known, &applied);
+        assert!(status.drift && status.pending.is_empty());
+        assert_eq!(status.unknown, vec!["0099_future.sql"]);
+
+        applied.pop();
+        assert!(!schema_status(known, &applied).drift);
+        assert_eq!(schema_status(known, &[]).applied, None);
+    }
+
+    #[test]
+    fn test_missing_index_warning() {
+        // A users table rebuilt by hand: the list-order index never came back
+        let actual: Vec<String> = [
+            "sqlite_autoindex_users_1",
+            "idx_users_deleted_at",
+            "idx_users_email_normalized",
+            "idx_users_tenant",
+            "idx_users_email_hmac",
+            "idx_users_tenant_email",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+        let report = index_report(EXPECTED_INDEXES, &actual);
+        assert_eq!(report.present.len(), EXPECTED_INDEXES.len() - 1);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].name, "idx_users_created_at_id");
+        assert_eq!(
+            index_warnings(&report),
+            vec![
+                "missing index idx_users_created_at_id on users (created_at, id): \
+                 user list ordering and cursors will scan the table"
+            ]
+        );
+
+        let all: Vec<String> = EXPECTED_INDEXES
+            .iter()
+            .map(|i| i.name.to_string())
+            .collect();
+        assert!(index_warnings(&index_report(EXPECTED_INDEXES, &all)).is_empty());
+        assert!(!Config::default().index_check_on_start);
+    }
+
+    #[test]
+    fn test_compute_batch_stops_at_deadline() {
+        use std::cell::Cell;
+
+        let operations: Vec<ComputeRequest> = (0..50)
+            .map(|_| ComputeRequest {
+                data: vec![1.0, 2.0],
+                operation: "sum".to_string(),
+                ..Default::default()
+            })
+            .collect();
+        // Each item takes 10ms; the reserve is hit at 300ms, before item 30
+        let clock = Cell::new(0.0);
+        let now = || {
+            let t = clock.get();
+            clock.set(t + 10.0);
+            t
+        };
+        let deadline = Deadline::after(0, 300 + BATCH_DEADLINE_RESERVE_MS);
+        let out_of_time = || deadline.nearly_expired(now() as u64);
+
+        let batch = futures::executor::block_on(run_compute_batch(
+            &operations,
+            false,
+            || unreachable!(),
+            out_of_time,
+        ));
+        assert_eq!(batch.results.len(), 30);
+        assert_eq!(batch.unprocessed, (30..50).collect::<Vec<_>>());
+        assert_eq!(batch.status(), 207);
+
+        let body = serde_json::to_value(&batch).unwrap();
+        assert_eq!(body["timed_out"], true);
+        assert_eq!(body["unprocessed"][0], 30);
+    }
+
+    #[test]
+    fn test_self_check_missing_binding() {
+        // A deploy whose wrangler config lost the R2 bucket
+        let check = run_self_check(&Config::default(), |name, _| name != "STORAGE");
+        assert_eq!(check.missing_critical, vec!["STORAGE"]);
+        assert_eq!(check.error().as_deref(), Some("missing bindings: STORAGE"));
+        assert!(check.summary().contains("missing critical [STORAGE]"));
+
+        // Optional pieces only warn
+        let check = run_self_check(&Config::default(), |_, kind| kind != BindingKind::Secret);
+        assert_eq!(check.missing_optional, vec!["ADMIN_KEY"]);
+        assert_eq!(check.error(), None);
+
+        // Feature bindings are only checked when the feature is on
+        let config = Config {
+            user_counter: true,
+            ..Config::default()
+        };
+        let check = run_self_check(&config, |_, kind| kind != BindingKind::DurableObject);
+        assert_eq!(check.missing_optional, vec!["USER_EVENTS", "USER_COUNTER"]);
+    }
+
+    #[test]
+    fn test_stale_cache_served_when_d1_fails() {
+        let user = test_user();
+        // Cached at t=0 with a 300s TTL, read again at t=400s
+        let entry = CachedUser {
+            user: user.clone(),
+            fresh_until_ms: 300_000,
+        };
+        assert!(entry.is_fresh(299_999));
+        assert!(!entry.is_fresh(400_000));
+
+        // The stale read goes to D1, D1 errors, the stale copy stands in
+        let d1_down = || Err::<Option<User>, _>(Error::RustError("D1_ERROR: network".to_string()));
+        let Ok(Err((served, _))) = stale_on_error(d1_down(), Some(entry.user)) else {
+            panic!("expected the stale user");
+        };
+        assert_eq!(served.id, "u1");
+        assert_eq!(STALE_WARNING, "110 - \"Response is Stale\"");
+
+        // Without a stale copy the error still surfaces; a good read wins
+        assert!(stale_on_error(d1_down(), None).is_err());
+        assert!(matches!(
+            stale_on_error(Ok(Some(user.clone())), Some(user)),
+            Ok(Ok(Some(_)))
+        ));
+    }
+
+    #[test]
+    fn test_health_report_takes_worst_status() {
+        let ok = DependencyCheck::from_outcome("kv", 4, Ok(()));
+        let slow = DependencyCheck::from_outcome("r2", HEALTH_SLOW_MS + 1, Ok(()));
+        let failed = DependencyCheck::from_outcome(
+            "d1",
+            12,
+            Err(Error::RustError("D1_ERROR: unavailable".to_string())),
+        );
+        assert_eq!(ok.status, HealthStatus::Healthy);
+        assert_eq!(slow.status, HealthStatus::Degraded);
+        assert_eq!(failed.error.as_deref(), Some("D1_ERROR: unavailable"));
+
+        let report = HealthReport::new("t".to_string(), vec![ok, slow, failed]);
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["status"], "unhealthy");
+        assert_eq!(json["checks"][0]["status"], "healthy");
+        assert!(json["checks"][0].get("error").is_none());
+        assert_eq!(json["checks"][2]["latency_ms"], 12);
+
+        let report = HealthReport::new(
+            "t".to_string(),
+            vec![DependencyCheck::from_outcome("kv", 4, Ok(()))],
+        );
+        assert_eq!(report.status, HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn test_health_probe_timeout() {
+        // KV hangs; its timeout has fired while D1 and R2 answer straight away
+        let probe = |hangs: bool| {
+            let check = async move {
+                if hangs {
+                    futures::future::pending::<()>().await;
+                }
+                Ok(())
+            };
+            let timeout: std::pin::Pin<Box<dyn std::future::Future<Output = ()>>> = if hangs {
+                Box::pin(futures::future::ready(()))
+            } else {
+                Box::pin(futures::future::pending())
+            };
+            run_with_budget(check, timeout)
+        };
+        let outcomes = futures::executor::block_on(futures::future::join_all([
+            probe(false),
+            probe(true),
+            probe(false),
+        ]));
+        let checks: Vec<DependencyCheck> = ["d1", "kv", "r2"]
+            .into_iter()
+            .zip(outcomes)
+            .map(|(name, outcome)| DependencyCheck::from_probe(name, 3, 2_000, outcome))
+            .collect();
+
+        let report = HealthReport::new("t".to_string(), checks);
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][0]["status"], "healthy");
+        assert_eq!(json["checks"][1]["status"], "unhealthy");
+        assert_eq!(json["checks"][1]["error"], "timeout");
+        assert_eq!(json["checks"][1]["latency_ms"], 2_000);
+        assert_eq!(json["checks"][2]["status"], "healthy");
+
+        let config = Config {
+            health_probe_timeout_ms: 15_000,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_request_id_propagation() {
+        let generated = || "ray-1".to_string();
+        assert_eq!(
+            resolve_request_id(Some("7f3c-upstream_01.a:b"), generated),
+            "7f3c-upstream_01.a:b"
+        );
+        assert_eq!(resolve_request_id(None, generated), "ray-1");
+        // Oversized, empty, or log-unsafe ids are replaced
+        let oversized = "a".repeat(REQUEST_ID_MAX_LEN + 1);
+        assert_eq!(resolve_request_id(Some(&oversized), generated), "ray-1");
+        assert_eq!(resolve_request_id(Some(""), generated), "ray-1");
+        assert_eq!(resolve_request_id(Some("id\nforged=1"), generated), "ray-1");
+        assert_eq!(resolve_request_id(Some("a b"), generated), "ray-1");
+        assert!(Config::default().trust_request_id);
+    }
+
+    #[test]
+    fn test_security_headers() {
+        let config = Config::default();
+        let csp = config.content_security_policy.as_str();
+        let names = |headers: Vec<(&'static str, String)>| {
+            headers
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+
+        // The index is text, so it gets the full set
+        let index = security_headers(csp, Some("text/plain; charset=utf-8"));
+        assert!(index.contains(&("X-Content-Type-Options", "nosniff".to_string())));
+        assert!(index.contains(&(
+            "Content-Security-Policy",
+            "default-src 'none'; frame-ancestors 'none'".to_string()
+        )));
+        assert_eq!(
+            names(index),
+            vec![
+                "Strict-Transport-Security",
+                "X-Content-Type-Options",
+                "Referrer-Policy",
+                "X-Frame-Options",
+                "Content-Security-Policy",
+            ]
+        );
+        // JSON gets the subset that matters for data
+        assert_eq!(
+            names(security_headers(csp, Some("application/json"))),
+            vec![
+                "Strict-Transport-Security",
+                "X-Content-Type-Options",
+                "Referrer-Policy",
+            ]
+        );
+        // An untyped response is treated as renderable
+        assert_eq!(security_headers(csp, None).len(), 5);
+
+        let off = Config::from_lookup(|name| (name == "SECURITY_HEADERS").then(|| "false".into()));
+        assert!(!off.security_headers);
+    }
+
+    #[test]
+    fn test_excessive_headers_rejected() {
+        let config = Config::default();
+        let header = |i: usize| (format!("x-filler-{}", i), "a".repeat(10));
+        let (max_count, max_bytes) = (config.max_request_headers, config.max_request_header_bytes);
+
+        // A request padded out with 150 small headers
+        let message = header_limit_error((0..150).map(header), max_count, max_bytes).unwrap();
+        assert_eq!(message, "Too many request headers; the limit is 100");
+        // A few headers, one of them a huge cookie
+        let cookie = ("cookie".to_string(), "c".repeat(40 * 1024));
+        let oversized = (0..3).map(header).chain(std::iter::once(cookie));
+        assert!(header_limit_error(oversized, max_count, max_bytes)
+            .unwrap()
+            .contains("32768 bytes"));
+        assert_eq!(ErrorCode::HeaderFieldsTooLarge.status(), 431);
+        assert_eq!(
+            serde_json::to_value(ErrorCode::HeaderFieldsTooLarge).unwrap(),
+            "header_fields_too_large"
+        );
+
+        assert_eq!(
+            header_limit_error((0..100).map(header), max_count, max_bytes),
+            None
+        );
+    }
+
+    #[test]
+    fn test_disabled_route_returns_503() {
+        let lookup = |name: &str| {
+            (name == "DISABLED_ROUTES").then(|| "/api/compute; /api/users/:id".to_string())
+        };
+        let config = Config::from_lookup(lookup);
+        assert_eq!(
+            config.disabled_routes,
+            vec!["/api/compute", "/api/users/:id"]
+        );
+        let runtime = vec!["/api/files/*".to_string()];
+
+        let disabled = |path| disabled_route(&config.disabled_routes, &runtime, path);
+        assert_eq!(disabled("/api/compute"), Some("/api/compute"));
+        assert_eq!(disabled("/api/users/u1"), Some("/api/users/:id"));
+        assert_eq!(disabled("/api/files/a/b.txt"), Some("/api/files/*"));
+        // Everything else is served as usual
+        assert_eq!(disabled("/api/compute/batch"), None);
+        assert_eq!(disabled("/api/users"), None);
+        assert_eq!(disabled("/health"), None);
+        // Admin routes stay reachable so the toggle can be undone
+        assert_eq!(
+            disabled_route(&["/admin/*".to_string()], &[], "/admin/routes"),
+            None
+        );
+        assert_eq!(ErrorCode::RouteDisabled.status(), 503);
+
+        assert!(Config::default().disabled_routes.is_empty());
+        assert!(!Config::default().route_toggles);
+    }
+
+    #[test]
+    fn test_method_override() {
+        // POST + override DELETE is routed as DELETE, so it reaches
+        // `.delete("/api/users/:id", handle_delete_user)`
+        assert_eq!(
+            method_override(&Method::Post, Some("DELETE")),
+            Ok(Some(Method::Delete))
+        );
+        assert_eq!(
+            method_override(&Method::Post, Some(" patch ")),
+            Ok(Some(Method::Patch))
+        );
+        assert_eq!(method_override(&Method::Post, None), Ok(None));
+        assert_eq!(method_override(&Method::Post, Some("")), Ok(None));
+
+        // Only POST, and never to a safe or unknown method
+        assert!(method_override(&Method::Get, Some("DELETE")).is_err());
+        assert!(method_override(&Method::Post, Some("GET")).is_err());
+        assert!(method_override(&Method::Post, Some("TRACE")).is_err());
+    }
+
+    #[test]
+    fn test_analytics_point_fields() {
+        let access = AccessLog {
+            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            request_id: "ray-1".to_string(),
+            method: "GET".to_string(),
+            path: "/api/users/u1".to_string(),
+            status: 404,
+            duration_ms: 12,
+            country: Some("DE".to_string()),
+            subject: Some("admin".to_string()),
+        };
+        // blob1..3 = path, method, country; double1..2 = duration, status.
+        // Identities and request ids stay out of analytics.
+        let point = AnalyticsPoint::from_access(&access);
+        assert_eq!(
+            serde_json::to_value(&point).unwrap(),
+            serde_json::json!({
+                "blobs": ["/api/users/u1", "GET", "DE"],
+                "doubles": [12.0, 404.0],
+            })
+        );
+
+        let access = AccessLog {
+            country: None,
+            ..access
+        };
+        assert_eq!(AnalyticsPoint::from_access(&access).blobs[2], "");
+    }
+
+    #[test]
+    fn test_cache_touch_reports_absent_keys() {
+        // Touching ["a", "gone", "b"] where "gone" has expired: "a" and "b"
+        // are re-put with the new TTL, "gone" is reported and not recreated
+        let keys = vec!["a".to_string(), "gone".to_string(), "b".to_string()];
+        let result = CacheTouchResult::from_outcomes(keys, vec![true, false, true]);
+        assert_eq!(
+            result,
+            CacheTouchResult {
+                refreshed: vec!["a".to_string(), "b".to_string()],
+                absent: vec!["gone".to_string()],
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({"refreshed": ["a", "b"], "absent": ["gone"]})
+        );
+    }
+
+    #[test]
+    fn test_shard_for_is_stable() {
+        // FNV-1a reference vectors
+        assert_eq!(fnv1a_64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a_64(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("cache:key-{}", i)).collect();
+        for key in &keys {
+            let shard = shard_for(key, 4);
+            assert!(shard < 4);
+            assert_eq!(shard_for(key, 4), shard);
+            assert_eq!(shard_for(key, 1), 0);
+        }
+        // Same answer in every isolate: pinned, not just self-consistent
+        let pinned: Vec<usize> = keys[..8].iter().map(|k| shard_for(k, 4)).collect();
+        assert_eq!(pinned, vec![0, 3, 2, 3, 1, 1, 2, 3]);
+
+        // Going from 4 to 5 shards only moves keys onto the new shard
+        let mut moved = 0;
+        for key in &keys {
+            let (before, after) = (shard_for(key, 4), shard_for(key, 5));
+            if before != after {
+                assert_eq!(after, 4);
+                moved += 1;
+            }
+        }
+        assert!((100..300).contains(&moved), "moved {}", moved);
+
+        let config = Config {
+            cache_shards: 3,
+            ..Config::default()
+        };
+        assert!(CACHE_SHARD_BINDINGS[..3].contains(&cache_binding(&config, "cache:x")));
+        let config = Config {
+            cache_shards: 0,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_route_body_limits() {
+        let rules = Config::default().route_body_limits;
+        let two_mb = (2 * 1024 * 1024).to_string();
+        let check = |path: &str| {
+            let max = body_limit_for(&rules, path).unwrap();
+            check_upload_length(Some(&two_mb), false, max, false)
+        };
+
+        // The same 2 MiB body: too big to create a user, fine for compute
+        assert!(matches!(
+            check("/api/users"),
+            Err((ErrorCode::PayloadTooLarge, _))
+        ));
+        assert!(matches!(
+            check("/api/users/abc"),
+            Err((ErrorCode::PayloadTooLarge, _))
+        ));
+        assert_eq!(
+            check("/api/compute"),
+            Ok(UploadLength::Declared(2 * 1024 * 1024))
+        );
+        // The literal route beats /api/users/:id
+        assert_eq!(body_limit_for(&rules, "/api/users/validate"), Some(1048576));
+        assert_eq!(body_limit_for(&rules, "/api/files/a.png"), None);
+
+        assert_eq!(
+            parse_route_body_limits("/a=10; /b=lots; /c="),
+            vec![("/a".to_string(), 10)]
+        );
+    }
+
+    #[test]
+    fn test_hide_user_existence() {
+        let key = || {
+            ApiCaller::Key(ApiKeyRecord {
+                name: "reporting".to_string(),
+                allowed_ops: None,
+                tenant_id: None,
+            })
+        };
+        // Flag off: lookups are open, as before
+        assert!(user_lookup_allowed(false, &ApiCaller::Anonymous, false));
+
+        // Flag on: unauthenticated and bad-key callers get the missing-id 404
+        // without a lookup; a valid key or the admin key reaches D1
+        assert!(!user_lookup_allowed(true, &ApiCaller::Anonymous, false));
+        assert!(!user_lookup_allowed(true, &ApiCaller::Invalid, false));
+        assert!(user_lookup_allowed(true, &key(), false));
+        assert!(user_lookup_allowed(true, &ApiCaller::Anonymous, true));
+
+        // Under tenant isolation a key without a tenant is refused a scope,
+        // which the handler reports as the same 404
+        assert_eq!(caller_tenant_scope(true, &key()), None);
+        assert_eq!(caller_tenant_scope(true, &ApiCaller::Anonymous), None);
+        let tenant_key = ApiCaller::Key(ApiKeyRecord {
+            name: "acme".to_string(),
+            allowed_ops: None,
+            tenant_id: Some("acme".to_string()),
+        });
+        assert_eq!(
+            caller_tenant_scope(true, &tenant_key),
+            Some(TenantScope::Tenant("acme".to_string()))
+        );
+        assert_eq!(
+            caller_tenant_scope(false, &ApiCaller::Invalid),
+            Some(TenantScope::All)
+        );
+
+        // A 2ms refusal and an 80ms D1 hit both go out at the 100ms floor
+        assert_eq!(existence_padding_ms(2, 100), 98);
+        assert_eq!(existence_padding_ms(80, 100), 20);
+        assert_eq!(existence_padding_ms(150, 100), 0);
+    }
+
+    #[test]
+    fn test_batch_mixed_outcomes_return_207() {
+        let mixed = BatchResult::new(vec![
+            ItemOutcome::ok(0, "a"),
+            ItemOutcome::err(1, ErrorCode::UserNotFound, "User not found"),
+        ]);
+        assert_eq!(mixed.status(), 207);
+        let body = serde_json::to_value(&mixed).unwrap();
+        assert_eq!(body["results"][0]["status"], 200);
+        assert_eq!(body["results"][1]["code"], "user_not_found");
+        assert!(body.get("timed_out").is_none());
+
+        assert_eq!(
+            BatchResult::new(vec![ItemOutcome::ok(0, "a")]).status(),
+            200
+        );
+        assert_eq!(BatchResult::<()>::new(Vec::new()).status(), 200);
+        // Failing for one reason answers with that reason's status
+        let missing = BatchResult::<()>::new(vec![
+            ItemOutcome::err(0, ErrorCode::UserNotFound, "User not found"),
+            ItemOutcome::err(1, ErrorCode::UserNotFound, "User not found"),
+        ]);
+        assert_eq!(missing.common_error(), Some(ErrorCode::UserNotFound));
+        assert_eq!(missing.status(), 404);
+        let varied = BatchResult::<()>::new(vec![
+            ItemOutcome::err(0, ErrorCode::UserNotFound, "User not found"),
+            ItemOutcome::err(1, ErrorCode::ValidationFailed, "Invalid email"),
+        ]);
+        assert_eq!(varied.status(), 207);
+    }
+
+    #[test]
+    fn test_backup_encoder_round_trip() {
+        use std::io::Read;
+
+        let rows = |from: usize, to: usize| -> Vec<serde_json::Value> {
+            (from..to)
+                .map(|i| serde_json::json!({ "id": format!("u{:03}", i), "name": "Ada" }))
+                .collect()
+        };
+        let mut encoder = BackupEncoder::new();
+        let mut file = encoder.write_rows(&rows(0, 300)).unwrap();
+        file.extend(encoder.write_rows(&rows(300, 500)).unwrap());
+        file.extend(encoder.finish().unwrap());
+
+        let mut ndjson = String::new();
+        flate2::read::GzDecoder::new(file.as_slice())
+            .read_to_string(&mut ndjson)
+            .unwrap();
+        let lines: Vec<serde_json::Value> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, rows(0, 500));
+        assert!(ndjson.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_cache_key_builder() {
+        assert_eq!(CacheKey::new(KeySpace::User).part("u1").build(), "user:u1");
+        assert_eq!(
+            CacheKey::new(KeySpace::Custom).part("greeting").build(),
+            "cache:greeting"
+        );
+        assert_eq!(
+            CacheKey::new(KeySpace::Webhook)
+                .part("user.created")
+                .part("u1")
+                .build(),
+            "webhook:user.created:u1"
+        );
+        // A flush with no prefix covers exactly its own keyspace
+        assert_eq!(CacheKey::new(KeySpace::Custom).part("").build(), "cache:");
+        assert_eq!(KeySpace::parse("file"), Some(KeySpace::File));
+        assert_eq!(KeySpace::parse("user"), None);
+        assert_eq!(KeySpace::parse("webhook"), None);
+        assert_eq!(KeySpace::parse("lock"), None);
+        assert_eq!(KeySpace::parse("apikey"), None);
+    }
+
+    #[test]
+    fn test_user_cache_key_versioned() {
+        assert_eq!(user_cache_key(0, "u1"), "v0:user:u1");
+        assert_eq!(user_cache_key(7, "u1"), "v7:user:u1");
+        assert_ne!(user_cache_key(1, "u1"), user_cache_key(2, "u1"));
+        assert_eq!(user_cache_version_key(), "meta:user_cache_version");
+        assert_eq!(KeySpace::parse("meta"), None);
+    }
+
+    #[test]
+    fn test_compare_etag() {
+        // `*` matches any current representation, weak or strong
+        assert!(compare_etag("*", "\"abc\"", true));
+        assert!(compare_etag("*", "W/\"abc\"", false));
+
+        // Lists, including tags that contain commas
+        assert!(compare_etag("\"x\", \"abc\"", "\"abc\"", true));
+        assert!(compare_etag("\"a,b\",\"c\"", "\"a,b\"", true));
+        assert!(!compare_etag("\"x\", \"y\"", "\"abc\"", false));
+
+        // Weak vs strong: If-None-Match (weak) matches, If-Match (strong) doesn't
+        assert!(compare_etag("W/\"abc\"", "\"abc\"", false));
+        assert!(!compare_etag("W/\"abc\"", "\"abc\"", true));
+        assert!(!compare_etag("\"abc\"", "W/\"abc\"", true));
+        assert!(compare_etag("W/\"abc\"", "W/\"abc\"", false));
+    }
+
+    #[test]
+    fn test_anonymize_identity() {
+        let (name, email) = anonymize_identity("Ada Lovelace", "Ada@Example.com");
+        assert!(name.starts_with("anon-"));
+        assert!(email.ends_with(ANONYMIZED_EMAIL_DOMAIN));
+        assert!(!name.contains("Ada") && !email.contains("example"));
+        // Email case doesn't change the digest
+        assert_eq!(
+            anonymize_identity("Ada Lovelace", "ada@example.com").1,
+            email
+        );
+
+        // Idempotent: a second run leaves the anonymized values alone
+        assert_eq!(
+            anonymize_identity(&name, &email),
+            (name.clone(), email.clone())
+        );
+    }
+
+    #[test]
+    fn test_deleted_cap_overflow() {
+        // Under or at the cap nothing is purged early
+        assert_eq!(deleted_cap_overflow(90, 100), 0);
+        assert_eq!(deleted_cap_overflow(100, 100), 0);
+        // Past it, exactly the excess goes, oldest first
+        assert_eq!(deleted_cap_overflow(103, 100), 3);
+        // A large backlog is drained a batch per run
+        assert_eq!(deleted_cap_overflow(10_000, 100), DELETED_CAP_BATCH_SIZE);
+        assert_eq!(Config::default().retention_max_deleted, None);
+        let lookup = |name: &str| (name == "RETENTION_MAX_DELETED").then(|| "0".to_string());
+        assert_eq!(Config::from_lookup(lookup).retention_max_deleted, None);
+        // The retention window holds unless explicitly overridden
+        assert_eq!(deleted_cap_window(&Config::default()), Some(30));
+        let lookup = |name: &str| (name == "RETENTION_CAP_IGNORES_WINDOW").then(|| "1".to_string());
+        assert_eq!(deleted_cap_window(&Config::from_lookup(lookup)), None);
+    }
+
+    #[test]
+    fn test_validate_batch_mixed_rows() {
+        let batch = validate_batch(vec![
+            serde_json::json!({ "name": "  Ada ", "email": "Ada@Example.com" }),
+            serde_json::json!({ "name": "", "email": "nope" }),
+            serde_json::json!({ "name": "Bob", "email": "bob@localhost" }),
+            serde_json::json!({ "email": "carol@example.com" }),
+        ]);
+        let results = &batch.results;
+
+        assert!(results[0].succeeded());
+        assert_eq!(
+            results[0].data,
+            Some(NewUser {
+                name: "Ada".to_string(),
+                email: "ada@example.com".to_string(),
+            })
+        );
+        assert_eq!(results[1].status, 400);
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("Name is required; Invalid email")
+        );
+        // Email domain must be dotted
+        assert_eq!(results[2].error.as_deref(), Some("Invalid email"));
+        assert!(results[3]
+            .error
+            .as_deref()
+            .is_some_and(|e| e.starts_with("Invalid user object")));
+        assert_eq!(
+            results.iter().map(|r| r.index).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_pretty_json_round_trips() {
+        let value = ApiResponse {
+            success: true,
+            data: Some(serde_json::json!({ "id": "u1", "tags": ["a", "b"] })),
+            error: None,
+            code: None,
+        };
+        let format = negotiate_format(Some("application/json")).with_pretty(true);
+        assert_eq!(format, Format::PrettyJson);
+        assert_eq!(format.content_type(), "application/json");
+
+        let pretty = format.serialize(&value).unwrap();
+        assert!(pretty.contains(&b'\n'));
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&pretty).unwrap(),
+            serde_json::to_value(&value).unwrap()
+        );
+
+        // MessagePack is unaffected by the flag
+        assert_eq!(
+            negotiate_format(Some("application/msgpack")).with_pretty(true),
+            Format::MessagePack
+        );
+    }
+
+    #[test]
+    fn test_omit_null_fields() {
+        let value = ApiResponse {
+            success: true,
+            data: Some(serde_json::json!({ "id": "u1", "deleted_at": null })),
+            error: None,
+            code: None,
+        };
+        let always = serialize_body(&Format::Json, &value, false).unwrap();
+        assert_eq!(
+            String::from_utf8(always).unwrap(),
+            r#"{"success":true,"data":{"deleted_at":null,"id":"u1"},"error":null,"code":null}"#
+        );
+        // Nulls inside data are kept
+        let omitted = serialize_body(&Format::Json, &value, true).unwrap();
+        assert_eq!(
+            String::from_utf8(omitted).unwrap(),
+            r#"{"data":{"deleted_at":null,"id":"u1"},"success":true}"#
+        );
+        let packed = serialize_body(&Format::MessagePack, &value, true).unwrap();
+        let unpacked: serde_json::Value = rmp_serde::from_slice(&packed).unwrap();
+        assert_eq!(unpacked.get("error"), None);
+
+        assert!(!Config::default().omit_null_fields);
+        assert!(!prefer_omit_nulls(None, false));
+        assert!(prefer_omit_nulls(Some("respond-async, nulls=omit"), false));
+        assert!(!prefer_omit_nulls(Some("nulls=include"), true));
+        assert!(prefer_omit_nulls(Some("nulls=sometimes"), true));
+    }
+
+    #[test]
+    fn test_unknown_field_rejected() {
+        let err = parse_json_body::<CreateUserRequest>(r#"{"name":"Ada","emial":"a@b.co"}"#)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            (ErrorCode::UnknownField, "Unknown field: emial".to_string())
+        );
+        assert_eq!(ErrorCode::UnknownField.status(), 422);
+
+        let err = parse_json_body::<UpdateUserRequest>("{not json")
+            .err()
+            .unwrap();
+        assert_eq!(err.0, ErrorCode::InvalidJson);
+        assert!(parse_json_body::<UpdateUserRequest>(r#"{"name":"Ada"}"#).is_ok());
+    }
+
+    #[test]
+    fn test_empty_body_required() {
+        // POST /api/users with no body, or only whitespace
+        for body in ["", "  \n"] {
+            let err = parse_json_body::<CreateUserRequest>(body).err().unwrap();
+            assert_eq!(
+                err,
+                (ErrorCode::BodyRequired, "Request body required".to_string())
+            );
+        }
+        assert_eq!(ErrorCode::BodyRequired.status(), 400);
+
+        // Malformed JSON says what's wrong with it
+        let (code, message) = parse_json_body::<CreateUserRequest>(r#"{"name": "Ada""#)
+            .err()
+            .unwrap();
+        assert_eq!(code, ErrorCode::InvalidJson);
+        assert!(
+            message.starts_with("Invalid JSON: EOF while parsing"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_float_rejected_for_integer_field() {
+        let round = |value: &str| {
+            parse_json_body::<ComputeRequest>(&format!(r#"{{"data":[1.25],"round":{}}}"#, value))
+                .map(|input| input.round)
+        };
+        let err = round("1.5").err().unwrap();
+        assert_eq!(
+            err,
+            (
+                ErrorCode::InvalidNumber,
+                "Invalid number: 1.5 is not a whole number in range".to_string()
+            )
+        );
+        assert_eq!(ErrorCode::InvalidNumber.status(), 422);
+        // Whole floats, strings, negatives and out-of-range values too
+        for value in ["2.0", "1e1", "\"2\"", "-1", "4294967296"] {
+            assert_eq!(
+                round(value).err().unwrap().0,
+                ErrorCode::InvalidNumber,
+                "{}",
+                value
+            );
+        }
+        assert_eq!(round("2"), Ok(Some(2)));
+        assert_eq!(round("null"), Ok(None));
+        assert!(matches!(
+            parse_json_body::<ComputeRequest>(r#"{"data":[1]}"#),
+            Ok(ComputeRequest { round: None, .. })
+        ));
+
+        let ttl =
+            parse_json_body::<CacheTouchRequest>(r#"{"keys":["a"],"ttl":18446744073709551616}"#);
+        assert_eq!(ttl.err().unwrap().0, ErrorCode::InvalidNumber);
+    }
+
+    #[test]
+    fn test_proxy_passes_through_rate_limit() {
+        let upstream = vec![
+            ("Retry-After".to_string(), "120".to_string()),
+            ("RateLimit-Remaining".to_string(), "0".to_string()),
+            ("X-RateLimit-Reset".to_string(), "1700000000".to_string()),
+            ("Set-Cookie".to_string(), "session=abc".to_string()),
+        ];
+        let headers = proxied_headers(upstream);
+        assert!(headers.contains(&("Retry-After".to_string(), "120".to_string())));
+        assert!(headers
+            .iter()
+            .any(|(name, _)| name == "RateLimit-Remaining"));
+        assert!(headers.iter().any(|(name, _)| name == "X-RateLimit-Reset"));
+        assert!(!headers.iter().any(|(name, _)| name == "Set-Cookie"));
+
+        // A long wait isn't absorbed: the 429 goes back to the client as-is
+        assert_eq!(proxy_retry_delay(&Method::Get, 429, Some("120")), None);
+        assert_eq!(proxy_retry_delay(&Method::Get, 429, Some("1")), Some(1));
+        // Never retried for non-idempotent methods
+        assert_eq!(proxy_retry_delay(&Method::Post, 429, Some("1")), None);
+        assert_eq!(proxy_retry_delay(&Method::Get, 200, Some("1")), None);
+    }
+
+    #[test]
+    fn test_compute_batch_timings() {
+        use std::cell::Cell;
+
+        let operations = vec![
+            ComputeRequest {
+                data: vec![1.0, 2.0, 3.0],
+                operation: "sum".to_string(),
+                ..Default::default()
+            },
+            ComputeRequest {
+                data: vec![1.0],
+                operation: "median".to_string(),
+                ..Default::default()
+            },
+        ];
+        let clock = Cell::new(0.0);
+        let now = || {
+            clock.set(clock.get() + 1.5);
+            clock.get()
+        };
+
+        let batch =
+            futures::executor::block_on(run_compute_batch(&operations, true, now, || false));
+        assert!(batch.unprocessed.is_empty());
+        let items = &batch.results;
+        assert!(
+            matches!(&items[0].data, Some(item) if matches!(item.result, ComputeValue::Scalar(sum) if sum == 6.0))
+        );
+        assert_eq!(items[1].code, Some(ErrorCode::UnknownOperation));
+        assert!(items
+            .iter()
+            .filter_map(|item| item.data.as_ref())
+            .all(|item| item.elapsed_ms.is_some_and(|ms| ms >= 0.0)));
+
+        let untimed = futures::executor::block_on(run_compute_batch(
+            &operations,
+            false,
+            || unreachable!(),
+            || false,
+        ));
+        assert!(untimed
+            .results
+            .iter()
+            .filter_map(|item| item.data.as_ref())
+            .all(|item| item.elapsed_ms.is_none()));
+    }
+
+    #[test]
+    fn test_d1_session_constraint() {
+        // A client bookmark always wins, for reads and writes alike
+        assert_eq!(session_constraint(Some("0000-abc"), false), "0000-abc");
+        assert_eq!(session_constraint(Some("0000-abc"), true), "0000-abc");
+        assert_eq!(session_constraint(None, false), "first-unconstrained");
+        assert_eq!(session_constraint(Some(""), true), "first-primary");
+    }
+
+    #[test]
+    fn test_signing_key_rotation() {
+        let before = SigningKeys::new("old-secret", None);
+        let token = before.sign("payload");
+
+        // After rotation the old signature verifies through `previous`
+        let rotated = SigningKeys::new("new-secret", Some("old-secret"));
+        assert!(rotated.verify("payload", &token));
+        assert!(rotated.verify("payload", &rotated.sign("payload")));
+        assert!(!rotated.verify("tampered", &token));
+
+        // A secret that is neither current nor previous fails
+        let unknown = SigningKeys::new("other-secret", None).sign("payload");
+        assert!(!rotated.verify("payload", &unknown));
+
+        // Flush cursors are bound to their prefix
+        let sealed = seal_cursor(Some(&rotated), "cache:a", "kv-cursor");
+        assert_eq!(
+            open_cursor(Some(&rotated), "cache:a", &sealed).as_deref(),
+            Some("kv-cursor")
+        );
+        assert_eq!(open_cursor(Some(&rotated), "cache:b", &sealed), None);
+    }
+
+    #[test]
+    fn test_sse_frame() {
+        assert_eq!(
+            sse_frame("updated", r#"{"id":"u1"}"#),
+            "event: updated\ndata: {\"id\":\"u1\"}\n\n"
+        );
+        assert_eq!(
+            sse_frame("note", "a\nb"),
+            "event: note\ndata: a\ndata: b\n\n"
+        );
+    }
+
+    #[test]
+    fn test_api_key_operation_allowlist() {
+        let limited = ApiKeyRecord {
+            name: "reporting".to_string(),
+            allowed_ops: Some(vec!["sum".to_string()]),
+            tenant_id: None,
+        };
+        assert!(limited.allows("sum"));
+        assert!(!limited.allows("std"));
+
+        let unrestricted: ApiKeyRecord = serde_json::from_str(r#"{"name":"admin-tools"}"#).unwrap();
+        assert!(COMPUTE_OPERATIONS.iter().all(|op| unrestricted.allows(op)));
+        assert_eq!(ErrorCode::OperationNotAllowed.status(), 403);
+    }
+
+    #[test]
+    fn test_in_clause_builder() {
+        assert_eq!(build_in_clause(1).as_deref(), Some("(?)"));
+        assert_eq!(build_in_clause(3).as_deref(), Some("(?, ?, ?)"));
+        assert_eq!(build_in_clause(0), None);
+
+        let ids = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let clause = in_clause(&ids).unwrap();
+        assert_eq!(clause.sql, "(?, ?, ?)");
+        assert_eq!(clause.params, ids);
+
+        // No ids: no query at all, rather than invalid `IN ()`
+        assert!(in_clause(&[]).is_none());
+
+        let ids = vec!["a".into(), "a".into(), "b".into()];
+        assert_eq!(batch_ids(ids, D1_MAX_BOUND_PARAMS).unwrap(), vec!["a", "b"]);
+        let too_many = (0..D1_MAX_BOUND_PARAMS).map(|i| i.to_string()).collect();
+        assert!(batch_ids(too_many, D1_MAX_BOUND_PARAMS - 1).is_err());
+    }
+
+    #[test]
+    fn test_background_task_over_budget_is_logged() {
+        // A task that never finishes, against a budget that has already run out
+        let outcome = futures::executor::block_on(run_with_budget(
+            futures::future::pending(),
+            futures::future::ready(()),
+        ));
+        assert_eq!(outcome, BackgroundOutcome::TimedOut);
+        assert_eq!(
+            background_log_message("webhook delivery", 25_000, &outcome).as_deref(),
+            Some("webhook delivery timed out after 25000ms and was abandoned")
+        );
+
+        let outcome = futures::executor::block_on(run_with_budget(
+            async { Err(Error::RustError("boom".into())) },
+            futures::future::pending(),
+        ));
+        assert_eq!(
+            background_log_message("user cache write", 25_000, &outcome).as_deref(),
+            Some("user cache write failed: boom")
+        );
+
+        let done = futures::executor::block_on(run_with_budget(
+            async { Ok(()) },
+            futures::future::pending(),
+        ));
+        assert_eq!(background_log_message("noop", 1, &done), None);
+    }
+
+    #[test]
+    fn test_parse_count() {
+        let big = 5_000_000_000u64;
+        let row = serde_json::json!({ "count": big });
+        assert_eq!(parse_count(Some(&row)).unwrap(), big);
+        let row = serde_json::json!({ "count": 5_000_000_000.0 });
+        assert_eq!(parse_count(Some(&row)).unwrap(), big);
+
+        assert!(parse_count(None).is_err());
+        assert!(parse_count(Some(&serde_json::json!({}))).is_err());
+        assert!(parse_count(Some(&serde_json::json!({ "count": -1 }))).is_err());
+        assert!(parse_count(Some(&serde_json::json!({ "count": 1.5 }))).is_err());
+    }
+
+    #[test]
+    fn test_json_patch_replace() {
+        let user = test_user();
+        let ops: Vec<PatchOp> = serde_json::from_value(serde_json::json!([
+            { "op": "test", "path": "/name", "value": "Ada" },
+            { "op": "replace", "path": "/name", "value": "Ada Lovelace" },
+        ]))
+        .unwrap();
+
+        let update = apply_json_patch(&user, &ops).ok().unwrap();
+        assert_eq!(update.name.as_deref(), Some("Ada Lovelace"));
+        assert_eq!(update.email.as_deref(), Some("ada@example.com"));
+
+        let unsupported: Vec<PatchOp> =
+            serde_json::from_value(serde_json::json!([{ "op": "remove", "path": "/id" }])).unwrap();
+        let (code, _) = apply_json_patch(&user, &unsupported).err().unwrap();
+        assert_eq!(code.status(), 422);
+    }
+
+    #[test]
+    fn test_json_patch_failing_test_aborts() {
+        let user = test_user();
+        let ops: Vec<PatchOp> = serde_json::from_value(serde_json::json!([
+            { "op": "replace", "path": "/email", "value": "new@example.com" },
+            { "op": "test", "path": "/name", "value": "Grace" },
+        ]))
+        .unwrap();
+
+        let (code, message) = apply_json_patch(&user, &ops).err().unwrap();
+        assert_eq!(code, ErrorCode::PatchTestFailed);
+        assert_eq!(code.status(), 409);
+        assert!(message.contains("/name"));
+    }
+
+    #[test]
+    fn test_email_plus_normalization() {
+        let domains = vec!["gmail.com".to_string()];
+        // Both variants map to one key, so the second create conflicts
+        assert_eq!(
+            normalized_email("User+news@gmail.com", &domains),
+            normalized_email("user+shopping@gmail.com", &domains)
+        );
+        assert_eq!(
+            normalized_email("user+news@gmail.com", &domains),
+            "user@gmail.com"
+        );
+
+        // Off by default and for unlisted domains
+        assert_eq!(
+            normalized_email("user+news@gmail.com", &[]),
+            "user+news@gmail.com"
+        );
+        assert_eq!(
+            normalized_email("a+b@example.com", &domains),
+            "a+b@example.com"
+        );
+        assert_eq!(
+            normalized_email("a+b@example.com", &["*".to_string()]),
+            "a@example.com"
+        );
+        // Nothing before the plus: kept whole
+        assert_eq!(
+            normalized_email("+tag@gmail.com", &domains),
+            "+tag@gmail.com"
+        );
+    }
+
+    #[test]
+    fn test_list_etag_conditional_get() {
+        let version = ListVersion {
+            count: 2,
+            stamps: vec![Some("2024-01-02T00:00:00Z".to_string()), None, None, None],
+        };
+        let url = |q: &str| Url::parse(&format!("https://x/api/users?{}", q)).unwrap();
+        let tag = list_etag(&url("page=1&limit=10"), "application/json", &version);
+
+        // An unchanged list matches the client's tag, so the handler sends 304
+        let polled = list_etag(&url("limit=10&page=1"), "application/json", &version);
+        assert!(compare_etag(&polled, &tag, false));
+
+        // Another page, format, count or update changes the tag
+        assert_ne!(
+            list_etag(&url("page=2&limit=10"), "application/json", &version),
+            tag
+        );
+        assert_ne!(
+            list_etag(
+                &url("page=1&limit=10&pretty=true"),
+                "application/json",
+                &version
+            ),
+            tag
+        );
+        assert_ne!(
+            list_etag(&url("page=1&limit=10"), "application/msgpack", &version),
+            tag
+        );
+        let changed = ListVersion {
+            count: 2,
+            stamps: vec![Some("2024-01-03T00:00:00Z".to_string()), None, None, None],
+        };
+        assert_ne!(
+            list_etag(&url("page=1&limit=10"), "application/json", &changed),
+            tag
+        );
+        assert!(tag.starts_with("W/\""));
+    }
+
+    #[test]
+    fn test_same_email_in_two_tenants() {
+        let acme = TenantScope::Tenant("acme".to_string());
+        let globex = TenantScope::Tenant("globex".to_string());
+
+        // Both conflict checks only look inside the caller's tenant
+        assert_eq!(
+            email_conflict_sql(&acme, false),
+            "SELECT id FROM users WHERE email_normalized = ? AND tenant_id = ?"
+        );
+        assert_eq!(
+            email_conflict_sql(&acme, true),
+            "SELECT id FROM users WHERE email_normalized = ? AND id != ? AND tenant_id = ?"
+        );
+        assert_eq!(
+            email_conflict_sql(&TenantScope::All, false),
+            "SELECT id FROM users WHERE email_normalized = ?"
+        );
+
+        // ada@ in acme is invisible to globex's check, so globex can create
+        // her too; the unique index is on (tenant, email) to match
+        let ada = User {
+            tenant_id: Some("acme".to_string()),
+            ..test_user()
+        };
+        assert!(acme.allows(&ada));
+        assert!(!globex.allows(&ada));
+        let index = EXPECTED_INDEXES
+            .iter()
+            .find(|i| i.name == "idx_users_tenant_email")
+            .unwrap();
+        assert!(index.columns.starts_with("COALESCE(tenant_id"));
+        assert_eq!(
+            SCHEMA_MIGRATIONS.last(),
+            Some(&"0008_tenant_email_unique.sql")
+        );
+    }
+
+    #[test]
+    fn test_tenant_scope_hides_other_tenants() {
+        let user = |tenant: &str| User {
+            tenant_id: Some(tenant.to_string()),
+            ..test_user()
+        };
+        let acme = TenantScope::Tenant("acme".to_string());
+
+        // Tenant "globex" asking for acme's user by id: the cached copy is
+        // refused and the D1 lookup carries the tenant filter, so it's a 404
+        assert!(acme.allows(&user("acme")));
+        assert!(!TenantScope::Tenant("globex".to_string()).allows(&user("acme")));
+        assert_eq!(acme.clause(), " AND tenant_id = ?");
+        assert_eq!(acme.param_count(), 1);
+        assert_eq!(acme.tenant_id(), Some("acme"));
+
+        // Unscoped adds nothing
+        assert_eq!(TenantScope::All.clause(), "");
+        assert_eq!(TenantScope::All.param_count(), 0);
+        assert!(TenantScope::All.allows(&user("acme")));
+    }
+
+    #[test]
+    fn test_email_encryption_round_trip() {
+        let cipher = EmailCipher::from_secret(&"11".repeat(32)).unwrap();
+        let domains = vec!["example.com".to_string()];
+
+        // encrypt -> store -> read -> decrypt
+        let stored = stored_email(Some(&cipher), "Ada+news@example.com", &domains);
+        assert!(stored.email.starts_with(EMAIL_CIPHERTEXT_PREFIX));
+        assert!(!stored.email.contains("example.com"));
+        let mut user = User {
+            email: stored.email.clone(),
+            ..test_user()
+        };
+        open_users(Some(&cipher), [&mut user]).unwrap();
+        assert_eq!(user.email, "Ada+news@example.com");
+
+        // Fresh nonce per write, but the lookup HMACs are deterministic
+        let again = stored_email(Some(&cipher), "Ada+news@example.com", &domains);
+        assert_ne!(again.email, stored.email);
+        assert_eq!(again.hmac, stored.hmac);
+        assert_eq!(
+            stored_email(Some(&cipher), "ada@example.com", &domains).lookup,
+            stored.lookup
+        );
+        assert_ne!(stored.lookup, "ada@example.com");
+
+        // Plaintext rows pass through; tampering and the wrong key fail
+        assert_eq!(
+            cipher.decrypt("old@example.com").unwrap(),
+            "old@example.com"
+        );
+        let mut tampered = stored.email.clone();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == '0' { '1' } else { '0' });
+        assert!(cipher.decrypt(&tampered).is_err());
+        let other = EmailCipher::from_secret(&"22".repeat(32)).unwrap();
+        assert!(other.decrypt(&stored.email).is_err());
+        assert!(EmailCipher::from_secret("too-short").is_none());
+
+        let plain = stored_email(None, "Ada@example.com", &[]);
+        assert_eq!(plain.email, "Ada@example.com");
+        assert_eq!(plain.lookup, "ada@example.com");
+        assert!(plain.hmac.is_none());
+    }
+
+    #[test]
+    fn test_encrypted_update_keeps_email() {
+        let cipher = EmailCipher::from_secret(&"11".repeat(32)).unwrap();
+        let stored = stored_email(Some(&cipher), "ada@example.com", &[]);
+        let mut user = User {
+            email: stored.email.clone(),
+            email_verified_at: Some("2024-01-02T00:00:00Z".to_string()),
+            ..test_user()
+        };
+
+        // load -> name-only update -> store -> re-read
+        open_users(Some(&cipher), [&mut user]).unwrap();
+        let input = UpdateUserRequest {
+            name: Some("Ada L".to_string()),
+            email: None,
+        };
+        apply_user_update(&mut user, input).unwrap();
+        let written = stored_email(Some(&cipher), &user.email, &[]);
+        assert_eq!(written.lookup, stored.lookup);
+        assert_eq!(written.hmac, stored.hmac);
+        assert!(user.email_verified_at.is_some());
+
+        user.email = written.email;
+        open_users(Some(&cipher), [&mut user]).unwrap();
+        assert_eq!(user.email, "ada@example.com");
+        assert_eq!(user.name, "Ada L");
+
+        // Resubmitting the same email keeps verification
+        let same = UpdateUserRequest {
+            name: None,
+            email: Some("ADA@example.com".to_string()),
+        };
+        apply_user_update(&mut user, same).unwrap();
+        assert!(user.email_verified_at.is_some());
     }
 
     #[test]